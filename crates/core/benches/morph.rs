@@ -0,0 +1,85 @@
+use std::sync::Arc;
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use liveview_native_core::{diff, dom::Document};
+
+macro_rules! fixture {
+    ($name:literal) => {
+        (
+            $name,
+            include_str!(concat!("../tests/fixtures/", $name, "/from.html")),
+            include_str!(concat!("../tests/fixtures/", $name, "/to.html")),
+        )
+    };
+}
+
+const FIXTURES: &[(&str, &str, &str)] = &[
+    fixture!("large"),
+    fixture!("data-table"),
+    fixture!("todomvc"),
+];
+
+/// A keyed list of `rows` rows, and the same list with one row moved from near the start to near the end
+fn keyed_list(rows: usize) -> (String, String) {
+    let items = (0..rows)
+        .map(|i| format!(r#"<tr id="row-{i}"><td>{i}</td><td>Row {i}</td></tr>"#))
+        .collect::<Vec<_>>();
+    let from = format!("<table>{}</table>", items.concat());
+    let mut moved = items;
+    let item = moved.remove(rows / 100);
+    moved.insert(rows - rows / 100, item);
+    let to = format!("<table>{}</table>", moved.concat());
+    (from, to)
+}
+
+fn morph(c: &mut Criterion) {
+    let mut group = c.benchmark_group("morph");
+    for (name, from, to) in FIXTURES.iter().copied() {
+        let prev = Document::parse(from).unwrap();
+        let next = Document::parse(to).unwrap();
+        group.bench_function(format!("diff/{name}"), |b| {
+            b.iter(|| diff::diff(black_box(&prev), black_box(&next)))
+        });
+        let patches = diff::diff(&prev, &next);
+        group.bench_function(format!("apply/{name}"), |b| {
+            b.iter_batched(
+                || prev.clone(),
+                |mut doc| {
+                    let mut editor = doc.edit();
+                    let mut stack = vec![];
+                    for patch in patches.iter().cloned() {
+                        patch.apply(&mut editor, &mut stack);
+                    }
+                    editor.finish();
+                },
+                BatchSize::SmallInput,
+            )
+        });
+        // The first change after an ffi snapshot copies the whole document, as the snapshot still shares it
+        group.bench_function(format!("snapshot-copy/{name}"), |b| {
+            b.iter_batched(
+                || {
+                    let doc = Arc::new(prev.clone());
+                    let snapshot = doc.clone();
+                    (doc, snapshot)
+                },
+                |(mut doc, snapshot)| {
+                    Arc::make_mut(&mut doc);
+                    (doc, snapshot)
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+
+    let (from, to) = keyed_list(1000);
+    let prev = Document::parse(from).unwrap();
+    let next = Document::parse(to).unwrap();
+    group.bench_function("diff/keyed-move-1000", |b| {
+        b.iter(|| diff::diff(black_box(&prev), black_box(&next)))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, morph);
+criterion_main!(benches);
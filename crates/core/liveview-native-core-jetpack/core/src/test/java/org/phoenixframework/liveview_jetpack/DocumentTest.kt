@@ -5,6 +5,7 @@ import org.junit.Assert.assertEquals;
 import org.phoenixframework.liveviewnative.core.Document;
 import org.phoenixframework.liveviewnative.core.DocumentChangeHandler;
 import org.phoenixframework.liveviewnative.core.ChangeType;
+import org.phoenixframework.liveviewnative.core.AttributeChange;
 import org.phoenixframework.liveviewnative.core.NodeRef;
 import org.phoenixframework.liveviewnative.core.NodeData;
 
@@ -41,6 +42,8 @@ class SimpleChangeHandler: DocumentChangeHandler {
         `nodeRef`: NodeRef,
         `nodeData`: NodeData,
         `optionNodeRef`: NodeRef?,
+        `attributes`: List<AttributeChange>,
+        `path`: String?,
     ) {
         println("${changeType}")
     }
@@ -7,7 +7,7 @@ final class SimpleHandler: DocumentChangeHandler {
     ) {
        self.callback = callback
     }
-    func handle(_ changeType: ChangeType, _ node: NodeRef, _ data: NodeData, _ parent: NodeRef?) {
+    func handle(_ changeType: ChangeType, _ node: NodeRef, _ data: NodeData, _ parent: NodeRef?, _ attributes: [AttributeChange], _ path: String?) {
         switch changeType {
         case .add:
             self.callback(parent!, data, parent)
@@ -17,6 +17,10 @@ final class SimpleHandler: DocumentChangeHandler {
             self.callback(node, data, parent)
         case .replace:
             self.callback(parent!, data, parent)
+        case .move:
+            self.callback(parent!, data, parent)
+        case .updateText:
+            self.callback(parent!, data, parent)
         }
     }
 
@@ -31,9 +35,10 @@ extension Document {
         let payload = String(data: jsonData, encoding: .utf8)!
         return try Document.parseFragmentJson(payload)
     }
+    @discardableResult
     public func mergeFragmentJson(
         _ payload: [String: Any]
-        ) throws {
+        ) throws -> ChangeSet {
         let jsonData = try JSONSerialization.data(withJSONObject: payload)
         let payload = String(data: jsonData, encoding: .utf8)!
 
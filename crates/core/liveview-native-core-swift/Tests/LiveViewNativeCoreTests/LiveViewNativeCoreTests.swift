@@ -1,7 +1,7 @@
 import XCTest
 @testable import LiveViewNativeCore
 final class SimpleHandler: DocumentChangeHandler {
-    func handle(_ changeType: ChangeType, _ nodeRef: NodeRef, _ nodeData: NodeData, _ parent: NodeRef?) {
+    func handle(_ changeType: ChangeType, _ nodeRef: NodeRef, _ nodeData: NodeData, _ parent: NodeRef?, _ attributes: [AttributeChange], _ path: String?) {
         print("Handler:", changeType, ", node:", nodeRef.ref());
     }
 }
@@ -1,7 +1,16 @@
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt,
+};
 
 use serde::Deserialize;
 
+use crate::{
+    diff::PatchError,
+    dom::Document,
+    parser::{IncrementalParser, ParseError},
+};
+
 #[cfg(test)]
 mod tests;
 
@@ -21,15 +30,197 @@ pub struct Root {
     components: Option<HashMap<String, Component>>,
 }
 
+/// The attribute used to tag the root element of a rendered component with its CID
+pub const PHX_COMPONENT: &str = "data-phx-component";
+
+/// Options which control how a `Root` is rendered
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct RenderOptions {
+    /// Tags the root element of each component with its CID in a [`PHX_COMPONENT`] attribute, like the JS
+    /// client does
+    ///
+    /// This is off by default, so the output is the markup of the fragment as the server sent it.
+    pub component_ids: bool,
+}
+
+/// Describes which parts of a `Root` were affected by merging a `RootDiff` into it
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RootChanges {
+    /// True if the top-level fragment changed, in which case anything may need to be re-rendered
+    pub fragment_changed: bool,
+    /// The CIDs of components whose rendered output may have changed
+    pub components: BTreeSet<i32>,
+}
+
+impl Root {
+    /// Merges `diff` into this root like [`FragmentMerge::merge`], but also reports what changed
+    ///
+    /// A component is reported as changed if it was added or modified by the diff.
+    pub fn merge_with_changes(self, diff: RootDiff) -> Result<(Self, RootChanges), MergeError> {
+        let old_fragment = self.fragment.clone();
+        let old_components = self.components.clone().unwrap_or_default();
+        let merged = self.merge(diff)?;
+
+        let mut changes = RootChanges {
+            fragment_changed: merged.fragment != old_fragment,
+            components: BTreeSet::new(),
+        };
+        let Some(components) = merged.components.as_ref() else {
+            return Ok((merged, changes));
+        };
+        for (key, component) in components.iter() {
+            if old_components.get(key) != Some(component) {
+                if let Ok(cid) = key.parse::<i32>() {
+                    changes.components.insert(cid);
+                }
+            }
+        }
+
+        Ok((merged, changes))
+    }
+
+    /// Returns the CIDs of all components which are no longer rendered, and can be destroyed
+    ///
+    /// Components which are only used as the source of statics for a rendered component are
+    /// still required for rendering, and are not considered orphaned.
+    pub fn orphaned_components(&self) -> BTreeSet<i32> {
+        let Some(components) = self.components.as_ref() else {
+            return BTreeSet::new();
+        };
+
+        let mut reachable = BTreeSet::new();
+        let mut pending = vec![];
+        self.fragment.component_ids(&mut pending);
+        while let Some(cid) = pending.pop() {
+            if !reachable.insert(cid) {
+                continue;
+            }
+            if let Some(component) = components.get(&cid.to_string()) {
+                for child in component.children.values() {
+                    child.component_ids(&mut pending);
+                }
+                if let ComponentStatics::ComponentRef(target) = component.statics {
+                    pending.push(target);
+                }
+            }
+        }
+
+        components
+            .keys()
+            .filter_map(|key| key.parse::<i32>().ok())
+            .filter(|cid| !reachable.contains(cid))
+            .collect()
+    }
+
+    /// Removes the components with the given CIDs
+    pub fn prune_components<I: IntoIterator<Item = i32>>(&mut self, cids: I) {
+        if let Some(components) = self.components.as_mut() {
+            for cid in cids {
+                components.remove(&cid.to_string());
+            }
+        }
+    }
+
+    /// Renders this root to a string
+    pub fn render(&self, options: RenderOptions) -> Result<String, RenderError> {
+        let mut roots = vec![];
+        self.fragment.component_ids(&mut roots);
+        self.check_component_nesting(roots)?;
+
+        let mut sink = StringSink::new(options);
+        self.fragment
+            .render_into(&mut sink, &self.components, None, None)?;
+        Ok(sink.out)
+    }
+
+    /// Renders the component with the given CID on its own, as it would appear in the full render
+    pub fn render_component(
+        &self,
+        cid: i32,
+        options: RenderOptions,
+    ) -> Result<String, RenderError> {
+        self.check_component_nesting(vec![cid])?;
+
+        let mut sink = StringSink::new(options);
+        Child::ComponentID(cid).render_into(&mut sink, &self.components, None, None)?;
+        Ok(sink.out)
+    }
+
+    /// Renders this root to a `Document`
+    ///
+    /// This produces the same tree as parsing the string rendered with the same `options`.
+    pub fn render_document(&self, options: RenderOptions) -> Result<Document, RenderError> {
+        let mut roots = vec![];
+        self.fragment.component_ids(&mut roots);
+        self.check_component_nesting(roots)?;
+
+        let mut sink = DocumentSink::new(options);
+        self.fragment
+            .render_into(&mut sink, &self.components, None, None)?;
+        Ok(sink.finish()?)
+    }
+
+    /// Renders the component with the given CID on its own to a `Document`
+    pub fn render_component_document(
+        &self,
+        cid: i32,
+        options: RenderOptions,
+    ) -> Result<Document, RenderError> {
+        self.check_component_nesting(vec![cid])?;
+
+        let mut sink = DocumentSink::new(options);
+        Child::ComponentID(cid).render_into(&mut sink, &self.components, None, None)?;
+        Ok(sink.finish()?)
+    }
+
+    /// Fails if a component reachable from `roots` is nested inside itself, which can't be rendered
+    fn check_component_nesting(&self, roots: Vec<i32>) -> Result<(), RenderError> {
+        let Some(components) = self.components.as_ref() else {
+            return Ok(());
+        };
+        let nested = |cid: i32| {
+            let mut out = vec![];
+            if let Some(component) = components.get(&cid.to_string()) {
+                for child in component.children.values() {
+                    child.component_ids(&mut out);
+                }
+            }
+            out
+        };
+
+        // Iterative depth-first search, where `stack` holds the chain of components being visited
+        let mut done = BTreeSet::new();
+        for root in roots {
+            if done.contains(&root) {
+                continue;
+            }
+            let mut stack = vec![(root, nested(root))];
+            while let Some((cid, pending)) = stack.last_mut() {
+                let cid = *cid;
+                match pending.pop() {
+                    Some(child) if stack.iter().any(|(visiting, _)| *visiting == child) => {
+                        return Err(RenderError::ComponentNestingCycle(child));
+                    }
+                    Some(child) if !done.contains(&child) => {
+                        stack.push((child, nested(child)));
+                    }
+                    Some(_) => (),
+                    None => {
+                        done.insert(cid);
+                        stack.pop();
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
 impl TryFrom<RootDiff> for Root {
     type Error = MergeError;
     fn try_from(value: RootDiff) -> Result<Self, MergeError> {
         let components = if let Some(components) = value.components {
-            let mut out: HashMap<String, Component> = HashMap::new();
-            for (key, value) in components.into_iter() {
-                out.insert(key, value.try_into()?);
-            }
-            Some(out)
+            Some(HashMap::new().merge(components)?)
         } else {
             None
         };
@@ -44,10 +235,137 @@ impl TryInto<String> for Root {
     type Error = RenderError;
 
     fn try_into(self) -> Result<String, Self::Error> {
-        let mut out = String::new();
-        let inner = self.fragment.render(&self.components, None, None)?;
-        out.push_str(&inner);
-        Ok(out)
+        self.render(RenderOptions::default())
+    }
+}
+
+/// Receives the output of rendering a fragment, one static or dynamic part at a time
+trait RenderSink {
+    fn push_str(&mut self, s: &str);
+
+    /// Called before rendering the component with the given CID
+    fn start_component(&mut self, cid: i32);
+
+    /// Called after rendering the component with the given CID
+    fn end_component(&mut self, cid: i32);
+}
+
+/// Renders to a string, tagging the root element of each component with its CID if asked to
+#[derive(Default)]
+struct StringSink {
+    out: String,
+    component_ids: bool,
+    /// The offsets in `out` at which the components currently being rendered start
+    components: Vec<usize>,
+}
+
+impl StringSink {
+    fn new(options: RenderOptions) -> Self {
+        Self {
+            component_ids: options.component_ids,
+            ..Default::default()
+        }
+    }
+}
+
+impl RenderSink for StringSink {
+    fn push_str(&mut self, s: &str) {
+        self.out.push_str(s);
+    }
+
+    fn start_component(&mut self, _cid: i32) {
+        if self.component_ids {
+            self.components.push(self.out.len());
+        }
+    }
+
+    fn end_component(&mut self, cid: i32) {
+        if let Some(start) = self.components.pop() {
+            let rendered = self.out.split_off(start);
+            self.out.push_str(&inject_component_id(rendered, cid));
+        }
+    }
+}
+
+/// Builds a document, tagging the root element of each component with its CID if asked to
+///
+/// The CID is injected into the markup exactly as `StringSink` does it, so the resulting tree is the same as
+/// parsing the rendered string. Output is only held back while a component has started but the end of its
+/// first tag name hasn't been seen yet.
+#[derive(Default)]
+struct DocumentSink {
+    parser: IncrementalParser,
+    component_ids: bool,
+    /// Output which can't be passed on to the parser until the components in `pending` are resolved
+    held: String,
+    /// The components whose injection point hasn't been found yet, and where their output starts in `held`
+    pending: Vec<(i32, usize)>,
+    /// The offsets in `held` at which to inject the CID of a component, in the order they were found
+    injections: Vec<(usize, i32)>,
+}
+
+impl DocumentSink {
+    fn new(options: RenderOptions) -> Self {
+        Self {
+            component_ids: options.component_ids,
+            ..Default::default()
+        }
+    }
+
+    fn finish(self) -> Result<Document, ParseError> {
+        self.parser.finish()
+    }
+
+    /// Looks for the injection point of every pending component, passing on held output once all are found
+    fn resolve(&mut self, complete: Option<i32>) {
+        let held = &self.held;
+        let injections = &mut self.injections;
+        self.pending.retain(|&(cid, start)| {
+            match injection_point(&held[start..], complete == Some(cid)) {
+                InjectionPoint::At(pos) => injections.push((start + pos, cid)),
+                InjectionPoint::Never => (),
+                InjectionPoint::Undecided => return true,
+            }
+            false
+        });
+        if !self.pending.is_empty() {
+            return;
+        }
+
+        // When components share a root element, the outermost one was found first and is injected first,
+        // which is also the order the attributes end up in when `StringSink` injects them
+        self.injections.sort_by_key(|&(pos, _)| pos);
+        let mut offset = 0;
+        for (pos, cid) in self.injections.drain(..) {
+            self.parser.push_str(&self.held[offset..pos]);
+            self.parser.push_str(&format!(" {PHX_COMPONENT}=\"{cid}\""));
+            offset = pos;
+        }
+        self.parser.push_str(&self.held[offset..]);
+        self.held.clear();
+    }
+}
+
+impl RenderSink for DocumentSink {
+    fn push_str(&mut self, s: &str) {
+        if self.pending.is_empty() {
+            self.parser.push_str(s);
+        } else {
+            self.held.push_str(s);
+            self.resolve(None);
+        }
+    }
+
+    fn start_component(&mut self, cid: i32) {
+        if self.component_ids {
+            self.pending.push((cid, self.held.len()));
+        }
+    }
+
+    fn end_component(&mut self, cid: i32) {
+        if self.pending.iter().any(|&(pending, _)| pending == cid) {
+            self.resolve(Some(cid));
+        }
     }
 }
 
@@ -73,7 +391,50 @@ pub enum RenderError {
     #[error("Serde Error {0}")]
     SerdeError(#[from] serde_json::Error),
     #[error("Parse Error {0}")]
-    ParseError(#[from] crate::parser::ParseError),
+    ParseError(#[from] ParseError),
+    #[error("Patch Error {0}")]
+    PatchError(#[from] PatchError),
+    #[error("Statics are empty")]
+    EmptyStatics,
+    #[error("Comprehension has both statics and cousin statics")]
+    ConflictingStatics,
+    #[error("Component ID {0} refers to its own statics")]
+    ComponentStaticsCycle(i32),
+    #[error("Component ID {0} is rendered inside itself")]
+    ComponentNestingCycle(i32),
+    #[error("{error} at {path}")]
+    AtPath {
+        path: String,
+        error: Box<RenderError>,
+    },
+    /// Merging failed after the document was patched, and the patches applied could not be undone
+    #[error("{error}, and undoing the patches applied before it failed: {undo}")]
+    UndoFailed {
+        error: Box<RenderError>,
+        undo: PatchError,
+    },
+    /// A failure injected by a failpoint, see `crate::dom::failpoint`
+    #[cfg(test)]
+    #[error("injected {0} failure")]
+    Injected(String),
+}
+
+impl RenderError {
+    /// Records that this error occurred under `segment` of the fragment tree
+    ///
+    /// Segments are prepended as the error propagates, so the path reads from the root downwards.
+    pub fn at<S: fmt::Display>(self, segment: S) -> Self {
+        match self {
+            Self::AtPath { path, error } => Self::AtPath {
+                path: format!("{segment}/{path}"),
+                error,
+            },
+            error => Self::AtPath {
+                path: segment.to_string(),
+                error: Box::new(error),
+            },
+        }
+    }
 }
 
 impl Fragment {
@@ -83,53 +444,55 @@ impl Fragment {
         cousin_statics: Option<Vec<String>>,
         parent_templates: Templates,
     ) -> Result<String, RenderError> {
-        let mut out = String::new();
+        let mut out = StringSink::default();
+        self.render_into(&mut out, components, cousin_statics, parent_templates)?;
+        Ok(out.out)
+    }
+
+    fn render_into<S: RenderSink>(
+        &self,
+        out: &mut S,
+        components: &Option<HashMap<String, Component>>,
+        cousin_statics: Option<Vec<String>>,
+        parent_templates: Templates,
+    ) -> Result<(), RenderError> {
         match &self {
-            Fragment::Regular { children, statics } => {
-                match statics {
-                    Statics::Statics(statics) => {
-                        out.push_str(&statics[0]);
-                        // We start at index 1 rather than zero here because
-                        // templates and statics are suppose to wrap the inner
-                        // contents of the children.
-                        for (i, static_item) in statics.iter().enumerate().skip(1) {
-                            let child = children
-                                .get(&(i - 1).to_string())
-                                .ok_or(RenderError::ChildNotFoundForStatic((i - 1) as i32))?;
-                            let val = child.render(
+            Fragment::Regular { children, statics } => match statics {
+                Statics::Statics(statics) => {
+                    render_statics(out, statics, |out, i| {
+                        let child = children
+                            .get(&i.to_string())
+                            .ok_or(RenderError::ChildNotFoundForStatic(i as i32))?;
+                        child
+                            .render_into(
+                                out,
                                 components,
                                 cousin_statics.clone(),
                                 parent_templates.clone(),
-                            )?;
-                            out.push_str(&val);
-                            out.push_str(static_item);
-                        }
-                    }
-                    Statics::TemplateRef(template_id) => {
-                        let templates = parent_templates.ok_or(RenderError::NoTemplates)?;
-                        let template = templates
-                            .get(&(template_id.to_string()))
-                            .ok_or(RenderError::TemplateNotFound(*template_id))?;
-                        out.push_str(&template[0]);
-                        // We start at index 1 rather than zero here because
-                        // templates and statics are suppose to wrap the inner
-                        // contents of the children.
-                        for (i, template_item) in template.iter().enumerate().skip(1) {
-                            let child_id = i - 1;
-                            let child = children
-                                .get(&child_id.to_string())
-                                .ok_or(RenderError::ChildNotFoundForTemplate(child_id as i32))?;
-                            let val = child.render(
+                            )
+                            .map_err(|error| error.at(i))
+                    })?;
+                }
+                Statics::TemplateRef(template_id) => {
+                    let templates = parent_templates.ok_or(RenderError::NoTemplates)?;
+                    let template = templates
+                        .get(&(template_id.to_string()))
+                        .ok_or(RenderError::TemplateNotFound(*template_id))?;
+                    render_statics(out, template, |out, i| {
+                        let child = children
+                            .get(&i.to_string())
+                            .ok_or(RenderError::ChildNotFoundForTemplate(i as i32))?;
+                        child
+                            .render_into(
+                                out,
                                 components,
                                 cousin_statics.clone(),
                                 Some(templates.clone()),
-                            )?;
-                            out.push_str(&val);
-                            out.push_str(template_item);
-                        }
-                    }
+                            )
+                            .map_err(|error| error.at(i))
+                    })?;
                 }
-            }
+            },
             Fragment::Comprehension {
                 dynamics,
                 statics,
@@ -142,115 +505,191 @@ impl Fragment {
                     (Some(t), None) => Some(t),
                     (Some(parent), Some(child)) => Some(parent).merge(Some(child.clone()))?,
                 };
-                match (statics, cousin_statics) {
-                    (None, None) => {
-                        for children in dynamics.iter() {
-                            for child in children.iter() {
-                                let val = child.render(components, None, templates.clone())?;
-                                out.push_str(&val);
-                            }
-                        }
+                let statics = match (statics, cousin_statics) {
+                    (None, None) => None,
+                    (None, Some(statics)) => Some(statics),
+                    (Some(Statics::Statics(statics)), None) => Some(statics.clone()),
+                    (Some(Statics::TemplateRef(template_id)), None) => {
+                        let this_template = templates.as_ref().ok_or(RenderError::NoTemplates)?;
+                        let template_statics = this_template
+                            .get(&template_id.to_string())
+                            .ok_or(RenderError::TemplateNotFound(*template_id))?;
+                        Some(template_statics.clone())
                     }
-                    (None, Some(statics)) => {
-                        for children in dynamics.iter() {
-                            out.push_str(&statics[0]);
-                            // We start at index 1 rather than zero here because
-                            // templates and statics are suppose to wrap the inner
-                            // contents of the children.
-                            for i in 1..statics.len() {
-                                let child = &children[i - 1];
-
-                                let val = child.render(components, None, templates.clone())?;
-                                out.push_str(&val);
-                                out.push_str(&statics[i]);
-                            }
-                        }
+                    (Some(_statics), Some(_cousin_statics)) => {
+                        return Err(RenderError::ConflictingStatics);
                     }
-                    (Some(statics), None) => {
-                        match statics {
-                            Statics::Statics(statics) => {
-                                for children in dynamics.iter() {
-                                    out.push_str(&statics[0]);
-                                    // We start at index 1 rather than zero here because
-                                    // templates and statics are suppose to wrap the inner
-                                    // contents of the children.
-                                    for i in 1..statics.len() {
-                                        let child = &children[i - 1];
-
-                                        let val =
-                                            child.render(components, None, templates.clone())?;
-                                        out.push_str(&val);
-                                        out.push_str(&statics[i]);
-                                    }
-                                }
-                            }
-                            Statics::TemplateRef(template_id) => {
-                                if let Some(ref this_template) = templates {
-                                    if let Some(template_statics) =
-                                        this_template.get(&template_id.to_string())
-                                    {
-                                        for children in dynamics.iter() {
-                                            out.push_str(&template_statics[0]);
-
-                                            // We start at index 1 rather than zero here because
-                                            // templates and statics are suppose to wrap the inner
-                                            // contents of the children.
-                                            for i in 1..template_statics.len() {
-                                                let child = &children[i - 1];
-
-                                                let val = child.render(
-                                                    components,
-                                                    None,
-                                                    templates.clone(),
-                                                )?;
-                                                out.push_str(&val);
-                                                out.push_str(&template_statics[i]);
-                                            }
-                                        }
-                                    } else {
-                                        return Err(RenderError::TemplateNotFound(*template_id));
-                                    }
-                                } else {
-                                    return Err(RenderError::NoTemplates);
-                                }
+                };
+                for (row, children) in dynamics.iter().enumerate() {
+                    let render_child = |out: &mut S, col: usize| {
+                        let child = children
+                            .get(col)
+                            .ok_or(RenderError::ChildNotFoundForStatic(col as i32))?;
+                        child
+                            .render_into(out, components, None, templates.clone())
+                            .map_err(|error| error.at(format!("d/{row}/{col}")))
+                    };
+                    match statics {
+                        Some(ref statics) => render_statics(out, statics, render_child)?,
+                        None => {
+                            for col in 0..children.len() {
+                                render_child(out, col)?;
                             }
                         }
                     }
-                    (Some(_statics), Some(_cousin_templates)) => {
-                        panic!("Either statics or cousin statics but not both");
-                    }
                 }
             }
         }
-        Ok(out)
+        Ok(())
+    }
+}
+
+/// Renders `statics` interleaved with the dynamic parts produced by `render_child`
+///
+/// Statics are supposed to wrap the inner contents of the children, so for `n + 1` statics this
+/// renders `statics[0]`, then child `i` followed by `statics[i + 1]` for each `i` in `0..n`.
+fn render_statics<S, F>(
+    out: &mut S,
+    statics: &[String],
+    mut render_child: F,
+) -> Result<(), RenderError>
+where
+    S: RenderSink,
+    F: FnMut(&mut S, usize) -> Result<(), RenderError>,
+{
+    let (first, rest) = statics.split_first().ok_or(RenderError::EmptyStatics)?;
+    out.push_str(first);
+    for (i, static_item) in rest.iter().enumerate() {
+        render_child(out, i)?;
+        out.push_str(static_item);
+    }
+    Ok(())
+}
+
+impl Fragment {
+    /// Collects the CIDs of all components referenced by this fragment
+    fn component_ids(&self, out: &mut Vec<i32>) {
+        match self {
+            Fragment::Regular { children, .. } => {
+                for child in children.values() {
+                    child.component_ids(out);
+                }
+            }
+            Fragment::Comprehension { dynamics, .. } => {
+                for child in dynamics.iter().flatten() {
+                    child.component_ids(out);
+                }
+            }
+        }
     }
 }
 
 impl Child {
+    fn component_ids(&self, out: &mut Vec<i32>) {
+        match self {
+            Child::Fragment(fragment) => fragment.component_ids(out),
+            Child::ComponentID(cid) => out.push(*cid),
+            Child::String(_) => (),
+        }
+    }
+
     pub fn render(
         &self,
         components: &Option<HashMap<String, Component>>,
         statics: Option<Vec<String>>,
         templates: Templates,
     ) -> Result<String, RenderError> {
+        let mut out = StringSink::default();
+        self.render_into(&mut out, components, statics, templates)?;
+        Ok(out.out)
+    }
+
+    fn render_into<S: RenderSink>(
+        &self,
+        out: &mut S,
+        components: &Option<HashMap<String, Component>>,
+        statics: Option<Vec<String>>,
+        templates: Templates,
+    ) -> Result<(), RenderError> {
         match self {
-            Child::Fragment(fragment) => fragment.render(components, statics, templates),
+            Child::Fragment(fragment) => fragment.render_into(out, components, statics, templates),
             Child::ComponentID(cid) => {
-                if let Some(inner_components) = components {
-                    if let Some(component) = inner_components.get(&cid.to_string()) {
-                        component.render(components)
-                    } else {
-                        Err(RenderError::ComponentNotFound(*cid))
-                    }
-                } else {
-                    Err(RenderError::NoComponents)
-                }
+                let component = components
+                    .as_ref()
+                    .ok_or(RenderError::NoComponents)?
+                    .get(&cid.to_string())
+                    .ok_or(RenderError::ComponentNotFound(*cid))?;
+                out.start_component(*cid);
+                component
+                    .render_into(out, components)
+                    .map_err(|error| error.at(format!("c/{cid}")))?;
+                out.end_component(*cid);
+                Ok(())
+            }
+            Child::String(inner) => {
+                out.push_str(inner);
+                Ok(())
             }
-            Child::String(inner) => Ok(inner.to_string()),
         }
     }
 }
 
+/// Tags the first start tag of a rendered component with the component's CID, like the JS client does
+fn inject_component_id(mut rendered: String, cid: i32) -> String {
+    if let InjectionPoint::At(pos) = injection_point(&rendered, true) {
+        rendered.insert_str(pos, &format!(" {PHX_COMPONENT}=\"{cid}\""));
+    }
+    rendered
+}
+
+/// Where the CID of a component is injected into its rendered output
+enum InjectionPoint {
+    /// After the name of the first tag, at the given offset
+    At(usize),
+    /// The output seen so far doesn't contain the end of the first tag name
+    Undecided,
+    /// The output doesn't contain a tag
+    Never,
+}
+
+/// Finds the end of the first tag name in `rendered`, skipping comments
+///
+/// If `complete` is false, more output may follow, so the result is `Undecided` where it could still change.
+fn injection_point(rendered: &str, complete: bool) -> InjectionPoint {
+    let undecided = |at_end| {
+        if complete {
+            at_end
+        } else {
+            InjectionPoint::Undecided
+        }
+    };
+    let bytes = rendered.as_bytes();
+    let mut offset = 0;
+    while let Some(pos) = rendered[offset..].find('<') {
+        let start = offset + pos;
+        let rest = &rendered[start..];
+        if rest.starts_with("<!--") {
+            match rest.find("-->") {
+                Some(end) => offset = start + end + 3,
+                None => return undecided(InjectionPoint::Never),
+            }
+            continue;
+        }
+        if !complete && "<!--".starts_with(rest) {
+            return InjectionPoint::Undecided;
+        }
+        if !bytes.get(start + 1).is_some_and(u8::is_ascii_alphabetic) {
+            offset = start + 1;
+            continue;
+        }
+        return match rest.find(|c: char| c.is_ascii_whitespace() || c == '>' || c == '/') {
+            Some(end) => InjectionPoint::At(start + end),
+            None => undecided(InjectionPoint::At(rendered.len())),
+        };
+    }
+    undecided(InjectionPoint::Never)
+}
+
 #[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct Component {
     #[serde(flatten)]
@@ -264,81 +703,87 @@ impl Component {
         &self,
         components: &Option<HashMap<String, Component>>,
     ) -> Result<String, RenderError> {
+        let mut out = StringSink::default();
+        self.render_into(&mut out, components)?;
+        Ok(out.out)
+    }
+
+    fn render_into<S: RenderSink>(
+        &self,
+        out: &mut S,
+        components: &Option<HashMap<String, Component>>,
+    ) -> Result<(), RenderError> {
         match &self.statics {
             ComponentStatics::Statics(statics) => {
-                let mut out = String::new();
-
-                out.push_str(&statics[0]);
-                // We start at index 1 rather than zero here because
-                // templates and statics are suppose to wrap the inner
-                // contents of the children.
-                for (i, static_item) in statics.iter().enumerate().skip(1) {
+                render_statics(out, statics, |out, i| {
                     let inner = self
                         .children
-                        .get(&(i - 1).to_string())
-                        .ok_or(RenderError::ChildNotFoundForStatic((i - 1) as i32))?;
-                    let val = inner.render(components, None, None)?;
-                    out.push_str(&val);
-                    out.push_str(static_item);
-                }
-                Ok(out)
+                        .get(&i.to_string())
+                        .ok_or(RenderError::ChildNotFoundForStatic(i as i32))?;
+                    inner
+                        .render_into(out, components, None, None)
+                        .map_err(|error| error.at(i))
+                })?;
             }
 
-            ComponentStatics::ComponentRef(mut cid) => {
-                let outer_statics: Vec<String>;
-                let cousin_component: Component;
-                loop {
-                    if let Some(inner_components) = components {
-                        if let Some(component) = inner_components.get(&cid.to_string()) {
-                            match &component.statics {
-                                ComponentStatics::Statics(s) => {
-                                    outer_statics = s.to_vec();
-                                    cousin_component = component.clone();
-                                    break;
-                                }
-                                ComponentStatics::ComponentRef(bread_crumb_cid) => {
-                                    cid = *bread_crumb_cid;
-                                }
-                            }
-                        } else {
-                            return Err(RenderError::ComponentNotFound(cid));
+            ComponentStatics::ComponentRef(cid) => {
+                let inner_components = components.as_ref().ok_or(RenderError::NoComponents)?;
+                let mut cid = *cid;
+                let mut visited = BTreeSet::new();
+                let (outer_statics, cousin_component) = loop {
+                    if !visited.insert(cid) {
+                        return Err(RenderError::ComponentStaticsCycle(cid));
+                    }
+                    let component = inner_components
+                        .get(&cid.to_string())
+                        .ok_or(RenderError::ComponentNotFound(cid))?;
+                    match &component.statics {
+                        ComponentStatics::Statics(s) => break (s, component),
+                        ComponentStatics::ComponentRef(bread_crumb_cid) => {
+                            cid = *bread_crumb_cid;
                         }
-                    } else {
-                        return Err(RenderError::NoComponents);
                     }
-                }
-                let mut out = String::new();
+                };
 
-                out.push_str(&outer_statics[0]);
-                // We start at index 1 rather than zero here because
-                // templates and statics are suppose to wrap the inner
-                // contents of the children.
-                for (i, outer_static_item) in outer_statics.iter().enumerate().skip(1) {
+                render_statics(out, outer_statics, |out, i| {
                     let child = self
                         .children
-                        .get(&(i - 1).to_string())
-                        .ok_or(RenderError::ChildNotFoundForStatic((i - 1) as i32))?;
+                        .get(&i.to_string())
+                        .ok_or(RenderError::ChildNotFoundForStatic(i as i32))?;
                     let cousin = cousin_component
                         .children
-                        .get(&(i - 1).to_string())
-                        .ok_or(RenderError::CousinNotFound((i - 1) as i32))?;
+                        .get(&i.to_string())
+                        .ok_or(RenderError::CousinNotFound(i as i32))?;
 
-                    let val = child.render(components, cousin.statics(), None)?;
-                    out.push_str(&val);
-                    out.push_str(outer_static_item);
-                }
-                Ok(out)
+                    child
+                        .render_into(out, components, cousin.statics(), None)
+                        .map_err(|error| error.at(i))
+                })?;
             }
         }
+        Ok(())
     }
-    pub fn fix_statics(self) -> Self {
-        match self.statics {
-            ComponentStatics::ComponentRef(cid) if cid < 0 => Self {
-                children: self.children,
-                statics: ComponentStatics::ComponentRef(-cid),
-            },
-            _ => self,
+
+    /// Builds a component which shares the statics of `target`, by merging `children` into a copy of it
+    ///
+    /// Children without statics of their own inherit them from the corresponding child of `target`.
+    fn merge_onto(
+        target: &Component,
+        children: HashMap<String, ChildDiff>,
+    ) -> Result<Self, MergeError> {
+        let mut new_children = target.children.clone();
+        for (key, child_diff) in children.into_iter() {
+            let child = match new_children.remove(&key) {
+                Some(child) => child.merge(child_diff),
+                None => child_diff.try_into(),
+            }
+            .map_err(|error| error.at(&key))?;
+            new_children.insert(key, child);
         }
+        Ok(Self {
+            children: new_children,
+            statics: target.statics.clone(),
+        })
     }
 }
 
@@ -456,6 +901,25 @@ pub enum StreamInsert {
     Limit(Option<i32>),
 }
 
+/// Converts the dynamics of a comprehension diff, which always replace the current dynamics
+fn dynamics_from_diff(dynamics: DynamicsDiff) -> Result<Dynamics, MergeError> {
+    dynamics
+        .into_iter()
+        .enumerate()
+        .map(|(row, children)| {
+            children
+                .into_iter()
+                .enumerate()
+                .map(|(col, child)| {
+                    child
+                        .to_new_child()
+                        .map_err(|error| error.at(format!("d/{row}/{col}")))
+                })
+                .collect::<Result<Vec<Child>, MergeError>>()
+        })
+        .collect()
+}
+
 impl TryFrom<FragmentDiff> for Fragment {
     type Error = MergeError;
     fn try_from(value: FragmentDiff) -> Result<Self, MergeError> {
@@ -463,7 +927,10 @@ impl TryFrom<FragmentDiff> for Fragment {
             FragmentDiff::UpdateRegular { children, statics } => {
                 let mut new_children: HashMap<String, Child> = HashMap::new();
                 for (key, cdiff) in children.into_iter() {
-                    new_children.insert(key, cdiff.try_into()?);
+                    let child = cdiff
+                        .try_into()
+                        .map_err(|error: MergeError| error.at(&key))?;
+                    new_children.insert(key, child);
                 }
                 let statics = if let Some(statics) = statics {
                     statics
@@ -482,15 +949,7 @@ impl TryFrom<FragmentDiff> for Fragment {
                 statics,
                 stream,
             } => {
-                let dynamics: Dynamics = dynamics
-                    .into_iter()
-                    .map(|cdiff_vec| {
-                        cdiff_vec
-                            .into_iter()
-                            .map(|cdiff| cdiff.try_into())
-                            .collect::<Result<Vec<Child>, MergeError>>()
-                    })
-                    .collect::<Result<Vec<Vec<Child>>, MergeError>>()?;
+                let dynamics = dynamics_from_diff(dynamics)?;
                 let stream = if let Some(stream_updates) = stream {
                     let stream: Stream = Stream::try_from(stream_updates)?;
                     Some(stream)
@@ -583,7 +1042,13 @@ impl TryFrom<ComponentDiff> for Component {
     fn try_from(value: ComponentDiff) -> Result<Self, MergeError> {
         match value {
             ComponentDiff::UpdateRegular { .. } => Err(MergeError::CreateComponentFromUpdate),
-            ComponentDiff::ReplaceCurrent { children, statics } => Ok(Self { children, statics }),
+            ComponentDiff::ReplaceCurrent { children, statics } => {
+                let children = children
+                    .into_iter()
+                    .map(|(key, child)| Ok((key, child.try_into()?)))
+                    .collect::<Result<HashMap<String, Child>, MergeError>>()?;
+                Ok(Self { children, statics })
+            }
         }
     }
 }
@@ -593,7 +1058,7 @@ impl TryFrom<ComponentDiff> for Component {
 pub enum ComponentDiff {
     ReplaceCurrent {
         #[serde(flatten)]
-        children: HashMap<String, Child>,
+        children: HashMap<String, ChildDiff>,
         #[serde(rename = "s")]
         statics: ComponentStatics,
     },
@@ -609,6 +1074,12 @@ impl ComponentDiff {
     }
 }
 
+/// The statics of a component, as sent by the server
+///
+/// Components rendered from the same template share statics: instead of sending them again, the
+/// server refers to another component by CID. A positive CID refers to a component in the same diff,
+/// while a negative CID refers to a component the client already has. These references are
+/// resolved when merging, so merged components always carry their own statics.
 #[derive(Debug, Clone, PartialEq, Deserialize)]
 #[serde(untagged)]
 pub enum ComponentStatics {
@@ -628,13 +1099,7 @@ impl FragmentMerge for Root {
         let fragment = self.fragment.merge(diff.fragment)?;
         let components = match (self.components, diff.components) {
             (None, None) => None,
-            (None, Some(component_diff)) => {
-                let mut components: HashMap<String, Component> = HashMap::new();
-                for (key, comp) in component_diff.into_iter() {
-                    components.insert(key, comp.to_new_component()?);
-                }
-                Some(components)
-            }
+            (None, Some(component_diff)) => Some(HashMap::new().merge(component_diff)?),
             (Some(components), None) => Some(components),
             (Some(new_components), Some(component_diff)) => {
                 Some(new_components.merge(component_diff)?)
@@ -653,6 +1118,13 @@ impl FragmentMerge for Fragment {
     fn merge(self, diff: FragmentDiff) -> Result<Self, MergeError> {
         match (self, diff) {
             (_, FragmentDiff::ReplaceCurrent(new_fragment)) => Ok(new_fragment),
+            // A regular fragment which comes with its statics replaces the current one entirely
+            (
+                _,
+                diff @ FragmentDiff::UpdateRegular {
+                    statics: Some(_), ..
+                },
+            ) => diff.try_into(),
             (
                 Fragment::Regular {
                     children: current_children,
@@ -685,15 +1157,7 @@ impl FragmentMerge for Fragment {
             ) => {
                 let templates = current_templates.merge(new_templates)?;
                 let statics = current_statics.merge(new_statics)?;
-                let new_dynamics: Vec<Vec<Child>> = new_dynamics
-                    .into_iter()
-                    .map(|children_children| {
-                        children_children
-                            .into_iter()
-                            .map(|child| child.to_new_child())
-                            .collect::<Result<Vec<Child>, MergeError>>()
-                    })
-                    .collect::<Result<Vec<Vec<Child>>, MergeError>>()?;
+                let new_dynamics = dynamics_from_diff(new_dynamics)?;
                 let stream = match (current_stream, new_stream) {
                     (None, None) => {
                         current_dynamics = new_dynamics;
@@ -762,23 +1226,100 @@ impl FragmentMerge for Fragment {
         }
     }
 }
+/// Merges the components of a diff into the existing components
+///
+/// Components which aren't in the diff are kept as they are, so a CID the server sent earlier keeps
+/// resolving to the same component until it is pruned, see [`Root::prune_components`]. The JS client
+/// keeps components the same way, since a diff only contains the components which changed.
 impl FragmentMerge for HashMap<String, Component> {
     type DiffItem = HashMap<String, ComponentDiff>;
 
     fn merge(self, diff: Self::DiffItem) -> Result<Self, MergeError> {
-        let mut new_components: HashMap<String, Component> = HashMap::new();
-        for (cid, comp_diff) in diff.into_iter() {
-            if let Some(existing) = new_components.get_mut(&cid) {
-                *existing = existing.clone().merge(comp_diff)?;
-            } else {
-                new_components.insert(cid.clone(), comp_diff.to_new_component()?);
-            }
+        let cids = diff.keys().cloned().collect::<Vec<_>>();
+        let mut resolver = ComponentResolver {
+            old: &self,
+            pending: diff,
+            resolved: HashMap::with_capacity(cids.len()),
+            visiting: BTreeSet::new(),
+        };
+        for cid in cids.iter() {
+            resolver
+                .resolve(cid)
+                .map_err(|error| error.at(format!("c/{cid}")))?;
         }
 
+        let resolved = resolver.resolved;
+        let mut new_components = self;
+        new_components.extend(resolved);
         Ok(new_components)
     }
 }
 
+/// Resolves the components of a diff against the existing components, following the statics
+/// references between them.
+///
+/// This mirrors how the LiveView JS client merges components: a component referring to another
+/// component's statics is built from a copy of that component with its own diff merged in.
+struct ComponentResolver<'a> {
+    /// The components prior to the diff
+    old: &'a HashMap<String, Component>,
+    /// Component diffs which have not been resolved yet
+    pending: HashMap<String, ComponentDiff>,
+    /// The merged components of the diff
+    resolved: HashMap<String, Component>,
+    /// Components currently being resolved, used to detect reference cycles
+    visiting: BTreeSet<String>,
+}
+impl ComponentResolver<'_> {
+    fn resolve(&mut self, cid: &str) -> Result<&Component, MergeError> {
+        if !self.resolved.contains_key(cid) {
+            let component = self.resolve_pending(cid)?;
+            self.resolved.insert(cid.to_string(), component);
+        }
+        Ok(&self.resolved[cid])
+    }
+
+    fn resolve_pending(&mut self, cid: &str) -> Result<Component, MergeError> {
+        let Some(diff) = self.pending.remove(cid) else {
+            return Err(if self.visiting.contains(cid) {
+                MergeError::ComponentStaticsCycle {
+                    cid: cid.parse().unwrap_or_default(),
+                }
+            } else {
+                MergeError::ComponentNotFound {
+                    cid: cid.parse().unwrap_or_default(),
+                }
+            });
+        };
+
+        self.visiting.insert(cid.to_string());
+        let component = match diff {
+            ComponentDiff::ReplaceCurrent {
+                children,
+                statics: ComponentStatics::ComponentRef(target),
+            } => {
+                if target > 0 {
+                    let target = self.resolve(&target.to_string())?;
+                    Component::merge_onto(target, children)?
+                } else {
+                    let target = self
+                        .old
+                        .get(&(-target).to_string())
+                        .ok_or(MergeError::ComponentNotFound { cid: -target })?;
+                    Component::merge_onto(target, children)?
+                }
+            }
+            diff @ ComponentDiff::ReplaceCurrent { .. } => diff.try_into()?,
+            diff @ ComponentDiff::UpdateRegular { .. } => match self.old.get(cid) {
+                Some(existing) => existing.clone().merge(diff)?,
+                None => diff.try_into()?,
+            },
+        };
+        self.visiting.remove(cid);
+        Ok(component)
+    }
+}
+
 impl FragmentMerge for Component {
     type DiffItem = ComponentDiff;
 
@@ -794,9 +1335,11 @@ impl FragmentMerge for Component {
                     statics: self.statics,
                 })
             }
-            ComponentDiff::ReplaceCurrent { statics, children } => {
-                Ok(Self { children, statics }.fix_statics())
-            }
+            ComponentDiff::ReplaceCurrent {
+                statics: ComponentStatics::ComponentRef(_),
+                ..
+            } => Err(MergeError::UnresolvedComponentStatics),
+            diff @ ComponentDiff::ReplaceCurrent { .. } => diff.try_into(),
         }
     }
 }
@@ -842,9 +1385,12 @@ impl FragmentMerge for HashMap<String, Child> {
         let mut new_children = self;
         for (index, comp_diff) in diff.into_iter() {
             if let Some(child) = new_children.get_mut(&index) {
-                *child = child.clone().merge(comp_diff)?;
+                *child = child
+                    .clone()
+                    .merge(comp_diff)
+                    .map_err(|error| error.at(&index))?;
             } else {
-                return Err(MergeError::AddChildToExisting);
+                return Err(MergeError::AddChildToExisting.at(index));
             }
         }
         Ok(new_children)
@@ -852,6 +1398,7 @@ impl FragmentMerge for HashMap<String, Child> {
 }
 
 #[derive(Debug, thiserror::Error, uniffi::Error)]
+#[uniffi(flat_error)]
 pub enum MergeError {
     #[error("Fragment type mismatch")]
     FragmentTypeMismatch,
@@ -863,11 +1410,40 @@ pub enum MergeError {
     AddChildToExisting,
     #[error("There was a id mismatch when merging a stream")]
     StreamIDMisMatch,
+    #[error("Component {cid} not found")]
+    ComponentNotFound { cid: i32 },
+    #[error("Component {cid} refers to its own statics")]
+    ComponentStaticsCycle { cid: i32 },
+    #[error("Component statics must be resolved against the other components of the diff")]
+    UnresolvedComponentStatics,
     #[error("Stream Error {error}")]
     Stream {
         #[from]
         error: StreamConversionError,
     },
+    #[error("{error} at {path}")]
+    AtPath {
+        path: String,
+        error: Box<MergeError>,
+    },
+}
+
+impl MergeError {
+    /// Records that this error occurred under `segment` of the fragment tree
+    ///
+    /// Segments are prepended as the error propagates, so the path reads from the root downwards.
+    pub fn at<S: fmt::Display>(self, segment: S) -> Self {
+        match self {
+            Self::AtPath { path, error } => Self::AtPath {
+                path: format!("{segment}/{path}"),
+                error,
+            },
+            error => Self::AtPath {
+                path: segment.to_string(),
+                error: Box::new(error),
+            },
+        }
+    }
 }
 
 #[derive(Debug, thiserror::Error, uniffi::Error)]
@@ -0,0 +1,571 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+use pretty_assertions::assert_eq;
+
+use super::*;
+use crate::dom::{
+    failpoint::{self, Stage},
+    AttributeChange, ChangeType, ComponentChangeHandler, ComponentChangeType, Document,
+    DocumentChangeHandler, NodeData, NodeRef,
+};
+
+const COMPONENT_IDS: RenderOptions = RenderOptions {
+    component_ids: true,
+};
+
+const INITIAL: &str = r#"{
+  "0": 1,
+  "1": 2,
+  "c": {
+    "1": {"0": "one", "s": ["<Text id=\"first\">", "</Text>"]},
+    "2": {"0": "two", "s": ["<Group>\n  <Text>", "</Text>\n</Group>"]}
+  },
+  "s": ["<VStack>\n  <Text>header</Text>\n  ", "\n  ", "\n</VStack>"]
+}"#;
+
+#[test]
+fn component_merge_preserves_components() {
+    let root: RootDiff = serde_json::from_str(INITIAL).expect("Failed to deserialize fragment");
+    let root: Root = root.try_into().expect("Failed to convert RootDiff to Root");
+
+    let diff: RootDiff = serde_json::from_str(r#"{"c": {"2": {"0": "three"}}}"#)
+        .expect("Failed to deserialize fragment");
+    let (root, changes) = root.merge_with_changes(diff).expect("Failed to merge diff");
+    assert_eq!(
+        changes,
+        RootChanges {
+            fragment_changed: false,
+            components: BTreeSet::from([2]),
+        }
+    );
+
+    let out: String = root.try_into().expect("Failed to convert Root into string");
+    let expected = r#"<VStack>
+  <Text>header</Text>
+  <Text id="first">one</Text>
+  <Group>
+  <Text>three</Text>
+</Group>
+</VStack>"#;
+    assert_eq!(out, expected);
+}
+
+#[test]
+fn component_ids_render_option() {
+    let root: RootDiff = serde_json::from_str(INITIAL).expect("Failed to deserialize fragment");
+    let root: Root = root.try_into().expect("Failed to convert RootDiff to Root");
+
+    // Components are only tagged with their CID when asked to
+    let out = root
+        .render(RenderOptions::default())
+        .expect("Failed to render");
+    assert!(!out.contains(PHX_COMPONENT));
+    let out = root.render(COMPONENT_IDS).expect("Failed to render");
+    let expected = r#"<VStack>
+  <Text>header</Text>
+  <Text data-phx-component="1" id="first">one</Text>
+  <Group data-phx-component="2">
+  <Text>two</Text>
+</Group>
+</VStack>"#;
+    assert_eq!(out, expected);
+    assert_eq!(
+        root.render_component(1, COMPONENT_IDS)
+            .expect("Failed to render component"),
+        r#"<Text data-phx-component="1" id="first">one</Text>"#
+    );
+    assert_eq!(
+        root.render_component(1, RenderOptions::default())
+            .expect("Failed to render component"),
+        r#"<Text id="first">one</Text>"#
+    );
+
+    let document = root
+        .render_document(RenderOptions::default())
+        .expect("Failed to render document");
+    assert!(document.component_nodes().is_empty());
+    let document = root
+        .render_document(COMPONENT_IDS)
+        .expect("Failed to render document");
+    assert_eq!(document.component_nodes().len(), 2);
+}
+
+#[test]
+fn component_map_merge_keeps_existing_components() {
+    let root: RootDiff = serde_json::from_str(INITIAL).expect("Failed to deserialize fragment");
+    let root: Root = root.try_into().expect("Failed to convert RootDiff to Root");
+    let components = root.components.clone().unwrap();
+
+    let diff: HashMap<String, ComponentDiff> = serde_json::from_str(
+        r#"{"2": {"0": "three"}, "3": {"0": "new", "s": ["<Label>", "</Label>"]}}"#,
+    )
+    .expect("Failed to deserialize components");
+    let merged = components
+        .clone()
+        .merge(diff)
+        .expect("Failed to merge components");
+    assert_eq!(merged.len(), 3);
+    // Components which aren't in the diff are kept as they were
+    assert_eq!(merged.get("1"), components.get("1"));
+    assert_ne!(merged.get("2"), components.get("2"));
+
+    let merged = Some(merged);
+    let render = |cid: i32| Child::ComponentID(cid).render(&merged, None, None);
+    assert_eq!(render(1).unwrap(), r#"<Text id="first">one</Text>"#);
+    assert_eq!(render(3).unwrap(), "<Label>new</Label>");
+}
+
+#[test]
+fn component_merge_copies_shared_statics() {
+    let root: RootDiff = serde_json::from_str(
+        r#"{
+  "0": 1,
+  "1": 2,
+  "c": {
+    "1": {"0": "one", "s": ["<Text>", "</Text>"]},
+    "2": {"0": "two", "s": 1}
+  },
+  "s": ["<VStack>", "", "</VStack>"]
+}"#,
+    )
+    .expect("Failed to deserialize fragment");
+    let root: Root = root.try_into().expect("Failed to convert RootDiff to Root");
+
+    let diff: RootDiff =
+        serde_json::from_str(r#"{"c": {"1": {"0": "uno", "s": ["<Label>", "</Label>"]}}}"#)
+            .expect("Failed to deserialize fragment");
+    let (root, changes) = root.merge_with_changes(diff).expect("Failed to merge diff");
+    assert!(!changes.fragment_changed);
+    // Component 2 got its own copy of the statics when it was created
+    assert_eq!(changes.components, BTreeSet::from([1]));
+    assert_eq!(
+        root.render_component(2, RenderOptions::default())
+            .expect("Failed to render component"),
+        r#"<Text>two</Text>"#
+    );
+
+    let diff: RootDiff =
+        serde_json::from_str(r#"{"0": 2}"#).expect("Failed to deserialize fragment");
+    let (_root, changes) = root.merge_with_changes(diff).expect("Failed to merge diff");
+    assert!(changes.fragment_changed);
+    assert!(changes.components.is_empty());
+}
+
+#[derive(Default)]
+struct Recorder {
+    changes: Mutex<Vec<(ChangeType, NodeData)>>,
+    components: Mutex<Vec<(ComponentChangeType, i32, Option<NodeRef>)>>,
+}
+
+struct Handler(Arc<Recorder>);
+
+impl DocumentChangeHandler for Handler {
+    fn handle(
+        &self,
+        change_type: ChangeType,
+        _node_ref: Arc<NodeRef>,
+        node_data: NodeData,
+        _parent: Option<Arc<NodeRef>>,
+        _attributes: Vec<AttributeChange>,
+        _path: Option<String>,
+    ) {
+        self.0
+            .changes
+            .lock()
+            .unwrap()
+            .push((change_type, node_data));
+    }
+}
+
+impl ComponentChangeHandler for Handler {
+    fn handle(&self, change_type: ComponentChangeType, cid: i32, node_ref: Option<Arc<NodeRef>>) {
+        self.0
+            .components
+            .lock()
+            .unwrap()
+            .push((change_type, cid, node_ref.map(|node| *node)));
+    }
+}
+
+#[test]
+fn component_targeted_render() {
+    let mut doc = Document::parse_fragment_json(INITIAL.into()).expect("Failed to parse");
+    let recorder = Arc::new(Recorder::default());
+    doc.event_callback = Some(Arc::new(Handler(recorder.clone())));
+    doc.component_callback = Some(Arc::new(Handler(recorder.clone())));
+
+    let components = doc.component_nodes();
+    assert_eq!(components.len(), 2);
+    let first = doc.get_by_id("first").unwrap();
+    assert_eq!(components[&1], first);
+    let group = components[&2];
+
+    doc.merge_fragment_json(r#"{"c": {"2": {"0": "three"}}}"#.into())
+        .expect("Failed to merge");
+
+    // Only the leaf inside the changed component was touched
+    let changes = recorder.changes.lock().unwrap();
+    assert_eq!(changes.len(), 1);
+    assert!(matches!(
+        &changes[0],
+        (ChangeType::UpdateText, NodeData::Leaf { value }) if value == "three"
+    ));
+    assert_eq!(
+        *recorder.components.lock().unwrap(),
+        vec![(ComponentChangeType::Change, 2, Some(group))]
+    );
+    assert_eq!(
+        doc.component_nodes(),
+        BTreeMap::from([(1, first), (2, group)])
+    );
+
+    let expected = Document::parse(
+        r#"<VStack>
+  <Text>header</Text>
+  <Text data-phx-component="1" id="first">one</Text>
+  <Group data-phx-component="2"><Text>three</Text></Group>
+</VStack>"#,
+    )
+    .unwrap();
+    assert_eq!(doc.to_string(), expected.to_string());
+}
+
+#[test]
+fn component_root_change_falls_back_to_full_render() {
+    let mut doc = Document::parse_fragment_json(INITIAL.into()).expect("Failed to parse");
+
+    doc.merge_fragment_json(r#"{"c": {"1": {"0": "uno", "s": ["<Label>", "</Label>"]}}}"#.into())
+        .expect("Failed to merge");
+
+    let expected = Document::parse(
+        r#"<VStack>
+  <Text>header</Text>
+  <Label data-phx-component="1">uno</Label>
+  <Group data-phx-component="2"><Text>two</Text></Group>
+</VStack>"#,
+    )
+    .unwrap();
+    assert_eq!(doc.to_string(), expected.to_string());
+}
+
+#[test]
+fn component_orphans() {
+    let root: RootDiff = serde_json::from_str(
+        r#"{
+  "0": 1,
+  "1": 2,
+  "c": {
+    "1": {"0": 3, "s": ["<Group>", "</Group>"]},
+    "2": {"0": "two", "s": 4},
+    "3": {"0": "three", "s": ["<Text>", "</Text>"]},
+    "4": {"0": "four", "s": ["<Label>", "</Label>"]},
+    "5": {"0": "five", "s": ["<Text>", "</Text>"]}
+  },
+  "s": ["<VStack>", "", "</VStack>"]
+}"#,
+    )
+    .expect("Failed to deserialize fragment");
+    let mut root: Root = root.try_into().expect("Failed to convert RootDiff to Root");
+    // 3 is nested in 1, while 2 only borrowed the statics of 4 when it was created
+    assert_eq!(root.orphaned_components(), BTreeSet::from([4, 5]));
+
+    let diff: RootDiff =
+        serde_json::from_str(r#"{"0": ""}"#).expect("Failed to deserialize fragment");
+    root = root.merge(diff).expect("Failed to merge diff");
+    assert_eq!(root.orphaned_components(), BTreeSet::from([1, 3, 4, 5]));
+
+    root.prune_components([1, 3, 4, 5]);
+    assert!(root.orphaned_components().is_empty());
+    let out: String = root.try_into().expect("Failed to convert Root into string");
+    assert_eq!(out, r#"<VStack><Label>two</Label></VStack>"#);
+}
+
+#[test]
+fn component_destroy_events() {
+    let mut doc = Document::parse_fragment_json(INITIAL.into()).expect("Failed to parse");
+    let recorder = Arc::new(Recorder::default());
+    doc.component_callback = Some(Arc::new(Handler(recorder.clone())));
+
+    doc.merge_fragment_json(r#"{"0": ""}"#.into())
+        .expect("Failed to merge");
+
+    // Orphans are kept until the server acknowledged their destruction
+    assert!(recorder.components.lock().unwrap().is_empty());
+    assert_eq!(doc.component_nodes().keys().collect::<Vec<_>>(), vec![&2]);
+    assert_eq!(doc.orphaned_components(), vec![1]);
+    assert!(doc
+        .fragment_template
+        .as_ref()
+        .unwrap()
+        .render_component(1, RenderOptions::default())
+        .is_ok());
+
+    // 2 is orphaned by the next diff after `cids_will_destroy` was sent, and rendered again by the one after
+    doc.merge_fragment_json(r#"{"1": ""}"#.into())
+        .expect("Failed to merge");
+    assert_eq!(doc.orphaned_components(), vec![1, 2]);
+    doc.merge_fragment_json(r#"{"1": 2}"#.into())
+        .expect("Failed to merge");
+    assert_eq!(doc.destroy_components(vec![1, 2]), vec![1]);
+
+    assert_eq!(
+        *recorder.components.lock().unwrap(),
+        vec![(ComponentChangeType::Destroy, 1, None)]
+    );
+    assert_eq!(doc.component_nodes().keys().collect::<Vec<_>>(), vec![&2]);
+    let template = doc.fragment_template.as_ref().unwrap();
+    assert!(template.orphaned_components().is_empty());
+    assert!(template
+        .render_component(1, RenderOptions::default())
+        .is_err());
+}
+
+#[test]
+fn component_shared_statics_fixture() {
+    let root: RootDiff = serde_json::from_str(include_str!("component-statics-0.json"))
+        .expect("Failed to deserialize fragment");
+    let root: Root = root.try_into().expect("Failed to convert RootDiff to Root");
+    let out: String = root
+        .clone()
+        .try_into()
+        .expect("Failed to convert Root into string");
+    assert_eq!(format!("{out}\n"), include_str!("component-statics-0.html"));
+
+    // Component 3 refers to the statics of the existing component 1, while component 4 refers to
+    // component 3 which is introduced by the same diff
+    let diff: RootDiff = serde_json::from_str(include_str!("component-statics-1.json"))
+        .expect("Failed to deserialize fragment");
+    let root = root.merge(diff).expect("Failed to merge diff");
+    let out: String = root.try_into().expect("Failed to convert Root into string");
+    assert_eq!(format!("{out}\n"), include_str!("component-statics-1.html"));
+}
+
+#[test]
+fn component_invalid_statics_refs() {
+    let root: RootDiff = serde_json::from_str(include_str!("component-statics-0.json"))
+        .expect("Failed to deserialize fragment");
+    let root: Root = root.try_into().expect("Failed to convert RootDiff to Root");
+
+    // Positive references must point to a component in the same diff
+    let diff: RootDiff = serde_json::from_str(
+        r#"{"c": {"3": {"0": "item-3", "1": "Eggs", "2": {"d": []}, "s": 1}}}"#,
+    )
+    .expect("Failed to deserialize fragment");
+    let Err(MergeError::AtPath { path, error }) = root.clone().merge(diff) else {
+        panic!("expected merge to fail");
+    };
+    assert_eq!(path, "c/3");
+    assert!(matches!(*error, MergeError::ComponentNotFound { cid: 1 }));
+
+    // Negative references must point to an existing component
+    let diff: RootDiff = serde_json::from_str(
+        r#"{"c": {"3": {"0": "item-3", "1": "Eggs", "2": {"d": []}, "s": -5}}}"#,
+    )
+    .expect("Failed to deserialize fragment");
+    let Err(MergeError::AtPath { path, error }) = root.clone().merge(diff) else {
+        panic!("expected merge to fail");
+    };
+    assert_eq!(path, "c/3");
+    assert!(matches!(*error, MergeError::ComponentNotFound { cid: 5 }));
+
+    let diff: RootDiff =
+        serde_json::from_str(r#"{"c": {"3": {"0": "a", "s": 4}, "4": {"0": "b", "s": 3}}}"#)
+            .expect("Failed to deserialize fragment");
+    let Err(MergeError::AtPath { error, .. }) = root.merge(diff) else {
+        panic!("expected merge to fail");
+    };
+    assert!(matches!(*error, MergeError::ComponentStaticsCycle { .. }));
+}
+
+#[test]
+fn component_nesting_cycle() {
+    let root: RootDiff =
+        serde_json::from_str(include_str!("fuzz-corpus/component-nesting-cycle.json"))
+            .expect("Failed to deserialize fragment");
+    let root: Root = root.try_into().expect("Failed to convert RootDiff to Root");
+
+    let out: Result<String, _> = root.clone().try_into();
+    assert!(matches!(out, Err(RenderError::ComponentNestingCycle(1))));
+    assert!(matches!(
+        root.render_component(2, RenderOptions::default()),
+        Err(RenderError::ComponentNestingCycle(2))
+    ));
+}
+
+#[test]
+fn render_document_matches_parsed_render() {
+    let fixtures = [
+        INITIAL,
+        // The first component starts in an unterminated attribute value, and the second shares its root
+        r#"{
+  "0": 1,
+  "c": {
+    "1": {"0": 2, "s": ["<Item title='", "'><!-- x --></Item>"]},
+    "2": {"0": "x", "s": ["<Text>", "</Text>"]}
+  },
+  "s": ["<List>", "</List>"]
+}"#,
+        r#"{
+  "0": 1,
+  "c": {
+    "1": {"0": 2, "s": ["<!-- outer -->", ""]},
+    "2": {"s": ["<Text/>"]}
+  },
+  "s": ["<List>", "</List>"]
+}"#,
+        include_str!("flow-1-change-0.json"),
+        include_str!("component-statics-0.json"),
+    ];
+    for fixture in fixtures {
+        let root: RootDiff = serde_json::from_str(fixture).expect("Failed to deserialize fragment");
+        let root: Root = root.try_into().expect("Failed to convert RootDiff to Root");
+
+        for options in [RenderOptions::default(), COMPONENT_IDS] {
+            let document = root
+                .render_document(options)
+                .expect("Failed to render document");
+            let out = root.render(options).expect("Failed to render");
+            let expected = Document::parse(out).expect("Failed to parse rendered fragment");
+            assert_eq!(document.to_bytes(), expected.to_bytes());
+
+            let cids = root
+                .components
+                .iter()
+                .flat_map(|components| components.keys());
+            for cid in cids.filter_map(|cid| cid.parse::<i32>().ok()) {
+                let document = root
+                    .render_component_document(cid, options)
+                    .expect("Failed to render component document");
+                let out = root
+                    .render_component(cid, options)
+                    .expect("Failed to render component");
+                let expected = Document::parse(out).expect("Failed to parse rendered component");
+                assert_eq!(document.to_bytes(), expected.to_bytes());
+            }
+        }
+    }
+}
+
+/// The nodes of `doc` in document order, with their data and children
+fn tree(doc: &Document) -> Vec<(NodeRef, NodeData, Vec<NodeRef>)> {
+    let mut nodes = vec![];
+    let mut stack = vec![doc.root()];
+    while let Some(node) = stack.pop() {
+        let children = doc.children(node).to_vec();
+        stack.extend(children.iter().rev());
+        nodes.push((node, doc.get(node).clone(), children));
+    }
+    nodes
+}
+
+#[test]
+fn component_merge_failures_leave_document_intact() {
+    let diffs = [
+        // Patches only the subtree of a component
+        r#"{"c": {"2": {"0": "three"}}}"#,
+        // Re-renders the whole document, moving both components
+        r#"{"0": 2, "1": 1}"#,
+        // Adds a component and orphans another, which is kept in the template
+        r#"{"1": 3, "c": {"1": {"0": "uno"}, "3": {"0": "new", "s": ["<Label>", "</Label>"]}}}"#,
+    ];
+    let stages = [Stage::Decode, Stage::Merge, Stage::Render, Stage::Patch];
+    for diff in diffs {
+        let mut expected = Document::parse_fragment_json(INITIAL.into()).expect("Failed to parse");
+        expected
+            .merge_fragment_json(diff.into())
+            .expect("Failed to merge");
+
+        let mut doc = Document::parse_fragment_json(INITIAL.into()).expect("Failed to parse");
+        let recorder = Arc::new(Recorder::default());
+        doc.event_callback = Some(Arc::new(Handler(recorder.clone())));
+        doc.component_callback = Some(Arc::new(Handler(recorder.clone())));
+        let nodes = tree(&doc);
+        let template = doc.fragment_template.clone();
+
+        // Fail at every occurrence of every stage, until the stage is no longer reached
+        for stage in stages {
+            for skip in 0.. {
+                failpoint::arm(stage, skip);
+                let result = doc.merge_fragment_json(diff.into());
+                if failpoint::disarm() {
+                    result.expect("Failed to merge");
+                    break;
+                }
+                let err = result.expect_err("expected merge to fail");
+                assert!(err.to_string().contains("injected"), "{diff} {err}");
+                assert_eq!(tree(&doc), nodes, "{diff} {stage:?} {skip}");
+                assert!(doc.fragment_template == template, "{diff} {stage:?} {skip}");
+                assert_eq!(doc.version(), 0, "{diff} {stage:?} {skip}");
+                assert!(recorder.components.lock().unwrap().is_empty());
+            }
+            // The merge succeeded when the failpoint wasn't reached, so start over
+            assert_eq!(doc.to_string(), expected.to_string());
+            assert!(doc.fragment_template == expected.fragment_template);
+            assert_eq!(doc.version(), 1);
+            doc = Document::parse_fragment_json(INITIAL.into()).expect("Failed to parse");
+            doc.event_callback = Some(Arc::new(Handler(recorder.clone())));
+            doc.component_callback = Some(Arc::new(Handler(recorder.clone())));
+            recorder.components.lock().unwrap().clear();
+        }
+    }
+}
+
+#[test]
+fn merge_failure_undoes_only_applied_patches() {
+    let initial =
+        r#"{"0": "a", "1": "b", "s": ["<VStack><Text>", "</Text><Text>", "</Text></VStack>"]}"#;
+    let mut doc = Document::parse_fragment_json(initial.into()).expect("Failed to parse");
+    let recorder = Arc::new(Recorder::default());
+    doc.event_callback = Some(Arc::new(Handler(recorder.clone())));
+    let nodes = tree(&doc);
+
+    // The diff updates both texts, but the second patch fails
+    failpoint::arm(Stage::Patch, 1);
+    let result = doc.merge_fragment_json(r#"{"0": "x", "1": "y"}"#.into());
+    assert!(!failpoint::disarm());
+    assert!(matches!(result, Err(RenderError::PatchError(_))));
+    assert_eq!(tree(&doc), nodes);
+
+    // The handler sees the first patch and its undo, but nothing of the patch which failed
+    let changes = recorder.changes.lock().unwrap();
+    let texts = changes
+        .iter()
+        .map(|change| match change {
+            (ChangeType::UpdateText, NodeData::Leaf { value }) => value.as_str(),
+            _ => panic!("expected only text updates"),
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(texts, ["x", "a"]);
+}
+
+#[test]
+fn component_render_failure_keeps_template() {
+    let mut doc = Document::parse_fragment_json(INITIAL.into()).expect("Failed to parse");
+    let nodes = tree(&doc);
+    let template = doc.fragment_template.clone();
+
+    // The diff merges, but component 1 now renders itself
+    let result = doc.merge_fragment_json(r#"{"c": {"1": {"0": 1}}}"#.into());
+    assert!(matches!(result, Err(RenderError::ComponentNestingCycle(1))));
+    assert_eq!(tree(&doc), nodes);
+    assert!(doc.fragment_template == template);
+
+    // Later diffs are merged into the template the document was rendered from
+    doc.merge_fragment_json(r#"{"c": {"2": {"0": "three"}}}"#.into())
+        .expect("Failed to merge");
+    assert_eq!(
+        doc.to_string(),
+        Document::parse(
+            r#"<VStack>
+  <Text>header</Text>
+  <Text data-phx-component="1" id="first">one</Text>
+  <Group data-phx-component="2"><Text>three</Text></Group>
+</VStack>"#,
+        )
+        .unwrap()
+        .to_string()
+    );
+}
@@ -0,0 +1,201 @@
+use std::panic::{self, AssertUnwindSafe};
+
+use serde_json::Value;
+
+use super::*;
+use crate::dom::Document;
+
+/// Inputs the fuzzer starts from: recorded server output, plus inputs which used to crash
+const CORPUS: &[&str] = &[
+    include_str!("flow-1-change-0.json"),
+    include_str!("flow-1-change-1.json"),
+    include_str!("flow-1-change-2.json"),
+    include_str!("flow-1-change-3.json"),
+    include_str!("component-statics-0.json"),
+    include_str!("component-statics-1.json"),
+    include_str!("fuzz-corpus/component-cycle.json"),
+    include_str!("fuzz-corpus/component-nesting-cycle.json"),
+    include_str!("fuzz-corpus/component-self-ref.json"),
+    include_str!("fuzz-corpus/conflicting-statics.json"),
+    include_str!("fuzz-corpus/empty-statics.json"),
+    include_str!("fuzz-corpus/empty-template.json"),
+    include_str!("fuzz-corpus/missing-template.json"),
+    include_str!("fuzz-corpus/short-dynamics.json"),
+    include_str!("fuzz-corpus/stray-end-tag.json"),
+    include_str!("fuzz-corpus/stream.json"),
+];
+
+const MUTANTS_PER_INPUT: usize = 150;
+
+/// A small deterministic PRNG (xorshift64*), so failures are reproducible
+struct Rng(u64);
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+/// Returns a JSON pointer to every value in `value`
+fn pointers(value: &Value, prefix: String, out: &mut Vec<String>) {
+    match value {
+        Value::Object(map) => {
+            for (key, child) in map.iter() {
+                let escaped = key.replace('~', "~0").replace('/', "~1");
+                pointers(child, format!("{prefix}/{escaped}"), out);
+            }
+        }
+        Value::Array(items) => {
+            for (i, child) in items.iter().enumerate() {
+                pointers(child, format!("{prefix}/{i}"), out);
+            }
+        }
+        _ => (),
+    }
+    out.push(prefix);
+}
+
+fn random_scalar(rng: &mut Rng) -> Value {
+    const STRINGS: &[&str] = &[
+        "",
+        "<p>",
+        "</div>",
+        "<a href=\"#\">",
+        " id=\"x\"",
+        "&amp;",
+        "s",
+    ];
+    match rng.below(6) {
+        0 => Value::Null,
+        1 => Value::Bool(rng.below(2) == 0),
+        2 => Value::from(rng.below(9) as i64 - 4),
+        3 => Value::String(STRINGS[rng.below(STRINGS.len())].to_string()),
+        4 => Value::Array(vec![]),
+        _ => Value::Object(Default::default()),
+    }
+}
+
+fn mutate(value: &mut Value, rng: &mut Rng) {
+    let mut all = vec![];
+    pointers(value, String::new(), &mut all);
+    let target = all[rng.below(all.len())].clone();
+    let donor = value
+        .pointer(&all[rng.below(all.len())])
+        .cloned()
+        .unwrap_or(Value::Null);
+
+    let Some(node) = value.pointer_mut(&target) else {
+        return;
+    };
+    match rng.below(6) {
+        // Replace with a scalar or empty container
+        0 => *node = random_scalar(rng),
+        // Replace with another part of the input
+        1 => *node = donor,
+        // Remove an entry
+        2 => match node {
+            Value::Object(map) if !map.is_empty() => {
+                let key = map.keys().nth(rng.below(map.len())).cloned().unwrap();
+                map.remove(&key);
+            }
+            Value::Array(items) if !items.is_empty() => {
+                items.remove(rng.below(items.len()));
+            }
+            _ => *node = Value::Null,
+        },
+        // Duplicate an entry
+        3 => {
+            if let Value::Array(items) = node {
+                if !items.is_empty() {
+                    let item = items[rng.below(items.len())].clone();
+                    items.push(item);
+                }
+            }
+        }
+        // Add a key with special meaning
+        4 => {
+            if let Value::Object(map) = node {
+                const KEYS: &[&str] = &["s", "d", "p", "c", "0", "1", "5", "stream"];
+                let key = KEYS[rng.below(KEYS.len())].to_string();
+                let value = if rng.below(2) == 0 {
+                    donor
+                } else {
+                    random_scalar(rng)
+                };
+                map.insert(key, value);
+            }
+        }
+        // Negate numbers, which are used as component and template references
+        _ => {
+            if let Some(n) = node.as_i64() {
+                *node = Value::from(-n);
+            }
+        }
+    }
+}
+
+/// Runs an input through every stage of the pipeline, ignoring errors
+fn exercise(input: &str, next: &str) {
+    if let Ok(diff) = serde_json::from_str::<RootDiff>(input) {
+        if let Ok(root) = Root::try_from(diff) {
+            let _: Result<String, _> = root.clone().try_into();
+            let _ = root.orphaned_components();
+            if let Ok(next) = serde_json::from_str::<RootDiff>(next) {
+                if let Ok((root, _)) = root.merge_with_changes(next) {
+                    let _: Result<String, _> = root.try_into();
+                }
+            }
+        }
+    }
+    if let Ok(root) = serde_json::from_str::<Root>(input) {
+        let _: Result<String, _> = root.try_into();
+    }
+    if let Ok(mut doc) = Document::parse_fragment_json(input.to_string()) {
+        let _ = doc.merge_fragment_json(next.to_string());
+    }
+}
+
+#[test]
+fn fuzz_root_diff() {
+    let seeds = CORPUS
+        .iter()
+        .map(|input| serde_json::from_str::<Value>(input).expect("corpus input is valid json"))
+        .collect::<Vec<_>>();
+
+    let mut rng = Rng(0x5EED_CAFE_F00D_D00D);
+    let mut failures = vec![];
+    for (i, seed) in seeds.iter().enumerate() {
+        let next_seed = &seeds[(i + 1) % seeds.len()];
+        let corpus = [seed.to_string(), next_seed.to_string()];
+        if panic::catch_unwind(|| exercise(&corpus[0], &corpus[1])).is_err() {
+            failures.push(corpus[0].clone());
+        }
+
+        for _ in 0..MUTANTS_PER_INPUT {
+            let mut input = seed.clone();
+            let mut next = next_seed.clone();
+            for _ in 0..=rng.below(3) {
+                mutate(&mut input, &mut rng);
+            }
+            mutate(&mut next, &mut rng);
+            let (input, next) = (input.to_string(), next.to_string());
+            let result = panic::catch_unwind(AssertUnwindSafe(|| exercise(&input, &next)));
+            if result.is_err() {
+                failures.push(format!("{input}\n{next}"));
+            }
+        }
+    }
+
+    assert!(
+        failures.is_empty(),
+        "{} inputs panicked, the first was:\n{}",
+        failures.len(),
+        failures[0]
+    );
+}
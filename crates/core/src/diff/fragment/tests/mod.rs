@@ -1,6 +1,8 @@
 use pretty_assertions::assert_eq;
 
 use super::*;
+mod components;
+mod fuzz;
 mod stream;
 
 #[test]
@@ -0,0 +1,117 @@
+use fxhash::FxHashMap;
+
+use crate::dom::{AttributeName, Document, Element, NodeData, NodeRef};
+
+/// Decides which elements of two documents are the same element, so that morphing can move them
+/// rather than recreate them
+///
+/// Keyed elements are only matched up when both their tag and key agree.
+pub trait NodeKey: Send + Sync {
+    /// Returns the key of `element`, or `None` if it isn't keyed
+    fn key(&self, element: &Element) -> Option<String>;
+}
+
+impl<F> NodeKey for F
+where
+    F: Fn(&Element) -> Option<String> + Send + Sync,
+{
+    fn key(&self, element: &Element) -> Option<String> {
+        self(element)
+    }
+}
+
+/// Keys elements by their `id` attribute, which is what [`super::diff`] uses
+#[derive(Debug, Default, Clone, Copy)]
+pub struct IdKey;
+impl NodeKey for IdKey {
+    fn key(&self, element: &Element) -> Option<String> {
+        element.id()
+    }
+}
+
+/// Keys elements by the first attribute from a list which they have a value for
+///
+/// For example, `AttributeKeys::new(["phx-key", "key", "id"])` keys list items by `phx-key`, falling back to
+/// `key` and then `id`.
+#[derive(Debug, Clone)]
+pub struct AttributeKeys(Vec<AttributeName>);
+impl AttributeKeys {
+    pub fn new<I, N>(names: I) -> Self
+    where
+        I: IntoIterator<Item = N>,
+        N: Into<AttributeName>,
+    {
+        Self(names.into_iter().map(Into::into).collect())
+    }
+}
+impl NodeKey for AttributeKeys {
+    fn key(&self, element: &Element) -> Option<String> {
+        self.0.iter().find_map(|name| {
+            element
+                .attributes
+                .iter()
+                .find(|attr| attr.name.eq(name))
+                .and_then(|attr| attr.value.clone())
+        })
+    }
+}
+
+/// The key strategy used while morphing one document into another
+pub(super) struct Keys<'a> {
+    strategy: &'a dyn NodeKey,
+    /// The keyed nodes of the documents being morphed, or `None` when keyed by id, as documents already
+    /// index their nodes by id
+    index: Option<[(&'a Document, FxHashMap<String, NodeRef>); 2]>,
+}
+impl<'a> Keys<'a> {
+    /// Keys nodes by id
+    pub fn ids() -> Self {
+        Self {
+            strategy: &IdKey,
+            index: None,
+        }
+    }
+
+    /// Keys nodes using `strategy`, indexing the keyed nodes of both documents up front
+    pub fn new(strategy: &'a dyn NodeKey, from: &'a Document, to: &'a Document) -> Self {
+        let index = [from, to].map(|doc| (doc, index(strategy, doc)));
+        Self {
+            strategy,
+            index: Some(index),
+        }
+    }
+
+    /// Returns the key of `node`, if it is a keyed element
+    pub fn key(&self, node: &NodeData) -> Option<String> {
+        match node {
+            NodeData::NodeElement { element } => self.strategy.key(element),
+            _ => None,
+        }
+    }
+
+    /// Returns the node of `doc` with `key`
+    pub fn find(&self, doc: &Document, key: &str) -> Option<NodeRef> {
+        let Some(index) = &self.index else {
+            return doc.get_by_id(key);
+        };
+        index
+            .iter()
+            .find(|(indexed, _)| std::ptr::eq(*indexed, doc))
+            .and_then(|(_, nodes)| nodes.get(key).copied())
+    }
+}
+
+/// Maps each key in `doc` to the node which has it; as with ids, the last node wins if a key is repeated
+fn index(strategy: &dyn NodeKey, doc: &Document) -> FxHashMap<String, NodeRef> {
+    let mut nodes = FxHashMap::default();
+    let mut stack = vec![doc.root()];
+    while let Some(node) = stack.pop() {
+        if let NodeData::NodeElement { element } = doc.get(node) {
+            if let Some(key) = strategy.key(element) {
+                nodes.insert(key, node);
+            }
+        }
+        stack.extend(doc.children(node).iter().rev().copied());
+    }
+    nodes
+}
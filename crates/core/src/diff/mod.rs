@@ -1,8 +1,10 @@
 pub mod fragment;
+mod keys;
 mod morph;
 mod patch;
 mod traversal;
 
-pub use morph::{diff, Morph};
-pub use patch::{Patch, PatchResult};
+pub use keys::{AttributeKeys, IdKey, NodeKey};
+pub use morph::{diff, diff_subtree, diff_subtree_with_keys, diff_with_keys, Morph};
+pub use patch::{Patch, PatchError, PatchResult, UndoLog};
 pub use traversal::MoveTo;
@@ -1,8 +1,13 @@
-use std::{cmp::Ordering, collections::BTreeSet, fmt, mem, ops::Deref};
+use std::{cmp::Ordering, collections::BTreeSet, fmt, iter, mem, ops::Deref};
 
+use fixedbitset::FixedBitSet;
+use fxhash::FxHashMap;
 use smallvec::{smallvec, SmallVec};
 
-use super::{MoveTo, Patch};
+use super::{
+    keys::{Keys, NodeKey},
+    MoveTo, Patch,
+};
 use crate::dom::*;
 
 #[derive(Clone)]
@@ -153,13 +158,13 @@ impl<'a> Deref for Cursor<'a> {
 }
 
 trait CompatibleWith: Deref<Target = NodeData> {
-    fn is_compatible_with<T>(&self, other: &T) -> bool
+    fn is_compatible_with<T>(&self, other: &T, keys: &Keys<'_>) -> bool
     where
         T: Deref<Target = NodeData>,
     {
         match (self.deref(), other.deref()) {
             (NodeData::NodeElement { element: from }, NodeData::NodeElement { element: to }) => {
-                to.name.eq(&from.name) && to.id().eq(&from.id())
+                to.name.eq(&from.name) && keys.key(self.deref()).eq(&keys.key(other.deref()))
             }
             (NodeData::Leaf { value: _ }, NodeData::Leaf { value: _ }) => true,
             (NodeData::Root, NodeData::Root) => true,
@@ -170,6 +175,78 @@ trait CompatibleWith: Deref<Target = NodeData> {
 
 impl<T> CompatibleWith for T where T: Deref<Target = NodeData> {}
 
+/// Returns true if the keyed `node` can be moved into place, and marks it as moved
+///
+/// A node can only be moved once, and not after it or one of its ancestors has been removed.
+fn can_move(
+    moved: &mut BTreeSet<NodeRef>,
+    removed: &BTreeSet<NodeRef>,
+    doc: &Document,
+    node: NodeRef,
+) -> bool {
+    let mut ancestor = Some(node);
+    while let Some(current) = ancestor {
+        if removed.contains(&current) {
+            return false;
+        }
+        ancestor = doc.parent(current);
+    }
+    moved.insert(node)
+}
+
+/// Returns the patches which change the attributes of `node` from those of `from` to those of `to`
+///
+/// Each added, updated or removed attribute gets its own patch. Those patches can only append new
+/// attributes, so if that wouldn't reproduce the order of `to`, the attributes are replaced as a whole.
+fn attribute_patches(node: NodeRef, from: &Element, to: &Element) -> Vec<Patch> {
+    let mut patched = from.clone();
+    let patches = AttributeChange::between(&from.attributes, &to.attributes)
+        .into_iter()
+        .map(|change| match change {
+            AttributeChange::Added { name, value } => {
+                patched.set_attribute(name.clone(), value.clone());
+                Patch::AddAttributeTo { node, name, value }
+            }
+            AttributeChange::Updated {
+                name, new_value, ..
+            } => {
+                patched.set_attribute(name.clone(), new_value.clone());
+                Patch::UpdateAttribute {
+                    node,
+                    name,
+                    value: new_value,
+                }
+            }
+            AttributeChange::Removed { name, .. } => {
+                patched.remove_attribute(&name);
+                Patch::RemoveAttributeByName { node, name }
+            }
+        })
+        .collect();
+
+    if patched.attributes == to.attributes {
+        patches
+    } else {
+        vec![Patch::SetAttributes {
+            node,
+            attributes: to.attributes.clone(),
+        }]
+    }
+}
+
+/// Finds the node with `key` in the document of `cursor`, if it can be morphed into/from `node`
+///
+/// Like morphdom, keyed nodes are only matched up when their tags agree as well as their keys.
+fn keyed_match(
+    keys: &Keys<'_>,
+    cursor: &Cursor<'_>,
+    key: &str,
+    node: &Cursor<'_>,
+) -> Option<NodeRef> {
+    keys.find(cursor.doc, key)
+        .filter(|keyed| cursor.at(*keyed).is_compatible_with(node, keys))
+}
+
 #[derive(Debug, Default)]
 enum Op<'a> {
     #[default]
@@ -233,6 +310,12 @@ pub struct Morph<'a> {
     stack: SmallVec<[Op<'a>; 16]>,
     queue: SmallVec<[Op<'a>; 8]>,
     detached: BTreeSet<NodeRef>,
+    /// Keyed nodes which have already been moved into place; if the target document repeats an id,
+    /// later occurrences are created from scratch instead of moving the same node twice
+    moved: BTreeSet<NodeRef>,
+    /// Nodes which have been removed, along with their subtree, and so can no longer be moved
+    removed: BTreeSet<NodeRef>,
+    keys: Keys<'a>,
 }
 
 impl<'a> Morph<'a> {
@@ -240,6 +323,14 @@ impl<'a> Morph<'a> {
         (from, to).into()
     }
 
+    /// Like [`Morph::new`], but matches up nodes by the keys which `keys` gives them, rather than by id
+    pub fn with_keys(from: &'a Document, to: &'a Document, keys: &'a dyn NodeKey) -> Self {
+        Morph {
+            keys: Keys::new(keys, from, to),
+            ..(from, to).into()
+        }
+    }
+
     fn advance(&mut self, advance: Advance, skip_children: bool) {
         let op = self.stack.last_mut().unwrap();
 
@@ -307,6 +398,154 @@ impl<'a> Morph<'a> {
     }
 }
 
+impl<'a> Morph<'a> {
+    /// Reconciles the children of `from` with the children of `to`, if every one of them is keyed by a unique id
+    ///
+    /// Children are matched up by id, and the longest run of matched children which are already in order
+    /// stays in place, so only the others are moved. This keeps the patches for a reordered list proportional
+    /// to the number of items that actually changed position.
+    ///
+    /// Returns false, without doing anything, if the children aren't a keyed list.
+    fn reconcile_keyed(&mut self, from: &Cursor<'a>, to: &Cursor<'a>) -> bool {
+        let (from_children, to_children) = (from.children(), to.children());
+        let (Some(from_keys), Some(to_keys)) = (
+            keyed_children(&self.keys, from.doc, from_children),
+            keyed_children(&self.keys, to.doc, to_children),
+        ) else {
+            return false;
+        };
+
+        let positions = from_keys
+            .iter()
+            .enumerate()
+            .map(|(i, key)| (key.as_str(), i))
+            .collect::<FxHashMap<_, _>>();
+        let mut matched = Vec::with_capacity(to_children.len());
+        for (key, to_child) in to_keys.iter().zip(to_children.iter()) {
+            let position = positions.get(key.as_str()).copied().filter(|i| {
+                let node = from_children[*i];
+                from.at(node)
+                    .is_compatible_with(&to.at(*to_child), &self.keys)
+                    && can_move(&mut self.moved, &self.removed, from.doc, node)
+            });
+            matched.push(position);
+        }
+        let stationary = longest_increasing_subsequence(&matched);
+
+        // Children which aren't in the new list are removed first, to leave only the ones being positioned
+        let mut claimed = FixedBitSet::with_capacity(from_children.len());
+        claimed.extend(matched.iter().flatten().copied());
+        for (i, node) in from_children.iter().enumerate() {
+            if !claimed.contains(i) && !self.detached.contains(node) {
+                self.queue.push(Op::RemoveNode {
+                    node: *node,
+                    cursor: from.at(*node),
+                    to: to.fork(),
+                });
+            }
+        }
+
+        // Every other child is positioned before the next stationary one, or appended after the last
+        let mut anchors = vec![None; to_children.len()];
+        let mut anchor = None;
+        for j in (0..to_children.len()).rev() {
+            anchors[j] = anchor;
+            if stationary.contains(j) {
+                anchor = matched[j].map(|i| from_children[i]);
+            }
+        }
+
+        self.queue.push(Op::Patch(Patch::Push(from.node)));
+        for (j, to_child) in to_children.iter().enumerate() {
+            let to_child = to.at(*to_child);
+            let before = anchors[j];
+            let node = match matched[j] {
+                Some(i) if stationary.contains(j) => {
+                    self.queue
+                        .push(Op::Morph(from.at(from_children[i]), to_child));
+                    continue;
+                }
+                Some(i) => Some(from_children[i]),
+                // A child which is new to this list may be moved here from elsewhere in the document,
+                // unless that would move a node into its own subtree
+                None => keyed_match(&self.keys, from, &to_keys[j], &to_child).filter(|node| {
+                    let mut ancestors = iter::successors(Some(from.node), |n| from.doc.parent(*n));
+                    !ancestors.any(|ancestor| ancestor == *node)
+                        && can_move(&mut self.moved, &self.removed, from.doc, *node)
+                }),
+            };
+            match (node, before) {
+                (Some(node), before) => {
+                    self.detached.insert(node);
+                    self.queue.extend([
+                        Op::Patch(Patch::MoveNode { node, before }),
+                        Op::Morph(from.at(node), to_child),
+                    ]);
+                }
+                (None, Some(before)) => self.queue.push(Op::InsertBefore {
+                    from: from.at(before),
+                    cursor: to_child,
+                }),
+                (None, None) => self.queue.push(Op::Append {
+                    from: from.fork(),
+                    cursor: to_child,
+                }),
+            }
+        }
+        self.queue.push(Op::Patch(Patch::Pop));
+
+        true
+    }
+}
+
+/// Returns the key of each of `children`, if all of them are keyed elements, and no key is repeated
+fn keyed_children(keys: &Keys<'_>, doc: &Document, children: &[NodeRef]) -> Option<Vec<String>> {
+    if children.is_empty() {
+        return None;
+    }
+    let mut seen = BTreeSet::new();
+    let mut child_keys = Vec::with_capacity(children.len());
+    for child in children {
+        let key = keys.key(doc.get(*child))?;
+        if !seen.insert(key.clone()) {
+            return None;
+        }
+        child_keys.push(key);
+    }
+    Some(child_keys)
+}
+
+/// Returns the positions in `sequence` which make up a longest strictly increasing subsequence of its values
+///
+/// `None` values are never part of the subsequence.
+fn longest_increasing_subsequence(sequence: &[Option<usize>]) -> FixedBitSet {
+    // `tails[k]` is the position of the smallest value ending an increasing subsequence of length `k + 1`
+    let mut tails: Vec<usize> = vec![];
+    let mut predecessors = vec![usize::MAX; sequence.len()];
+    for (position, value) in sequence.iter().enumerate() {
+        let Some(value) = value else {
+            continue;
+        };
+        let length = tails.partition_point(|tail| sequence[*tail].unwrap() < *value);
+        if length > 0 {
+            predecessors[position] = tails[length - 1];
+        }
+        if length == tails.len() {
+            tails.push(position);
+        } else {
+            tails[length] = position;
+        }
+    }
+
+    let mut subsequence = FixedBitSet::with_capacity(sequence.len());
+    let mut position = tails.last().copied().unwrap_or(usize::MAX);
+    while position != usize::MAX {
+        subsequence.insert(position);
+        position = predecessors[position];
+    }
+    subsequence
+}
+
 impl<'a, T> From<T> for Morph<'a>
 where
     T: Into<Op<'a>>,
@@ -316,6 +555,9 @@ where
             stack: smallvec![op.into()],
             queue: smallvec![],
             detached: BTreeSet::new(),
+            moved: BTreeSet::new(),
+            removed: BTreeSet::new(),
+            keys: Keys::ids(),
         }
     }
 }
@@ -342,21 +584,31 @@ impl<'a> Iterator for Morph<'a> {
                     cursor,
                     ref to,
                 } => {
+                    // A keyed node which is elsewhere in the target document is detached to be moved there,
+                    // along with its subtree
+                    if cursor.node == *node && cursor.depth() == 0 {
+                        if let Some(key) = self.keys.key(cursor) {
+                            if keyed_match(&self.keys, to, &key, cursor).is_some() {
+                                *op = Op::MaybeDetach { node: *node };
+                                continue;
+                            }
+                        }
+                    }
+
                     if cursor.next().is_some() {
-                        if let NodeData::NodeElement { element: el } = cursor.node() {
-                            if let Some(id) = el.id() {
-                                if to.doc.get_by_id(id).is_some() {
-                                    // Only detach if not previously moved
-                                    if self.detached.insert(cursor.node) {
-                                        self.queue
-                                            .push(Op::Patch(Patch::Detach { node: cursor.node }));
-                                        continue;
-                                    }
+                        if let Some(key) = self.keys.key(cursor) {
+                            if keyed_match(&self.keys, to, &key, cursor).is_some() {
+                                // Only detach if not previously moved
+                                if self.detached.insert(cursor.node) {
+                                    self.queue
+                                        .push(Op::Patch(Patch::Detach { node: cursor.node }));
+                                    continue;
                                 }
                             }
                         }
                     }
 
+                    self.removed.insert(*node);
                     *op = Op::Patch(Patch::Remove { node: *node });
                 }
                 Op::RemoveNodes { from, to } => {
@@ -373,14 +625,16 @@ impl<'a> Iterator for Morph<'a> {
                     }
                 }
                 Op::Append { ref from, cursor } => {
-                    if let Some(id) = cursor.id() {
-                        if let Some(node) = from.doc.get_by_id(id) {
+                    if let Some(key) = self.keys.key(cursor) {
+                        let (moved, removed) = (&mut self.moved, &self.removed);
+                        if let Some(node) = keyed_match(&self.keys, from, &key, cursor)
+                            .filter(|node| can_move(moved, removed, from.doc, *node))
+                        {
+                            // The node is moved away from its current position, so it is skipped over there
+                            self.detached.insert(node);
                             self.queue.extend([
-                                Op::MaybeDetach { node },
-                                // Parent will already be on the stack so only need to push child
-                                Op::Patch(Patch::Push(node)),
-                                // Attach will pop node off the stack and append to parent
-                                Op::Patch(Patch::Attach),
+                                // Parent will already be on the stack
+                                Op::Patch(Patch::MoveNode { node, before: None }),
                                 Op::Morph(from.at(node), cursor.fork()),
                             ]);
 
@@ -469,7 +723,7 @@ impl<'a> Iterator for Morph<'a> {
                             Op::Patch(Patch::PrependBefore { before: from.node }),
                             // Set newly inserted node as append parent
                             Op::Patch(Patch::PushCurrent),
-                            Op::Append {
+                            Op::AppendSiblings {
                                 from: from.clone(),
                                 cursor: cursor.fork(),
                             },
@@ -505,9 +759,12 @@ impl<'a> Iterator for Morph<'a> {
                     }
 
                     match (from.node(), to.node()) {
-                        (NodeData::Root, NodeData::Root)
-                        | (NodeData::Root, _)
-                        | (_, NodeData::Root) => {
+                        (NodeData::Root, NodeData::Root) => {
+                            let (from, to) = (from.fork(), to.fork());
+                            let keyed = self.reconcile_keyed(&from, &to);
+                            self.advance(Advance::BothCursors, keyed);
+                        }
+                        (NodeData::Root, _) | (_, NodeData::Root) => {
                             self.advance(Advance::BothCursors, false);
                         }
                         (
@@ -515,11 +772,9 @@ impl<'a> Iterator for Morph<'a> {
                             NodeData::Leaf { value: content },
                         ) => {
                             if old_content.ne(content) {
-                                self.queue.push(Op::Patch(Patch::Replace {
+                                self.queue.push(Op::Patch(Patch::UpdateText {
                                     node: from.node,
-                                    replacement: NodeData::Leaf {
-                                        value: content.to_owned(),
-                                    },
+                                    value: content.to_owned(),
                                 }));
                             }
 
@@ -549,21 +804,22 @@ impl<'a> Iterator for Morph<'a> {
                             NodeData::NodeElement { element: to_el },
                         ) => {
                             // nodes are compatible; morph attribute changes and continue
-                            if to_el.name.eq(&from_el.name) && to_el.id().eq(&from_el.id()) {
+                            if from.is_compatible_with(to, &self.keys) {
                                 if from_el.attributes.ne(&to_el.attributes) {
-                                    self.queue.push(Op::Patch(Patch::SetAttributes {
-                                        node: from.node,
-                                        attributes: to.attributes().to_vec(),
-                                    }));
+                                    let patches = attribute_patches(from.node, from_el, to_el);
+                                    self.queue.extend(patches.into_iter().map(Op::Patch));
                                 }
 
-                                self.advance(Advance::BothCursors, false);
+                                // Keyed children are reconciled as a list, and skipped by the cursors
+                                let (from, to) = (from.fork(), to.fork());
+                                let keyed = self.reconcile_keyed(&from, &to);
+                                self.advance(Advance::BothCursors, keyed);
                                 continue;
                             }
 
                             // Keyed node shouldn't be here; detach/remove and continue
-                            if let Some(id) = from.id() {
-                                if to.doc.get_by_id(id).is_some() {
+                            if let Some(key) = self.keys.key(from) {
+                                if keyed_match(&self.keys, to, &key, from).is_some() {
                                     self.queue.push(Op::MaybeDetach { node: from.node });
                                 } else {
                                     self.queue.push(Op::RemoveNode {
@@ -578,12 +834,17 @@ impl<'a> Iterator for Morph<'a> {
                             }
 
                             // If keyed el should be here, relocated or insert instead of transforming el
-                            if let Some(id) = to.id() {
-                                if let Some(node) = from.doc.get_by_id(id) {
+                            if let Some(key) = self.keys.key(to) {
+                                let (moved, removed) = (&mut self.moved, &self.removed);
+                                if let Some(node) = keyed_match(&self.keys, from, &key, to)
+                                    .filter(|node| can_move(moved, removed, from.doc, *node))
+                                {
+                                    self.detached.insert(node);
                                     self.queue.extend([
-                                        Op::Patch(Patch::Push(node)),
-                                        Op::MaybeDetach { node },
-                                        Op::Patch(Patch::PrependBefore { before: from.node }),
+                                        Op::Patch(Patch::MoveNode {
+                                            node,
+                                            before: Some(from.node),
+                                        }),
                                         Op::Morph(from.at(node), to.fork()),
                                     ]);
 
@@ -603,7 +864,7 @@ impl<'a> Iterator for Morph<'a> {
 
                             // If the next existing el can be morphed into the target el, delete current instead of replacing
                             if let Some(from_next) = from.next_sibling() {
-                                if from_next.is_compatible_with(to) {
+                                if from_next.is_compatible_with(to, &self.keys) {
                                     self.queue.push(Op::RemoveNode {
                                         node: from.node,
                                         cursor: from.fork(),
@@ -617,7 +878,7 @@ impl<'a> Iterator for Morph<'a> {
 
                             // If the next node being morphed into is compatible, insert target node before current
                             if let Some(to_next) = to.next_sibling() {
-                                if to_next.is_compatible_with(from) {
+                                if to_next.is_compatible_with(from, &self.keys) {
                                     self.queue.push(Op::InsertBefore {
                                         from: from.clone(),
                                         cursor: to.fork(),
@@ -655,3 +916,43 @@ impl<'a> Iterator for Morph<'a> {
 pub fn diff(old_document: &Document, new_document: &Document) -> Vec<Patch> {
     Vec::from_iter(Morph::new(old_document, new_document))
 }
+
+/// Like [`diff`], but matches up nodes by the keys which `keys` gives them, rather than by id
+pub fn diff_with_keys(
+    old_document: &Document,
+    new_document: &Document,
+    keys: &dyn NodeKey,
+) -> Vec<Patch> {
+    Vec::from_iter(Morph::with_keys(old_document, new_document, keys))
+}
+
+/// Like [`diff`], but only morphs the subtree of `old_document` rooted at `old_node` into the subtree of
+/// `new_document` rooted at `new_node`.
+///
+/// The two subtree roots are expected to be compatible (same tag and id), as the root is updated in place.
+pub fn diff_subtree(
+    old_document: &Document,
+    old_node: NodeRef,
+    new_document: &Document,
+    new_node: NodeRef,
+) -> Vec<Patch> {
+    let from = Cursor::new(old_document, old_node);
+    let to = Cursor::new(new_document, new_node);
+    Vec::from_iter(Morph::from((from, to)))
+}
+
+/// Like [`diff_subtree`], but matches up nodes by the keys which `keys` gives them, rather than by id
+pub fn diff_subtree_with_keys(
+    old_document: &Document,
+    old_node: NodeRef,
+    new_document: &Document,
+    new_node: NodeRef,
+    keys: &dyn NodeKey,
+) -> Vec<Patch> {
+    let from = Cursor::new(old_document, old_node);
+    let to = Cursor::new(new_document, new_node);
+    Vec::from_iter(Morph {
+        keys: Keys::new(keys, old_document, new_document),
+        ..Morph::from((from, to))
+    })
+}
@@ -1,7 +1,14 @@
+use serde::{Deserialize, Serialize};
+
 use super::traversal::MoveTo;
 use crate::dom::*;
 
-#[derive(Debug, PartialEq, Clone)]
+/// A single edit to a document, as produced by [`super::diff`]
+///
+/// Patches refer to nodes by `NodeRef`, so a patch list can be serialized and applied to another copy of the
+/// document it was computed against, as long as that copy has the same `NodeRef`s, e.g. one decoded with
+/// `Document::from_bytes`.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub enum Patch {
     InsertBefore {
         before: NodeRef,
@@ -38,6 +45,13 @@ pub enum Patch {
     PrependBefore {
         before: NodeRef,
     },
+    /// Moves `node` from its current position, if it has one, to just before `before`
+    ///
+    /// If `before` is `None`, `node` is appended to the parent on top of the stack instead, which stays there.
+    MoveNode {
+        node: NodeRef,
+        before: Option<NodeRef>,
+    },
     /// Appends `node` using the current node as parent
     ///
     /// This is used in conjunction with `Move` to construct a subtree
@@ -61,6 +75,11 @@ pub enum Patch {
         node: NodeRef,
         replacement: NodeData,
     },
+    /// Sets the text of the leaf `node` to `value`, keeping the node itself
+    UpdateText {
+        node: NodeRef,
+        value: String,
+    },
     /// Adds `attr` to the current node
     AddAttribute {
         name: AttributeName,
@@ -104,17 +123,307 @@ pub enum PatchResult {
         parent: NodeRef,
         data: NodeData,
     },
-    /// The `node` has been changed in some other way.
-    Change { node: NodeRef, data: NodeData },
+    /// The attributes of `node` have been changed, `data` is the node as it was before the change.
+    Change {
+        node: NodeRef,
+        data: NodeData,
+        attributes: Vec<AttributeChange>,
+    },
     /// The `node` has been replaced
     Replace {
         node: NodeRef,
         parent: NodeRef,
         data: NodeData,
     },
+    /// The text of the leaf `node` has been changed in place, `data` is the leaf as it was before the change.
+    UpdateText {
+        node: NodeRef,
+        parent: NodeRef,
+        data: NodeData,
+    },
+    /// The `node` has been moved to position `index` among the children of `new_parent`.
+    ///
+    /// `old_parent` is `None` if the node had already been detached by an earlier patch.
+    Move {
+        node: NodeRef,
+        old_parent: Option<NodeRef>,
+        new_parent: NodeRef,
+        index: usize,
+    },
+}
+
+/// The patches which undo a sequence of patches applied with [`Patch::apply_reversible`]
+#[derive(Debug, Default, Clone)]
+pub struct UndoLog {
+    patches: Vec<Vec<Patch>>,
+    /// Nodes removed by the recorded patches, which were only detached so they can be restored
+    removed: Vec<NodeRef>,
+}
+impl UndoLog {
+    pub fn is_empty(&self) -> bool {
+        self.patches.is_empty()
+    }
+
+    /// Returns the nodes removed by the recorded patches, which can be deleted once they won't be undone
+    pub fn removed(&self) -> &[NodeRef] {
+        self.removed.as_slice()
+    }
+
+    /// Returns the patches which restore the document to how it was before the first recorded patch
+    ///
+    /// They only do so if the document hasn't been changed in any other way since.
+    pub fn into_patches(self) -> Vec<Patch> {
+        self.patches.into_iter().rev().flatten().collect()
+    }
+}
+
+/// Represents the ways in which a list of patches can be rejected by `Document::try_apply_patches`
+#[derive(Debug, thiserror::Error, uniffi::Error)]
+#[uniffi(flat_error)]
+pub enum PatchError {
+    #[error("could not decode patches: {0}")]
+    Decode(#[from] serde_json::Error),
+    #[error("patch {index} refers to {node}, which is not in the document")]
+    UnknownNode { index: usize, node: NodeRef },
+    #[error("patch {index} pops more nodes than are on the stack")]
+    StackUnderflow { index: usize },
+    #[error("patch {index} cannot be applied{}: {reason}", at(.path))]
+    Invalid {
+        index: usize,
+        reason: &'static str,
+        /// The path of the node the patch was rejected at, as of that patch, if it is part of the tree
+        path: Option<NodePath>,
+    },
+    /// A failure injected by a failpoint, see `crate::dom::failpoint`
+    #[cfg(test)]
+    #[error("patch {index} failed with an injected failure")]
+    Injected { index: usize },
+}
+
+fn at(path: &Option<NodePath>) -> String {
+    path.as_ref()
+        .map(|path| format!(" at {path}"))
+        .unwrap_or_default()
 }
 
 impl Patch {
+    /// Checks that this patch, at position `index` in its list, can be applied to `doc` using `stack`
+    ///
+    /// A patch which passes can be applied without panicking, and leaves the document a tree.
+    pub fn check<B>(&self, index: usize, doc: &B, stack: &[NodeRef]) -> Result<(), PatchError>
+    where
+        B: DocumentBuilder,
+    {
+        let d = doc.document();
+        let known = |node: NodeRef| {
+            if d.contains(node) {
+                Ok(node)
+            } else {
+                Err(PatchError::UnknownNode { index, node })
+            }
+        };
+        let invalid = |node: NodeRef, reason| {
+            Err(PatchError::Invalid {
+                index,
+                reason,
+                path: d.path_of(node),
+            })
+        };
+        let peek = |depth: usize| {
+            stack
+                .len()
+                .checked_sub(depth + 1)
+                .map(|i| stack[i])
+                .ok_or(PatchError::StackUnderflow { index })
+        };
+        let has_parent = |node: NodeRef| match d.parent(known(node)?) {
+            Some(_) => Ok(()),
+            None => invalid(node, "the anchor node has no parent"),
+        };
+        let is_element = |node: NodeRef| match d.get(known(node)?) {
+            NodeData::NodeElement { .. } => Ok(()),
+            _ => invalid(node, "attributes can only be changed on elements"),
+        };
+        // Checks that attaching the detached `node` under or next to `target` doesn't create a cycle
+        let can_attach = |node: NodeRef, target: NodeRef| {
+            if d.parent(node).is_some() {
+                return invalid(target, "the node to attach already has a parent");
+            }
+            if node == d.root() || is_ancestor(d, node, target) {
+                return invalid(target, "the node would become its own ancestor");
+            }
+            Ok(())
+        };
+        // Only elements and the root have children
+        let can_parent = |parent: NodeRef| match d.get(parent) {
+            NodeData::Leaf { .. } => invalid(parent, "leaf nodes cannot have children"),
+            _ => Ok(()),
+        };
+        // A document has a single root
+        let not_root_data = |node: NodeRef, data: &NodeData| match data {
+            NodeData::Root => invalid(node, "a root cannot be added to a document"),
+            _ => Ok(()),
+        };
+        let can_add = |parent: NodeRef, data: &NodeData| {
+            can_parent(parent)?;
+            not_root_data(parent, data)
+        };
+        let not_root = |node: NodeRef| {
+            if known(node)? == d.root() {
+                return invalid(node, "the root node cannot be removed, replaced or moved");
+            }
+            Ok(())
+        };
+
+        match self {
+            Self::InsertBefore {
+                before: anchor,
+                node,
+            }
+            | Self::InsertAfter {
+                after: anchor,
+                node,
+            } => {
+                has_parent(*anchor)?;
+                can_add(d.parent(*anchor).unwrap(), node)
+            }
+            Self::Create { node } | Self::CreateAndMoveTo { node } => {
+                not_root_data(doc.insertion_point(), node)
+            }
+            Self::PushCurrent => Ok(()),
+            Self::Push(node) => known(*node).map(|_| ()),
+            Self::Pop => peek(0).map(|_| ()),
+            Self::Attach => {
+                let (child, parent) = (peek(0)?, peek(1)?);
+                can_parent(known(parent)?)?;
+                can_attach(known(child)?, parent)
+            }
+            Self::Detach { node } => known(*node).map(|_| ()),
+            Self::PrependBefore { before: anchor } | Self::AppendAfter { after: anchor } => {
+                let node = known(peek(0)?)?;
+                has_parent(*anchor)?;
+                can_attach(node, *anchor)
+            }
+            Self::MoveNode { node, before } => {
+                not_root(*node)?;
+                let target = match before {
+                    Some(before) => {
+                        has_parent(*before)?;
+                        *before
+                    }
+                    None => {
+                        let parent = known(peek(0)?)?;
+                        can_parent(parent)?;
+                        parent
+                    }
+                };
+                if is_ancestor(d, *node, target) {
+                    return invalid(target, "the node would become its own ancestor");
+                }
+                Ok(())
+            }
+            Self::Append { node } => can_add(doc.insertion_point(), node),
+            Self::AppendTo { parent, node } => can_add(known(*parent)?, node),
+            Self::Remove { node } => not_root(*node),
+            Self::Replace { node, replacement } => {
+                not_root(*node)?;
+                match replacement {
+                    NodeData::Root => invalid(*node, "a node cannot be replaced by a root"),
+                    NodeData::Leaf { .. } if !d.children(*node).is_empty() => {
+                        invalid(*node, "a node with children cannot be replaced by a leaf")
+                    }
+                    _ => Ok(()),
+                }
+            }
+            Self::UpdateText { node, .. } => match d.get(known(*node)?) {
+                NodeData::Leaf { .. } if d.parent(*node).is_some() => Ok(()),
+                NodeData::Leaf { .. } => invalid(*node, "the node to update has no parent"),
+                _ => invalid(*node, "text can only be updated on leaf nodes"),
+            },
+            Self::AddAttribute { .. } => is_element(doc.insertion_point()),
+            Self::AddAttributeTo { node, .. }
+            | Self::UpdateAttribute { node, .. }
+            | Self::RemoveAttributeByName { node, .. }
+            | Self::SetAttributes { node, .. } => is_element(*node),
+            Self::Move(MoveTo::Node(node)) => known(*node).map(|_| ()),
+            Self::Move(MoveTo::Parent) => match doc.parent() {
+                Some(_) => Ok(()),
+                None => invalid(doc.insertion_point(), "the current node has no parent"),
+            },
+            Self::Move(MoveTo::Child(n) | MoveTo::ReverseChild(n)) => {
+                if (*n as usize) < doc.children().len() {
+                    Ok(())
+                } else {
+                    invalid(doc.insertion_point(), "the current node has no such child")
+                }
+            }
+            Self::Move(MoveTo::Sibling(n) | MoveTo::ReverseSibling(n)) => match doc.parent() {
+                Some(parent) if (*n as usize) < d.children(parent).len() => Ok(()),
+                _ => invalid(
+                    doc.insertion_point(),
+                    "the current node has no such sibling",
+                ),
+            },
+        }
+    }
+
+    /// Like [`Patch::apply`], but also records the patches which undo this one in `undo`
+    ///
+    /// Removed nodes are detached rather than deleted, so that undoing the removal restores the same nodes.
+    pub fn apply_reversible<B>(
+        self,
+        doc: &mut B,
+        stack: &mut Vec<NodeRef>,
+        undo: &mut UndoLog,
+    ) -> Option<PatchResult>
+    where
+        B: DocumentBuilder,
+    {
+        let d = doc.document();
+        let inverse = match &self {
+            Self::Detach { node } | Self::MoveNode { node, .. } | Self::Remove { node } => {
+                restore_position(d, *node)
+            }
+            Self::Replace { node, .. } => vec![Self::Replace {
+                node: *node,
+                replacement: d.get(*node).clone(),
+            }],
+            Self::UpdateText { node, .. } => match d.get(*node) {
+                NodeData::Leaf { value } => vec![Self::UpdateText {
+                    node: *node,
+                    value: value.clone(),
+                }],
+                _ => vec![],
+            },
+            Self::AddAttribute { .. } => restore_attributes(d, doc.insertion_point()),
+            Self::AddAttributeTo { node, .. }
+            | Self::UpdateAttribute { node, .. }
+            | Self::RemoveAttributeByName { node, .. }
+            | Self::SetAttributes { node, .. } => restore_attributes(d, *node),
+            _ => vec![],
+        };
+
+        let result = match self {
+            Self::Remove { node } => {
+                let data = doc.document().get(node).clone();
+                let parent = doc.document().parent(node);
+                doc.detach_node(node);
+                undo.removed.push(node);
+                parent.map(|parent| PatchResult::Remove { node, parent, data })
+            }
+            patch => patch.apply(doc, stack),
+        };
+        // Nodes which the patch attached are detached again
+        let inverse = match result {
+            Some(PatchResult::Add { node, .. }) => vec![Self::Detach { node }],
+            _ => inverse,
+        };
+        if !inverse.is_empty() {
+            undo.patches.push(inverse);
+        }
+        result
+    }
+
     /// Applies this patch to `doc` using `stack`.
     ///
     /// If this patch will result in a change to the underlying document, a [PatchResult]
@@ -189,6 +498,35 @@ impl Patch {
                 let data = d.get(node).clone();
                 Some(PatchResult::Add { node, parent, data })
             }
+            Self::MoveNode { node, before } => {
+                let old_parent = doc.document().parent(node);
+                doc.detach_node(node);
+                let new_parent = match before {
+                    Some(before) => {
+                        let d = doc.document_mut();
+                        d.insert_before(node, before);
+                        d.parent(before).expect("inserted node should have parent")
+                    }
+                    None => {
+                        let parent = *stack.last().unwrap();
+                        doc.set_insertion_point(parent);
+                        doc.attach_node(node);
+                        parent
+                    }
+                };
+                let index = doc
+                    .document()
+                    .children(new_parent)
+                    .iter()
+                    .position(|child| *child == node)
+                    .expect("moved node should be a child of its new parent");
+                Some(PatchResult::Move {
+                    node,
+                    old_parent,
+                    new_parent,
+                    index,
+                })
+            }
             Self::Append { node: data } => {
                 let node = doc.append(data.clone());
                 Some(PatchResult::Add {
@@ -221,39 +559,50 @@ impl Patch {
                 doc.replace(node, replacement);
                 Some(PatchResult::Replace { node, parent, data })
             }
+            Self::UpdateText { node, value } => {
+                let d = doc.document_mut();
+                let parent = d.parent(node)?;
+                let NodeData::Leaf { value: text } = d.get_mut(node) else {
+                    return None;
+                };
+                let data = NodeData::Leaf {
+                    value: std::mem::replace(text, value),
+                };
+                Some(PatchResult::UpdateText { node, parent, data })
+            }
             Self::AddAttribute { name, value } => {
-                doc.set_attribute(name, value);
                 let node = doc.insertion_point();
                 let data = doc.document().get(node).clone();
-                Some(PatchResult::Change { node, data })
+                doc.set_attribute(name, value);
+                Some(attributes_changed(doc.document(), node, data))
             }
             Self::AddAttributeTo { node, name, value } => {
                 let data = doc.document().get(node).clone();
                 let mut guard = doc.insert_guard();
                 guard.set_insertion_point(node);
                 guard.set_attribute(name, value);
-                Some(PatchResult::Change { node, data })
+                Some(attributes_changed(guard.document(), node, data))
             }
             Self::UpdateAttribute { node, name, value } => {
                 let data = doc.document().get(node).clone();
                 let mut guard = doc.insert_guard();
                 guard.set_insertion_point(node);
                 guard.set_attribute(name, value);
-                Some(PatchResult::Change { node, data })
+                Some(attributes_changed(guard.document(), node, data))
             }
             Self::RemoveAttributeByName { node, name } => {
                 let data = doc.document().get(node).clone();
                 let mut guard = doc.insert_guard();
                 guard.set_insertion_point(node);
                 guard.remove_attribute(name);
-                Some(PatchResult::Change { node, data })
+                Some(attributes_changed(guard.document(), node, data))
             }
             Self::SetAttributes { node, attributes } => {
                 let data = doc.document().get(node).clone();
                 let mut guard = doc.insert_guard();
                 guard.set_insertion_point(node);
                 guard.replace_attributes(attributes);
-                Some(PatchResult::Change { node, data })
+                Some(attributes_changed(guard.document(), node, data))
             }
             Self::Move(MoveTo::Node(node)) => {
                 doc.set_insertion_point(node);
@@ -282,3 +631,52 @@ impl Patch {
         }
     }
 }
+
+/// Describes the change to the attributes of `node`, which was `data` before the change
+fn attributes_changed(doc: &Document, node: NodeRef, data: NodeData) -> PatchResult {
+    let attributes = AttributeChange::between(&data.attributes(), &doc.attributes(node));
+    PatchResult::Change {
+        node,
+        data,
+        attributes,
+    }
+}
+
+/// Returns true if `node` is `other` or one of its ancestors
+fn is_ancestor(doc: &Document, node: NodeRef, other: NodeRef) -> bool {
+    std::iter::successors(Some(other), |n| doc.parent(*n)).any(|n| n == node)
+}
+
+/// Returns the patches which put `node` back where it is now, once the patches since have been undone
+fn restore_position(doc: &Document, node: NodeRef) -> Vec<Patch> {
+    let Some(parent) = doc.parent(node) else {
+        return vec![Patch::Detach { node }];
+    };
+    let siblings = doc.children(parent);
+    let next = siblings
+        .iter()
+        .position(|sibling| *sibling == node)
+        .and_then(|index| siblings.get(index + 1));
+    match next {
+        Some(before) => vec![Patch::MoveNode {
+            node,
+            before: Some(*before),
+        }],
+        None => vec![
+            Patch::Push(parent),
+            Patch::MoveNode { node, before: None },
+            Patch::Pop,
+        ],
+    }
+}
+
+/// Returns the patch which sets the attributes of `node` back to what they are now
+fn restore_attributes(doc: &Document, node: NodeRef) -> Vec<Patch> {
+    match doc.get(node) {
+        NodeData::NodeElement { element } => vec![Patch::SetAttributes {
+            node,
+            attributes: element.attributes.clone(),
+        }],
+        _ => vec![],
+    }
+}
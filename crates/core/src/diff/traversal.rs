@@ -1,6 +1,8 @@
+use serde::{Deserialize, Serialize};
+
 use crate::dom::NodeRef;
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum MoveTo {
     Node(NodeRef),
 
@@ -1,11 +1,14 @@
 use std::fmt::{self, Write};
 
+use serde::{Deserialize, Serialize};
 use smallstr::SmallString;
 
 use crate::InternedString;
 
 /// Represents the fully-qualified name of an attribute
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, uniffi::Record)]
+#[derive(
+    Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, uniffi::Record,
+)]
 pub struct AttributeName {
     /// This is used by svg attributes, e.g. `xlink-href`
     pub namespace: Option<String>,
@@ -57,7 +60,7 @@ impl PartialEq<str> for AttributeName {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, uniffi::Record)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, uniffi::Record)]
 pub struct Attribute {
     pub name: AttributeName,
     pub value: Option<String>,
@@ -80,6 +83,67 @@ impl Attribute {
     }
 }
 
+/// A change to one attribute of an element
+#[derive(Debug, Clone, PartialEq, Eq, uniffi::Enum)]
+pub enum AttributeChange {
+    Added {
+        name: AttributeName,
+        value: Option<String>,
+    },
+    Updated {
+        name: AttributeName,
+        old_value: Option<String>,
+        new_value: Option<String>,
+    },
+    Removed {
+        name: AttributeName,
+        old_value: Option<String>,
+    },
+}
+impl AttributeChange {
+    /// Returns the changes which turn the attributes `old` into `new`
+    ///
+    /// Removed and updated attributes are listed in their order in `old`, followed by added attributes in
+    /// their order in `new`. Attributes which only moved are unchanged.
+    pub fn between(old: &[Attribute], new: &[Attribute]) -> Vec<Self> {
+        let find = |attrs: &[Attribute], name: &AttributeName| {
+            attrs.iter().find(|attr| attr.name.eq(name)).cloned()
+        };
+        let mut changes = vec![];
+        for attr in old.iter() {
+            match find(new, &attr.name) {
+                None => changes.push(Self::Removed {
+                    name: attr.name.clone(),
+                    old_value: attr.value.clone(),
+                }),
+                Some(updated) if updated.value != attr.value => changes.push(Self::Updated {
+                    name: attr.name.clone(),
+                    old_value: attr.value.clone(),
+                    new_value: updated.value,
+                }),
+                Some(_) => (),
+            }
+        }
+        for attr in new.iter() {
+            if find(old, &attr.name).is_none() {
+                changes.push(Self::Added {
+                    name: attr.name.clone(),
+                    value: attr.value.clone(),
+                });
+            }
+        }
+        changes
+    }
+
+    pub fn name(&self) -> &AttributeName {
+        match self {
+            Self::Added { name, .. } | Self::Updated { name, .. } | Self::Removed { name, .. } => {
+                name
+            }
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum AttributeValue {
     None,
@@ -0,0 +1,454 @@
+//! A compact, versioned binary encoding for `Document`.
+//!
+//! Unlike printing a document to HTML and parsing it back, this encoding preserves the exact
+//! `NodeRef` of every node (including detached and deleted ones), so caches keyed by `NodeRef` remain
+//! valid after a document is restored. The version of the document, and the version each node was
+//! last changed in, are preserved as well, so `Document::changed_since` gives the same answers for a
+//! decoded document. All strings (tag names, attribute names/values, text) are stored once in a
+//! deduplicated string table and referenced by index.
+//!
+//! The layout of version 2 is as follows, where all integers are LEB128-encoded:
+//!
+//! ```text
+//! magic     b"LVND"
+//! version   u8
+//! strings   count, then (len, utf-8 bytes) for each string
+//! root      node index
+//! nodes     count, then for each node:
+//!             0 (root)
+//!             1 (element), namespace, name, attribute count, then (namespace, name, value) for each
+//!             2 (leaf), value
+//! children  for each node, child count, then child node indices
+//! ids       count, then (string, node index) for each registered id
+//! version   the version of the document
+//! deleted   count, then node index for each deleted node
+//! modified  count, then (node index, version) for each node changed since the document was loaded
+//! ```
+//!
+//! Optional strings (namespaces and attribute values) are encoded as `0` for `None`, otherwise as
+//! the string index plus one. Parent links are not stored, as they are derived from the children.
+//!
+//! The fragment template and event callbacks of a document are not part of the encoding.
+use std::collections::BTreeMap;
+
+use cranelift_entity::{
+    packed_option::PackedOption, EntityRef, EntitySet, PrimaryMap, SecondaryMap,
+};
+use fxhash::FxHashMap;
+use smallstr::SmallString;
+use smallvec::SmallVec;
+
+use super::{Attribute, AttributeName, Document, Element, ElementName, NodeData, NodeRef};
+
+const MAGIC: &[u8; 4] = b"LVND";
+
+/// The current version of the binary encoding
+pub const BINARY_VERSION: u8 = 2;
+
+const TAG_ROOT: u8 = 0;
+const TAG_ELEMENT: u8 = 1;
+const TAG_LEAF: u8 = 2;
+
+/// Represents the possible types of failure that can occur while decoding a `Document`
+#[derive(Debug, thiserror::Error, uniffi::Error)]
+#[uniffi(flat_error)]
+pub enum DecodeError {
+    #[error("input is not an encoded document")]
+    InvalidMagic,
+    #[error("unsupported encoding version {0}")]
+    UnsupportedVersion(u8),
+    #[error("unexpected end of input")]
+    UnexpectedEof,
+    #[error("integer overflow while decoding")]
+    IntegerOverflow,
+    #[error("invalid utf-8 in string table")]
+    InvalidUtf8(#[from] std::str::Utf8Error),
+    #[error("string index {0} is out of bounds")]
+    InvalidString(u64),
+    #[error("node index {0} is out of bounds")]
+    InvalidNode(u64),
+    #[error("invalid node tag {0}")]
+    InvalidTag(u8),
+    #[error("node {0} has more than one parent")]
+    MultipleParents(u64),
+    #[error("node {0} is its own ancestor")]
+    Cycle(u64),
+    #[error("the root node {0} is not a root")]
+    InvalidRoot(u64),
+    #[error("node {0} is a root, but not the root of the document")]
+    MultipleRoots(u64),
+    #[error("deleted node {0} is still part of the tree")]
+    DeletedNodeAttached(u64),
+    #[error("node {0} was changed in a version newer than the document")]
+    InvalidVersion(u64),
+    #[error("unexpected trailing data after document")]
+    TrailingData,
+}
+
+impl Document {
+    /// Encodes this document in a compact, versioned binary format which preserves `NodeRef`s
+    pub fn to_bytes(&self) -> Vec<u8> {
+        Encoder::new(self).encode()
+    }
+
+    /// Decodes a document previously encoded with [`Document::to_bytes`]
+    ///
+    /// Every node is restored with the same `NodeRef` it had in the encoded document, and the document
+    /// keeps its version and the versions its nodes were changed in.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
+        Decoder::new(bytes).decode()
+    }
+}
+
+struct Encoder<'a> {
+    doc: &'a Document,
+    strings: Vec<&'a str>,
+    string_ids: FxHashMap<&'a str, u64>,
+}
+impl<'a> Encoder<'a> {
+    fn new(doc: &'a Document) -> Self {
+        Self {
+            doc,
+            strings: vec![],
+            string_ids: FxHashMap::default(),
+        }
+    }
+
+    fn intern(&mut self, s: &'a str) -> u64 {
+        if let Some(id) = self.string_ids.get(s) {
+            return *id;
+        }
+        let id = self.strings.len() as u64;
+        self.strings.push(s);
+        self.string_ids.insert(s, id);
+        id
+    }
+
+    fn intern_opt(&mut self, s: Option<&'a str>) -> u64 {
+        s.map(|s| self.intern(s) + 1).unwrap_or(0)
+    }
+
+    fn encode(mut self) -> Vec<u8> {
+        // Node records are written to a separate buffer first, so that the string table,
+        // which is populated along the way, can precede them in the output
+        let mut body = Vec::with_capacity(self.doc.nodes.len() * 8);
+        write_uint(&mut body, self.doc.root.index() as u64);
+        write_uint(&mut body, self.doc.nodes.len() as u64);
+        let doc = self.doc;
+        for (_, data) in doc.nodes.iter() {
+            match data {
+                NodeData::Root => body.push(TAG_ROOT),
+                NodeData::NodeElement { element } => {
+                    body.push(TAG_ELEMENT);
+                    let namespace = self.intern_opt(element.name.namespace.as_deref());
+                    let name = self.intern(element.name.name.as_str());
+                    write_uint(&mut body, namespace);
+                    write_uint(&mut body, name);
+                    write_uint(&mut body, element.attributes.len() as u64);
+                    for attr in element.attributes.iter() {
+                        let namespace = self.intern_opt(attr.name.namespace.as_deref());
+                        let name = self.intern(attr.name.name.as_str());
+                        let value = self.intern_opt(attr.value.as_deref());
+                        write_uint(&mut body, namespace);
+                        write_uint(&mut body, name);
+                        write_uint(&mut body, value);
+                    }
+                }
+                NodeData::Leaf { value } => {
+                    body.push(TAG_LEAF);
+                    let value = self.intern(value.as_str());
+                    write_uint(&mut body, value);
+                }
+            }
+        }
+        for (node, _) in doc.nodes.iter() {
+            let children = doc.children(node);
+            write_uint(&mut body, children.len() as u64);
+            for child in children {
+                write_uint(&mut body, child.index() as u64);
+            }
+        }
+        write_uint(&mut body, doc.ids.len() as u64);
+        for (id, node) in doc.ids.iter() {
+            let id = self.intern(id.as_str());
+            write_uint(&mut body, id);
+            write_uint(&mut body, node.index() as u64);
+        }
+        write_uint(&mut body, doc.version);
+        let deleted = doc
+            .deleted
+            .keys()
+            .filter(|node| doc.deleted.contains(*node))
+            .collect::<Vec<_>>();
+        write_uint(&mut body, deleted.len() as u64);
+        for node in deleted {
+            write_uint(&mut body, node.index() as u64);
+        }
+        write_uint(&mut body, doc.history.iter().count() as u64);
+        for (node, version) in doc.history.iter() {
+            write_uint(&mut body, node.index() as u64);
+            write_uint(&mut body, version);
+        }
+
+        let strings_len: usize = self.strings.iter().map(|s| s.len() + 2).sum();
+        let mut out = Vec::with_capacity(MAGIC.len() + 1 + strings_len + body.len());
+        out.extend_from_slice(MAGIC);
+        out.push(BINARY_VERSION);
+        write_uint(&mut out, self.strings.len() as u64);
+        for s in self.strings.iter() {
+            write_uint(&mut out, s.len() as u64);
+            out.extend_from_slice(s.as_bytes());
+        }
+        out.extend_from_slice(&body);
+        out
+    }
+}
+
+struct Decoder<'a> {
+    input: &'a [u8],
+    strings: Vec<&'a str>,
+}
+impl<'a> Decoder<'a> {
+    fn new(input: &'a [u8]) -> Self {
+        Self {
+            input,
+            strings: vec![],
+        }
+    }
+
+    fn decode(mut self) -> Result<Document, DecodeError> {
+        if self.take(MAGIC.len())? != MAGIC {
+            return Err(DecodeError::InvalidMagic);
+        }
+        let version = self.byte()?;
+        if version != BINARY_VERSION {
+            return Err(DecodeError::UnsupportedVersion(version));
+        }
+
+        let num_strings = self.uint()?;
+        // Every string takes at least one byte, so this bounds the preallocation by the input size
+        self.strings
+            .reserve(num_strings.min(self.input.len() as u64) as usize);
+        for _ in 0..num_strings {
+            let len = self.uint()?;
+            let len = usize::try_from(len).map_err(|_| DecodeError::IntegerOverflow)?;
+            let bytes = self.take(len)?;
+            self.strings.push(std::str::from_utf8(bytes)?);
+        }
+
+        let root = self.uint()?;
+        let num_nodes = self.uint()?;
+        if root >= num_nodes {
+            return Err(DecodeError::InvalidNode(root));
+        }
+        let mut nodes = PrimaryMap::<NodeRef, NodeData>::with_capacity(
+            num_nodes.min(self.input.len() as u64) as usize,
+        );
+        for index in 0..num_nodes {
+            let data = match self.byte()? {
+                TAG_ROOT if index == root => NodeData::Root,
+                TAG_ROOT => return Err(DecodeError::MultipleRoots(index)),
+                _ if index == root => return Err(DecodeError::InvalidRoot(root)),
+                TAG_ELEMENT => {
+                    let namespace = self.string_opt()?;
+                    let name = self.string()?.to_string();
+                    let mut element = Element::new(ElementName { namespace, name });
+                    let num_attributes = self.uint()?;
+                    for _ in 0..num_attributes {
+                        let namespace = self.string_opt()?;
+                        let name = self.string()?.to_string();
+                        let value = self.string_opt()?;
+                        element.attributes.push(Attribute {
+                            name: AttributeName { namespace, name },
+                            value,
+                        });
+                    }
+                    NodeData::NodeElement { element }
+                }
+                TAG_LEAF => NodeData::Leaf {
+                    value: self.string()?.to_string(),
+                },
+                tag => return Err(DecodeError::InvalidTag(tag)),
+            };
+            nodes.push(data);
+        }
+
+        let mut parents = SecondaryMap::<NodeRef, PackedOption<NodeRef>>::new();
+        let mut children = SecondaryMap::<NodeRef, SmallVec<[NodeRef; 4]>>::new();
+        for index in 0..num_nodes {
+            let parent = NodeRef::new(index as usize);
+            let num_children = self.uint()?;
+            let mut node_children = SmallVec::<[NodeRef; 4]>::new();
+            for _ in 0..num_children {
+                let child = self.node(num_nodes)?;
+                if parents[child].is_some() {
+                    return Err(DecodeError::MultipleParents(child.index() as u64));
+                }
+                parents[child] = parent.into();
+                node_children.push(child);
+            }
+            children[parent] = node_children;
+        }
+        if parents[NodeRef::new(root as usize)].is_some() {
+            return Err(DecodeError::Cycle(root));
+        }
+        check_acyclic(&parents, num_nodes as usize)?;
+
+        let num_ids = self.uint()?;
+        let mut document = Document {
+            root: NodeRef::new(root as usize),
+            fragment_template: None,
+            event_callback: None,
+            component_callback: None,
+            node_key: None,
+            nodes,
+            deleted: EntitySet::new(),
+            parents,
+            children,
+            ids: Default::default(),
+            indexes: None,
+            subscriptions: Default::default(),
+            version: 0,
+            history: Default::default(),
+        };
+        for _ in 0..num_ids {
+            let id = SmallString::<[u8; 16]>::from_str(self.string()?);
+            let node = self.node(num_nodes)?;
+            document.ids.insert(id, node);
+        }
+
+        document.version = self.uint()?;
+        let num_deleted = self.uint()?;
+        for _ in 0..num_deleted {
+            let node = self.node(num_nodes)?;
+            if node == document.root || document.parents[node].is_some() {
+                return Err(DecodeError::DeletedNodeAttached(node.index() as u64));
+            }
+            document.deleted.insert(node);
+        }
+        let num_modified = self.uint()?;
+        let mut versions = BTreeMap::<u64, Vec<NodeRef>>::new();
+        for _ in 0..num_modified {
+            let node = self.node(num_nodes)?;
+            let version = self.uint()?;
+            if version > document.version {
+                return Err(DecodeError::InvalidVersion(node.index() as u64));
+            }
+            versions.entry(version).or_default().push(node);
+        }
+        for (version, nodes) in versions {
+            document.history.record(version, nodes, num_nodes as usize);
+        }
+
+        if !self.input.is_empty() {
+            return Err(DecodeError::TrailingData);
+        }
+
+        Ok(document)
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        if self.input.len() < len {
+            return Err(DecodeError::UnexpectedEof);
+        }
+        let (bytes, rest) = self.input.split_at(len);
+        self.input = rest;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn uint(&mut self) -> Result<u64, DecodeError> {
+        let mut value = 0u64;
+        let mut shift = 0;
+        loop {
+            let byte = self.byte()?;
+            if shift >= 64 || (shift == 63 && byte > 1) {
+                return Err(DecodeError::IntegerOverflow);
+            }
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+            shift += 7;
+        }
+    }
+
+    fn string(&mut self) -> Result<&'a str, DecodeError> {
+        let index = self.uint()?;
+        self.strings
+            .get(index as usize)
+            .copied()
+            .ok_or(DecodeError::InvalidString(index))
+    }
+
+    fn string_opt(&mut self) -> Result<Option<String>, DecodeError> {
+        match self.uint()? {
+            0 => Ok(None),
+            index => self
+                .strings
+                .get((index - 1) as usize)
+                .map(|s| Some(s.to_string()))
+                .ok_or(DecodeError::InvalidString(index - 1)),
+        }
+    }
+
+    fn node(&mut self, num_nodes: u64) -> Result<NodeRef, DecodeError> {
+        let index = self.uint()?;
+        if index >= num_nodes {
+            return Err(DecodeError::InvalidNode(index));
+        }
+        Ok(NodeRef::new(index as usize))
+    }
+}
+
+/// Ensures that following parent links from any node always terminates
+fn check_acyclic(
+    parents: &SecondaryMap<NodeRef, PackedOption<NodeRef>>,
+    num_nodes: usize,
+) -> Result<(), DecodeError> {
+    #[derive(Copy, Clone, PartialEq)]
+    enum Mark {
+        Unvisited,
+        Visiting,
+        Done,
+    }
+
+    let mut marks = vec![Mark::Unvisited; num_nodes];
+    let mut path = vec![];
+    for index in 0..num_nodes {
+        let mut node = NodeRef::new(index);
+        loop {
+            match marks[node.index()] {
+                Mark::Done => break,
+                Mark::Visiting => return Err(DecodeError::Cycle(node.index() as u64)),
+                Mark::Unvisited => {
+                    marks[node.index()] = Mark::Visiting;
+                    path.push(node);
+                    match parents[node].expand() {
+                        Some(parent) => node = parent,
+                        None => break,
+                    }
+                }
+            }
+        }
+        for node in path.drain(..) {
+            marks[node.index()] = Mark::Done;
+        }
+    }
+    Ok(())
+}
+
+fn write_uint(out: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
@@ -0,0 +1,345 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ops::Bound,
+    sync::Arc,
+};
+
+use cranelift_entity::SecondaryMap;
+
+use super::{path::PathCache, Attribute, AttributeChange, Document, NodeData, NodeRef};
+use crate::diff::{Patch, PatchResult};
+
+/// A node affected by a change, along with where it is in the document
+#[derive(Debug, Clone, PartialEq, uniffi::Record)]
+pub struct NodeChange {
+    pub node: Arc<NodeRef>,
+    /// The parent of the node, or for a removed node, the parent it was removed from
+    pub parent: Option<Arc<NodeRef>>,
+    /// The position of the node among the children of `parent`
+    ///
+    /// This is the position once all changes in the set are applied, except for removed nodes, where it is
+    /// the position the node had at the time it was removed.
+    pub index: Option<u32>,
+    /// The path from the root to the node, as of the same point as `index`, see [`Document::path_of`]
+    ///
+    /// This is only missing for a node which was removed while its ancestors were detached from the tree.
+    pub path: Option<String>,
+    /// The data of the node once all changes in the set are applied, or for a removed node, its last data
+    pub data: NodeData,
+    /// For a node whose attributes changed, each attribute which is different once all changes are applied
+    pub attributes: Vec<AttributeChange>,
+}
+
+/// Describes every change made to a document by a single merge, so it can be applied as one transaction
+///
+/// Each node is listed at most once, in the first category which applies to it: nodes which were added don't
+/// also appear as changed, and nodes in the subtree of an added or removed node aren't listed separately.
+#[derive(Debug, Clone, Default, PartialEq, uniffi::Record)]
+pub struct ChangeSet {
+    pub added: Vec<NodeChange>,
+    pub removed: Vec<NodeChange>,
+    /// Nodes which were detached from one position and reattached at another
+    pub moved: Vec<NodeChange>,
+    pub attributes_changed: Vec<NodeChange>,
+    /// Leaf nodes whose text changed
+    pub text_changed: Vec<NodeChange>,
+    /// Element nodes which were replaced in place by an element with a different name
+    pub replaced: Vec<NodeChange>,
+}
+impl ChangeSet {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.moved.is_empty()
+            && self.attributes_changed.is_empty()
+            && self.text_changed.is_empty()
+            && self.replaced.is_empty()
+    }
+}
+
+/// Records the results of applying patches to a document, to be summarized as a `ChangeSet`
+#[derive(Default)]
+pub(crate) struct ChangeRecorder {
+    /// Nodes which were attached to the tree, in the order they were attached
+    attached: Vec<NodeRef>,
+    /// Nodes which were detached by a patch, and the parent they were detached from, which makes attaching
+    /// them again a move
+    detached: BTreeMap<NodeRef, Option<NodeRef>>,
+    removed: Vec<NodeChange>,
+    changed: Vec<NodeRef>,
+    /// The attributes of each changed node before its first change
+    original_attributes: BTreeMap<NodeRef, Vec<Attribute>>,
+    replaced: BTreeSet<NodeRef>,
+}
+impl ChangeRecorder {
+    /// Called before `patch` is applied to `doc`, to capture state which the patch destroys
+    pub fn before(&mut self, doc: &Document, patch: &Patch) {
+        match patch {
+            Patch::Detach { node } => {
+                self.detached.entry(*node).or_insert(doc.parent(*node));
+            }
+            Patch::Remove { node } => {
+                if let Some(parent) = doc.parent(*node) {
+                    self.removed.push(NodeChange {
+                        node: Arc::new(*node),
+                        parent: Some(Arc::new(parent)),
+                        index: position(doc, parent, *node),
+                        path: doc.path_of(*node).map(|path| path.to_string()),
+                        data: doc.get(*node).clone(),
+                        attributes: vec![],
+                    });
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Called with the result of applying a patch
+    ///
+    /// The result is returned with the old parent of a move filled in, if the node had been detached earlier.
+    pub fn record(&mut self, mut result: PatchResult) -> PatchResult {
+        match result {
+            PatchResult::Add { node, .. } => self.attached.push(node),
+            PatchResult::Move {
+                node,
+                ref mut old_parent,
+                ..
+            } => {
+                let detached = self.detached.entry(node).or_insert(*old_parent);
+                if old_parent.is_none() {
+                    *old_parent = *detached;
+                }
+                self.attached.push(node);
+            }
+            // Captured in `before`, as the node's position is gone by now
+            PatchResult::Remove { .. } => (),
+            PatchResult::Change { node, ref data, .. } => {
+                self.changed.push(node);
+                self.original_attributes
+                    .entry(node)
+                    .or_insert_with(|| data.attributes());
+            }
+            // Leaves are reported as text changes rather than replacements
+            PatchResult::Replace { node, .. } | PatchResult::UpdateText { node, .. } => {
+                self.replaced.insert(node);
+            }
+        }
+        result
+    }
+
+    /// Returns the nodes touched by the recorded changes, as they are in the final state of `doc`
+    pub fn touched(&self, doc: &Document) -> Touched {
+        let moved = self
+            .attached
+            .iter()
+            .copied()
+            .filter(|node| self.detached.contains_key(node));
+        let changed = self.changed.iter().chain(self.replaced.iter()).copied();
+        let parents = self
+            .attached
+            .iter()
+            .filter_map(|node| doc.parent(*node))
+            .chain(self.detached.values().flatten().copied())
+            .chain(
+                self.removed
+                    .iter()
+                    .filter_map(|change| change.parent.as_deref().copied()),
+            );
+        Touched {
+            attached: self.attached.iter().copied().collect(),
+            changed: changed.chain(moved).collect(),
+            parents: parents.collect(),
+        }
+    }
+
+    /// Summarizes the recorded changes against the final state of `doc`
+    pub fn finish(self, doc: &Document) -> ChangeSet {
+        let mut seen = BTreeSet::new();
+        let mut paths = PathCache::default();
+        let mut changes = ChangeSet {
+            // A node removed along with the subtree it was in is part of the removal of that subtree
+            removed: self
+                .removed
+                .into_iter()
+                .filter(|change| {
+                    let parent = change.parent.as_deref().copied();
+                    parent.is_some_and(|parent| is_attached(doc, parent))
+                })
+                .collect(),
+            ..Default::default()
+        };
+
+        let added = self
+            .attached
+            .iter()
+            .copied()
+            .filter(|node| !self.detached.contains_key(node))
+            .collect::<BTreeSet<_>>();
+        for node in self.attached.iter().copied() {
+            // Nodes created inside a new subtree are part of the addition of its root, but nodes moved there
+            // are still reported, as they have left their old position
+            let in_new_subtree = ancestors(doc, node).any(|parent| added.contains(&parent));
+            if (in_new_subtree && added.contains(&node)) || !seen.insert(node) {
+                continue;
+            }
+            let Some(change) = describe(doc, &mut paths, node) else {
+                continue;
+            };
+            if added.contains(&node) {
+                changes.added.push(change);
+            } else {
+                changes.moved.push(change);
+            }
+        }
+
+        for node in self.changed.iter().chain(self.replaced.iter()).copied() {
+            let in_new_subtree = ancestors(doc, node).any(|parent| added.contains(&parent));
+            if in_new_subtree || seen.contains(&node) {
+                continue;
+            }
+            let Some(mut change) = describe(doc, &mut paths, node) else {
+                continue;
+            };
+            seen.insert(node);
+            match (&change.data, self.replaced.contains(&node)) {
+                (NodeData::Leaf { .. }, true) => changes.text_changed.push(change),
+                (_, true) => changes.replaced.push(change),
+                (data, false) => {
+                    let original = &self.original_attributes[&node];
+                    change.attributes = AttributeChange::between(original, &data.attributes());
+                    // Attributes which were changed and then changed back are left out
+                    if !change.attributes.is_empty() {
+                        changes.attributes_changed.push(change);
+                    }
+                }
+            }
+        }
+
+        changes
+    }
+}
+
+/// The nodes touched by a set of changes
+#[derive(Debug, Default)]
+pub(crate) struct Touched {
+    /// The roots of subtrees which were added or moved
+    pub attached: BTreeSet<NodeRef>,
+    /// Nodes whose data changed, or which were moved
+    pub changed: BTreeSet<NodeRef>,
+    /// Nodes which gained or lost children
+    pub parents: BTreeSet<NodeRef>,
+}
+impl Touched {
+    pub fn is_empty(&self) -> bool {
+        self.attached.is_empty() && self.changed.is_empty() && self.parents.is_empty()
+    }
+}
+
+/// The version in which each node of a document was last changed, see [`Document::changed_since`]
+#[derive(Debug, Default, Clone)]
+pub(crate) struct History {
+    /// The version in which each node was last changed, or 0 if it hasn't been since it was loaded
+    modified: SecondaryMap<NodeRef, u64>,
+    /// The nodes changed in each version, including nodes which have been changed again since
+    versions: BTreeMap<u64, Vec<NodeRef>>,
+    /// The number of nodes listed in `versions`
+    len: usize,
+}
+impl History {
+    /// Returns the version in which `node` was last changed
+    pub fn modified(&self, node: NodeRef) -> u64 {
+        self.modified[node]
+    }
+
+    /// Records that `nodes` were changed in `version`, which must be newer than any recorded so far
+    ///
+    /// Nodes listed under an older version than the one they were last changed in are dropped once they
+    /// make up most of the log, so it stays proportional to the `num_nodes` of the document.
+    pub fn record(&mut self, version: u64, mut nodes: Vec<NodeRef>, num_nodes: usize) {
+        nodes.sort_unstable();
+        nodes.dedup();
+        for node in nodes.iter() {
+            self.modified[*node] = version;
+        }
+        self.len += nodes.len();
+        self.versions.insert(version, nodes);
+
+        if self.len > 2 * num_nodes {
+            let modified = &self.modified;
+            self.versions.retain(|version, nodes| {
+                nodes.retain(|node| modified[*node] == *version);
+                !nodes.is_empty()
+            });
+            self.len = self.versions.values().map(Vec::len).sum();
+        }
+    }
+
+    /// Returns the nodes which have been changed since `version`, each listed once
+    pub fn since(&self, version: u64) -> impl Iterator<Item = NodeRef> + '_ {
+        self.versions
+            .range((Bound::Excluded(version), Bound::Unbounded))
+            .flat_map(move |(version, nodes)| {
+                // Nodes changed again later are listed under that version instead
+                nodes
+                    .iter()
+                    .copied()
+                    .filter(move |node| self.modified[*node] == *version)
+            })
+    }
+
+    /// Returns each node which has been changed, with the version it was last changed in
+    pub fn iter(&self) -> impl Iterator<Item = (NodeRef, u64)> + '_ {
+        self.modified
+            .iter()
+            .filter(|(_, version)| **version > 0)
+            .map(|(node, version)| (node, *version))
+    }
+
+    /// Forgets every change, as when the nodes of the document are cleared
+    pub fn clear(&mut self) {
+        self.modified.clear();
+        self.versions.clear();
+        self.len = 0;
+    }
+}
+
+/// Describes `node` as it is now, or returns `None` if it is no longer part of the tree
+fn describe(doc: &Document, paths: &mut PathCache, node: NodeRef) -> Option<NodeChange> {
+    let (path, index) = paths.get(doc, node)?;
+    let parent = doc.parent(node)?;
+    Some(NodeChange {
+        node: Arc::new(node),
+        parent: Some(Arc::new(parent)),
+        index: Some(index as u32),
+        path: Some(path.to_string()),
+        data: doc.get(node).clone(),
+        attributes: vec![],
+    })
+}
+
+/// Returns true if `node` is part of the tree under the root of `doc`
+pub(super) fn is_attached(doc: &Document, node: NodeRef) -> bool {
+    node == doc.root() || ancestors(doc, node).last() == Some(doc.root())
+}
+
+fn position(doc: &Document, parent: NodeRef, node: NodeRef) -> Option<u32> {
+    doc.children(parent)
+        .iter()
+        .position(|child| *child == node)
+        .map(|index| index as u32)
+}
+
+pub(super) fn ancestors(doc: &Document, node: NodeRef) -> impl Iterator<Item = NodeRef> + '_ {
+    std::iter::successors(doc.parent(node), |node| doc.parent(*node))
+}
+
+/// Returns `node` and all of its descendants
+pub(super) fn subtree(doc: &Document, node: NodeRef) -> Vec<NodeRef> {
+    let mut nodes = vec![];
+    let mut stack = vec![node];
+    while let Some(node) = stack.pop() {
+        nodes.push(node);
+        stack.extend_from_slice(doc.children(node));
+    }
+    nodes
+}
@@ -0,0 +1,101 @@
+//! Failure injection for the stages of [`super::Document::merge_fragment_json`]
+//!
+//! Some stages, such as applying patches, can't be made to fail through their input, so tests arm a
+//! failpoint instead. Outside of tests every failpoint passes.
+
+use crate::diff::{fragment::RenderError, PatchError};
+
+/// A stage of merging a fragment into a document
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Stage {
+    /// Decoding the JSON of the diff
+    Decode,
+    /// Merging the diff into the fragment template
+    Merge,
+    /// Rendering the merged template, or one of its components
+    Render,
+    /// Applying a patch to the document
+    Patch,
+}
+
+pub(crate) use self::imp::*;
+
+/// Fails with an injected error if the failpoint of `stage` is armed
+pub(crate) fn failpoint(stage: Stage) -> Result<(), RenderError> {
+    if fails(stage) {
+        return Err(injected(stage));
+    }
+    Ok(())
+}
+
+/// Fails with an injected error if the failpoint of [`Stage::Patch`] is armed
+pub(crate) fn patch(index: usize) -> Result<(), PatchError> {
+    if fails(Stage::Patch) {
+        return Err(injected_patch(index));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod imp {
+    use std::cell::Cell;
+
+    use super::Stage;
+    use crate::diff::{fragment::RenderError, PatchError};
+
+    thread_local! {
+        /// The stage to fail at, and how many times it passes before failing
+        static ARMED: Cell<Option<(Stage, usize)>> = const { Cell::new(None) };
+    }
+
+    /// Makes the failpoint of `stage` fail once, after passing `skip` times
+    pub(crate) fn arm(stage: Stage, skip: usize) {
+        ARMED.with(|armed| armed.set(Some((stage, skip))));
+    }
+
+    /// Returns true if an armed failpoint was not reached
+    pub(crate) fn disarm() -> bool {
+        ARMED.with(|armed| armed.take()).is_some()
+    }
+
+    pub(crate) fn injected(stage: Stage) -> RenderError {
+        RenderError::Injected(format!("{stage:?}"))
+    }
+
+    pub(crate) fn injected_patch(index: usize) -> PatchError {
+        PatchError::Injected { index }
+    }
+
+    pub(crate) fn fails(stage: Stage) -> bool {
+        ARMED.with(|armed| match armed.get() {
+            Some((armed_stage, 0)) if armed_stage == stage => {
+                armed.set(None);
+                true
+            }
+            Some((armed_stage, skip)) if armed_stage == stage => {
+                armed.set(Some((stage, skip - 1)));
+                false
+            }
+            _ => false,
+        })
+    }
+}
+
+#[cfg(not(test))]
+mod imp {
+    use super::Stage;
+    use crate::diff::{fragment::RenderError, PatchError};
+
+    pub(crate) fn injected(_stage: Stage) -> RenderError {
+        unreachable!("failpoints never fail outside of tests")
+    }
+
+    pub(crate) fn injected_patch(_index: usize) -> PatchError {
+        unreachable!("failpoints never fail outside of tests")
+    }
+
+    #[inline(always)]
+    pub(crate) fn fails(_stage: Stage) -> bool {
+        false
+    }
+}
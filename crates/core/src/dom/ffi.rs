@@ -1,22 +1,59 @@
-use std::{cell::SyncUnsafeCell, fmt, sync::Arc};
+use std::{
+    fmt, mem,
+    ops::{Deref, DerefMut},
+    sync::{Arc, Mutex, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError},
+};
 
 pub use super::{
-    attribute::Attribute,
+    attribute::{Attribute, AttributeName},
+    binary::DecodeError,
     node::{Node, NodeData, NodeRef},
+    path::{NodePath, PathError},
     printer::PrintOptions,
-    DocumentChangeHandler,
+    select::{Selector, SelectorError},
+    subscriptions::MatchChangeHandler,
+    ChangeSet, ComponentChangeHandler, DocumentChangeHandler,
+};
+use super::{subscriptions::MatchChangeType, AttributeChange, ChangeType, ComponentChangeType};
+use crate::{
+    diff::{fragment::RenderError, AttributeKeys, Patch, PatchError, PatchResult},
+    parser::ParseError,
 };
-use crate::{diff::fragment::RenderError, parser::ParseError};
 
+mod snapshot;
+#[cfg(test)]
+mod tests;
+
+pub use self::snapshot::Snapshot;
+
+/// The result of applying patches which can be undone
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct AppliedPatches {
+    pub changes: ChangeSet,
+    /// The JSON encoded patches which undo the changes
+    pub undo: String,
+}
+
+/// A document shared with the host, which may read it from any thread while another thread changes it
+///
+/// Changes take the document's write lock, so reads never observe a change half way through. The handlers
+/// notified of a change are called once the lock is released, so they can read the document themselves.
+/// The document owns its handlers, so a handler which keeps a reference to the document must hold it weakly,
+/// or the document is never freed.
+/// To traverse the tree without holding up changes, take a [`Snapshot`].
 #[derive(Clone, uniffi::Object)]
 pub struct Document {
-    inner: Arc<SyncUnsafeCell<super::Document>>,
+    /// The live document, which is shared with the snapshots taken of it, until it is changed while any of
+    /// them are still alive and cloned as a whole, see [`Document::snapshot`]
+    inner: Arc<RwLock<Arc<super::Document>>>,
+    outbox: Arc<Outbox>,
 }
 
 impl From<super::Document> for Document {
     fn from(doc: super::Document) -> Self {
         Self {
-            inner: Arc::new(SyncUnsafeCell::new(doc)),
+            inner: Arc::new(RwLock::new(Arc::new(doc))),
+            outbox: Default::default(),
         }
     }
 }
@@ -25,33 +62,211 @@ impl From<super::Document> for Document {
 impl Document {
     #[uniffi::constructor]
     pub fn parse(input: String) -> Result<Arc<Self>, ParseError> {
-        Ok(Arc::new(Self {
-            inner: Arc::new(SyncUnsafeCell::new(super::Document::parse(input)?)),
-        }))
+        Ok(Arc::new(super::Document::parse(input)?.into()))
     }
 
     #[uniffi::constructor]
     pub fn empty() -> Arc<Self> {
-        Arc::new(Self {
-            inner: Arc::new(SyncUnsafeCell::new(super::Document::empty())),
-        })
+        Arc::new(super::Document::empty().into())
     }
 
     #[uniffi::constructor]
     pub fn parse_fragment_json(input: String) -> Result<Arc<Self>, RenderError> {
-        let inner = Arc::new(SyncUnsafeCell::new(super::Document::parse_fragment_json(
-            input,
-        )?));
-        Ok(Arc::new(Self { inner }))
+        Ok(Arc::new(
+            super::Document::parse_fragment_json(input)?.into(),
+        ))
     }
+
+    #[uniffi::constructor]
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<Arc<Self>, DecodeError> {
+        Ok(Arc::new(super::Document::from_bytes(&bytes)?.into()))
+    }
+
     pub fn set_event_handler(&self, handler: Box<dyn DocumentChangeHandler>) {
-        self.inner_mut().event_callback = Some(Arc::from(handler));
+        let handler = self.outbox.defer(Arc::from(handler));
+        self.inner_mut().event_callback = Some(Arc::new(handler));
+    }
+
+    pub fn set_component_handler(&self, handler: Box<dyn ComponentChangeHandler>) {
+        let handler = self.outbox.defer(Arc::from(handler));
+        self.inner_mut().component_callback = Some(Arc::new(handler));
+    }
+
+    /// Matches up elements by the first of `attributes` which they have when merging, instead of by `id`
+    ///
+    /// An empty list restores the default of matching by `id`.
+    pub fn set_key_attributes(&self, attributes: Vec<String>) {
+        self.inner_mut().node_key = if attributes.is_empty() {
+            None
+        } else {
+            let keys = AttributeKeys::new(attributes.iter().map(String::as_str));
+            Some(Arc::new(keys))
+        };
     }
 
-    pub fn merge_fragment_json(&self, json: String) -> Result<(), RenderError> {
+    /// Indexes elements by tag, attribute and class, to speed up queries on large documents
+    pub fn enable_indexes(&self) {
+        self.inner_mut().enable_indexes();
+    }
+
+    pub fn merge_fragment_json(&self, json: String) -> Result<ChangeSet, RenderError> {
         self.inner_mut().merge_fragment_json(json)
     }
 
+    /// Returns the CIDs of the components which are no longer rendered, to send in `cids_will_destroy`
+    pub fn orphaned_components(&self) -> Vec<i32> {
+        self.inner().orphaned_components()
+    }
+
+    /// Drops the components with `cids` once the server acknowledged `cids_destroyed`, keeping those rendered
+    /// again since, and returns the CIDs which were dropped
+    pub fn destroy_components(&self, cids: Vec<i32>) -> Vec<i32> {
+        self.inner_mut().destroy_components(cids)
+    }
+
+    /// Applies a JSON encoded list of patches, computed against a copy of this document
+    ///
+    /// The list is rejected as a whole if any patch refers to a node this document doesn't have.
+    pub fn apply_patches(&self, json: String) -> Result<ChangeSet, PatchError> {
+        let patches = serde_json::from_str(&json)?;
+        self.inner_mut().try_apply_patches(patches)
+    }
+
+    /// Like `apply_patches`, but also returns a JSON encoded list of patches which undo the changes
+    ///
+    /// Passing the undo patches to `apply_patches` reverts the document, if it hasn't changed in between.
+    pub fn apply_patches_with_undo(&self, json: String) -> Result<AppliedPatches, PatchError> {
+        let patches = serde_json::from_str(&json)?;
+        let (changes, undo) = self.inner_mut().try_apply_patches_with_undo(patches)?;
+        let undo = serde_json::to_string(&undo)?;
+        Ok(AppliedPatches { changes, undo })
+    }
+
+    /// Appends a new node with `data` to the children of `parent`, returning the new node
+    ///
+    /// Like the other edits below, this is applied as a patch, so the event handler and subscriptions are
+    /// notified of it, and the version of the document is bumped.
+    pub fn append_child(
+        &self,
+        parent: Arc<NodeRef>,
+        data: NodeData,
+    ) -> Result<Arc<NodeRef>, PatchError> {
+        self.insert(Patch::AppendTo {
+            parent: *parent,
+            node: data,
+        })
+    }
+
+    /// Inserts a new node with `data` just before `before`, returning the new node
+    pub fn insert_before(
+        &self,
+        before: Arc<NodeRef>,
+        data: NodeData,
+    ) -> Result<Arc<NodeRef>, PatchError> {
+        self.insert(Patch::InsertBefore {
+            before: *before,
+            node: data,
+        })
+    }
+
+    /// Inserts a new node with `data` just after `after`, returning the new node
+    pub fn insert_after(
+        &self,
+        after: Arc<NodeRef>,
+        data: NodeData,
+    ) -> Result<Arc<NodeRef>, PatchError> {
+        self.insert(Patch::InsertAfter {
+            after: *after,
+            node: data,
+        })
+    }
+
+    /// Removes `node_ref` and its subtree from the document
+    pub fn remove(&self, node_ref: Arc<NodeRef>) -> Result<(), PatchError> {
+        self.edit(Patch::Remove { node: *node_ref })
+    }
+
+    pub fn set_attribute(
+        &self,
+        node_ref: Arc<NodeRef>,
+        name: AttributeName,
+        value: Option<String>,
+    ) -> Result<(), PatchError> {
+        self.edit(Patch::UpdateAttribute {
+            node: *node_ref,
+            name,
+            value,
+        })
+    }
+
+    pub fn remove_attribute(
+        &self,
+        node_ref: Arc<NodeRef>,
+        name: AttributeName,
+    ) -> Result<(), PatchError> {
+        self.edit(Patch::RemoveAttributeByName {
+            node: *node_ref,
+            name,
+        })
+    }
+
+    pub fn replace_attributes(
+        &self,
+        node_ref: Arc<NodeRef>,
+        attributes: Vec<Attribute>,
+    ) -> Result<(), PatchError> {
+        self.edit(Patch::SetAttributes {
+            node: *node_ref,
+            attributes,
+        })
+    }
+
+    /// Sets the text of the leaf `node_ref`
+    pub fn set_text(&self, node_ref: Arc<NodeRef>, text: String) -> Result<(), PatchError> {
+        self.edit(Patch::UpdateText {
+            node: *node_ref,
+            value: text,
+        })
+    }
+
+    /// Returns an immutable view of the document as it is now, which can be read without locking it
+    ///
+    /// # Cost
+    ///
+    /// Snapshots are copy-on-write, without structural sharing. Taking one is O(1), as it shares the document,
+    /// but the first change made to the document while any snapshot of it is alive is O(n) in the size of the
+    /// document: it clones every node, the indexes and the subscriptions before changing the copy. Later changes
+    /// are O(1) again until the next snapshot is taken. The `snapshot-copy` benches measure that copy.
+    ///
+    /// Drop snapshots as soon as they have been read, so that changes made in the meantime don't pay for it.
+    pub fn snapshot(&self) -> Arc<Snapshot> {
+        let doc = self.inner.read().unwrap_or_else(PoisonError::into_inner);
+        Arc::new(Snapshot::new(doc.clone()))
+    }
+
+    /// Returns the version of the document, which is bumped by every merge or list of patches which changes it
+    pub fn version(&self) -> u64 {
+        self.inner().version()
+    }
+
+    /// Returns the nodes which have been added or changed since `version`, or lost or gained children
+    pub fn changed_since(&self, version: u64) -> Vec<Arc<NodeRef>> {
+        self.inner()
+            .changed_since(version)
+            .into_iter()
+            .map(Arc::new)
+            .collect()
+    }
+
+    /// Returns the version in which `node_ref` was last changed, or 0 if it hasn't been
+    pub fn modified_version(&self, node_ref: Arc<NodeRef>) -> u64 {
+        self.inner().modified_version(*node_ref)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.inner().to_bytes()
+    }
+
     pub fn root(&self) -> Arc<NodeRef> {
         self.inner().root().into()
     }
@@ -70,6 +285,47 @@ impl Document {
             .collect()
     }
 
+    /// Returns the first node matching the CSS `selector`, e.g. `form#upload-form > input[data-phx-upload-ref]`
+    pub fn query(&self, selector: String) -> Result<Option<Arc<NodeRef>>, SelectorError> {
+        let selector = Selector::parse(&selector)?;
+        Ok(self.inner().select(selector).next().map(Arc::new))
+    }
+
+    /// Returns every node matching the CSS `selector`
+    pub fn query_all(&self, selector: String) -> Result<Vec<Arc<NodeRef>>, SelectorError> {
+        let selector = Selector::parse(&selector)?;
+        Ok(self.inner().select(selector).map(Arc::new).collect())
+    }
+
+    /// Returns the path from the root to `node_ref`, e.g. `/VStack[1]/Text[2]`, or nothing if it is not
+    /// part of the tree
+    pub fn path_of(&self, node_ref: Arc<NodeRef>) -> Option<String> {
+        self.inner().path_of(*node_ref).map(|path| path.to_string())
+    }
+
+    /// Returns the node at `path`, as returned by `path_of` for this or any copy of the document
+    pub fn resolve_path(&self, path: String) -> Result<Option<Arc<NodeRef>>, PathError> {
+        let path = NodePath::parse(&path)?;
+        Ok(self.inner().resolve_path(&path).map(Arc::new))
+    }
+
+    /// Notifies `handler` whenever a node starts or stops matching the CSS `selector`, or changes while
+    /// matching it, after each merge
+    ///
+    /// Returns an id which can be passed to `unsubscribe`.
+    pub fn subscribe(
+        &self,
+        selector: String,
+        handler: Box<dyn MatchChangeHandler>,
+    ) -> Result<u64, SelectorError> {
+        let handler = self.outbox.defer(Arc::from(handler));
+        self.inner_mut().subscribe(&selector, Arc::new(handler))
+    }
+
+    pub fn unsubscribe(&self, id: u64) -> bool {
+        self.inner_mut().unsubscribe(id)
+    }
+
     pub fn get_attributes(&self, node_ref: Arc<NodeRef>) -> Vec<Attribute> {
         self.inner().attributes(*node_ref).to_vec()
     }
@@ -85,12 +341,25 @@ impl Document {
     }
 }
 impl Document {
-    fn inner(&self) -> &super::Document {
-        unsafe { &*self.inner.get() }
+    fn edit(&self, patch: Patch) -> Result<(), PatchError> {
+        self.inner_mut().try_apply_patch(patch).map(|_| ())
     }
-    #[allow(clippy::mut_from_ref)]
-    fn inner_mut(&self) -> &mut super::Document {
-        unsafe { &mut *self.inner.get() }
+    /// Applies `patch`, which adds a single node, returning the node it added
+    fn insert(&self, patch: Patch) -> Result<Arc<NodeRef>, PatchError> {
+        match self.inner_mut().try_apply_patch(patch)? {
+            (_, Some(PatchResult::Add { node, .. })) => Ok(Arc::new(node)),
+            _ => unreachable!("patches which insert a node report the node they added"),
+        }
+    }
+    pub(super) fn inner(&self) -> Reader<'_> {
+        Reader(self.inner.read().unwrap_or_else(PoisonError::into_inner))
+    }
+    /// Locks the document for a change, delivering the notifications it raises once the lock is released
+    fn inner_mut(&self) -> Writer<'_> {
+        Writer {
+            guard: Some(self.inner.write().unwrap_or_else(PoisonError::into_inner)),
+            outbox: &self.outbox,
+        }
     }
     pub fn print_node(
         &self,
@@ -108,3 +377,140 @@ impl fmt::Display for Document {
         self.inner().print(f, PrintOptions::Pretty)
     }
 }
+
+/// The read lock of a [`Document`]
+pub(super) struct Reader<'a>(RwLockReadGuard<'a, Arc<super::Document>>);
+impl Deref for Reader<'_> {
+    type Target = super::Document;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+/// The write lock of a [`Document`], which delivers the notifications raised under it when dropped
+struct Writer<'a> {
+    guard: Option<RwLockWriteGuard<'a, Arc<super::Document>>>,
+    outbox: &'a Outbox,
+}
+impl Deref for Writer<'_> {
+    type Target = super::Document;
+
+    fn deref(&self) -> &Self::Target {
+        self.guard.as_deref().unwrap()
+    }
+}
+impl DerefMut for Writer<'_> {
+    /// Clones the document if a snapshot still shares it, which is O(n) in the size of the document
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        Arc::make_mut(self.guard.as_deref_mut().unwrap())
+    }
+}
+impl Drop for Writer<'_> {
+    fn drop(&mut self) {
+        self.guard.take();
+        self.outbox.deliver();
+    }
+}
+
+type Notification = Box<dyn FnOnce() + Send>;
+
+/// Holds the notifications raised while a [`Document`] is locked for writing, until it is unlocked
+#[derive(Default)]
+struct Outbox {
+    queue: Mutex<Vec<Notification>>,
+    /// Held by the thread delivering notifications, so they are delivered one at a time and in order
+    delivering: Mutex<()>,
+}
+impl Outbox {
+    /// Wraps `handler` so that its notifications are queued here
+    fn defer<H: ?Sized>(self: &Arc<Self>, handler: Arc<H>) -> Deferred<H> {
+        Deferred {
+            handler,
+            outbox: self.clone(),
+        }
+    }
+
+    fn push(&self, notification: Notification) {
+        self.queue
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(notification);
+    }
+
+    fn take(&self) -> Vec<Notification> {
+        mem::take(&mut *self.queue.lock().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    fn is_empty(&self) -> bool {
+        self.queue
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .is_empty()
+    }
+
+    /// Delivers every queued notification, unless another thread is already delivering them
+    ///
+    /// A handler which changes the document from within a notification ends up here too, in which case its
+    /// notifications are delivered by the outer call once the current one returns.
+    fn deliver(&self) {
+        loop {
+            let delivering = match self.delivering.try_lock() {
+                Ok(delivering) => delivering,
+                Err(TryLockError::Poisoned(err)) => err.into_inner(),
+                Err(TryLockError::WouldBlock) => return,
+            };
+            loop {
+                let notifications = self.take();
+                if notifications.is_empty() {
+                    break;
+                }
+                for notification in notifications {
+                    notification();
+                }
+            }
+            drop(delivering);
+            // Notifications queued by a thread which found this one delivering are picked up here
+            if self.is_empty() {
+                return;
+            }
+        }
+    }
+}
+
+/// A handler whose notifications are queued in an [`Outbox`] rather than delivered immediately
+struct Deferred<H: ?Sized> {
+    handler: Arc<H>,
+    outbox: Arc<Outbox>,
+}
+impl DocumentChangeHandler for Deferred<dyn DocumentChangeHandler> {
+    fn handle(
+        &self,
+        change_type: ChangeType,
+        node_ref: Arc<NodeRef>,
+        node_data: NodeData,
+        parent: Option<Arc<NodeRef>>,
+        attributes: Vec<AttributeChange>,
+        path: Option<String>,
+    ) {
+        let handler = self.handler.clone();
+        self.outbox.push(Box::new(move || {
+            handler.handle(change_type, node_ref, node_data, parent, attributes, path)
+        }));
+    }
+}
+impl ComponentChangeHandler for Deferred<dyn ComponentChangeHandler> {
+    fn handle(&self, change_type: ComponentChangeType, cid: i32, node_ref: Option<Arc<NodeRef>>) {
+        let handler = self.handler.clone();
+        self.outbox
+            .push(Box::new(move || handler.handle(change_type, cid, node_ref)));
+    }
+}
+impl MatchChangeHandler for Deferred<dyn MatchChangeHandler> {
+    fn handle(&self, change_type: MatchChangeType, node_ref: Arc<NodeRef>, node_data: NodeData) {
+        let handler = self.handler.clone();
+        self.outbox.push(Box::new(move || {
+            handler.handle(change_type, node_ref, node_data)
+        }));
+    }
+}
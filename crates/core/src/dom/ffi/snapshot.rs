@@ -0,0 +1,102 @@
+use std::{fmt, sync::Arc};
+
+use super::{
+    Attribute, NodeData, NodePath, NodeRef, PathError, PrintOptions, Selector, SelectorError,
+};
+
+/// An immutable view of a [`super::Document`] as it was at some point
+///
+/// Reading a snapshot never waits on changes to the document, and always sees the same tree, so a renderer
+/// can traverse it in several calls without the tree changing in between.
+///
+/// A snapshot shares the document it was taken of rather than copying it, so the first change made to the
+/// document while the snapshot is alive clones the whole document, see [`super::Document::snapshot`].
+#[derive(uniffi::Object)]
+pub struct Snapshot {
+    doc: Arc<crate::dom::Document>,
+}
+
+impl Snapshot {
+    pub(super) fn new(doc: Arc<crate::dom::Document>) -> Self {
+        Self { doc }
+    }
+}
+
+#[uniffi::export]
+impl Snapshot {
+    /// Returns the version of the document when the snapshot was taken
+    ///
+    /// Two snapshots of the same document with the same version contain the same tree.
+    pub fn version(&self) -> u64 {
+        self.doc.version()
+    }
+
+    /// Returns the nodes which had been added or changed since `version` when the snapshot was taken
+    pub fn changed_since(&self, version: u64) -> Vec<Arc<NodeRef>> {
+        self.doc
+            .changed_since(version)
+            .into_iter()
+            .map(Arc::new)
+            .collect()
+    }
+
+    pub fn root(&self) -> Arc<NodeRef> {
+        self.doc.root().into()
+    }
+
+    pub fn get_parent(&self, node_ref: Arc<NodeRef>) -> Option<Arc<NodeRef>> {
+        self.doc.parent(*node_ref).map(|node_ref| node_ref.into())
+    }
+
+    pub fn children(&self, node_ref: Arc<NodeRef>) -> Vec<Arc<NodeRef>> {
+        self.doc
+            .children(*node_ref)
+            .iter()
+            .map(|node| Arc::new(*node))
+            .collect()
+    }
+
+    pub fn get(&self, node_ref: Arc<NodeRef>) -> NodeData {
+        self.doc.get(*node_ref).clone()
+    }
+
+    pub fn get_attributes(&self, node_ref: Arc<NodeRef>) -> Vec<Attribute> {
+        self.doc.attributes(*node_ref)
+    }
+
+    /// Returns the first node matching the CSS `selector`
+    pub fn query(&self, selector: String) -> Result<Option<Arc<NodeRef>>, SelectorError> {
+        let selector = Selector::parse(&selector)?;
+        Ok(self.doc.select(selector).next().map(Arc::new))
+    }
+
+    /// Returns every node matching the CSS `selector`
+    pub fn query_all(&self, selector: String) -> Result<Vec<Arc<NodeRef>>, SelectorError> {
+        let selector = Selector::parse(&selector)?;
+        Ok(self.doc.select(selector).map(Arc::new).collect())
+    }
+
+    pub fn path_of(&self, node_ref: Arc<NodeRef>) -> Option<String> {
+        self.doc.path_of(*node_ref).map(|path| path.to_string())
+    }
+
+    pub fn resolve_path(&self, path: String) -> Result<Option<Arc<NodeRef>>, PathError> {
+        let path = NodePath::parse(&path)?;
+        Ok(self.doc.resolve_path(&path).map(Arc::new))
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.doc.to_bytes()
+    }
+
+    pub fn render(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl fmt::Display for Snapshot {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.doc.print(f, PrintOptions::Pretty)
+    }
+}
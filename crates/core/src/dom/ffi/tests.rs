@@ -0,0 +1,479 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, Weak,
+    },
+    thread,
+};
+
+use super::*;
+use crate::dom::{node::NodeIterator, Element};
+
+const ITEMS: usize = 8;
+const MERGES: usize = 200;
+
+fn list(len: usize) -> String {
+    let items = (0..len)
+        .map(|i| format!(r#"["{i}"]"#))
+        .collect::<Vec<_>>()
+        .join(",");
+    format!(r#"{{"0": {{"d": [{items}]}}}}"#)
+}
+
+/// Returns the ids of the items in `doc`, checking that they are numbered in order
+fn items(doc: &super::super::Document) -> usize {
+    let ids = doc
+        .select(Selector::Tag("Item".into()))
+        .filter_map(|node| doc.get(node).attributes().into_iter().next())
+        .filter_map(|attr| attr.value)
+        .collect::<Vec<_>>();
+    let mut expected = (0..ids.len()).map(|i| i.to_string()).collect::<Vec<_>>();
+    // The selection visits siblings from last to first
+    expected.reverse();
+    assert_eq!(ids, expected, "read a partially merged document");
+    ids.len()
+}
+
+#[test]
+fn ffi_concurrent_reads_during_merges() {
+    let doc = Document::parse_fragment_json(
+        r#"{"0": {"d": [], "s": ["<Item id=\"", "\"/>"]}, "s": ["<List>", "</List>"]}"#.into(),
+    )
+    .unwrap();
+    let done = Arc::new(AtomicBool::new(false));
+
+    let readers = (0..4)
+        .map(|_| {
+            let doc = doc.clone();
+            let done = done.clone();
+            thread::spawn(move || {
+                let mut reads = 0;
+                while !done.load(Ordering::Relaxed) {
+                    let rendered = super::super::Document::parse(doc.render()).unwrap();
+                    assert!(items(&rendered) <= ITEMS);
+                    let copy = super::super::Document::from_bytes(&doc.to_bytes()).unwrap();
+                    items(&copy);
+
+                    let found = doc.query_all("Item".into()).unwrap();
+                    assert!(found.len() <= ITEMS);
+                    if let Some(node) = doc.query("List > Item".into()).unwrap() {
+                        // The node may be gone by now, but reading it must not fail
+                        if let Some(path) = doc.path_of(node) {
+                            doc.resolve_path(path).unwrap();
+                        }
+                    }
+                    let root = doc.root();
+                    for child in doc.children(root) {
+                        doc.get(child);
+                    }
+                    reads += 1;
+                }
+                reads
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for i in 0..MERGES {
+        doc.merge_fragment_json(list(i % (ITEMS + 1))).unwrap();
+    }
+    done.store(true, Ordering::Relaxed);
+    for reader in readers {
+        reader.join().expect("reader panicked");
+    }
+    assert_eq!(
+        doc.query_all("Item".into()).unwrap().len(),
+        (MERGES - 1) % (ITEMS + 1)
+    );
+}
+
+/// Reads the document it is attached to from within each notification
+///
+/// The document is held weakly, as the document owns its handlers.
+struct Reader {
+    doc: Weak<Document>,
+    seen: Arc<Mutex<Vec<usize>>>,
+}
+impl DocumentChangeHandler for Reader {
+    fn handle(
+        &self,
+        _change_type: ChangeType,
+        _node_ref: Arc<NodeRef>,
+        _node_data: NodeData,
+        _parent: Option<Arc<NodeRef>>,
+        _attributes: Vec<AttributeChange>,
+        _path: Option<String>,
+    ) {
+        let Some(doc) = self.doc.upgrade() else {
+            return;
+        };
+        let len = items(&doc.inner());
+        self.seen.lock().unwrap().push(len);
+    }
+}
+
+/// Unsubscribes itself from the document it is attached to on its first notification
+struct Unsubscriber {
+    doc: Weak<Document>,
+    id: Arc<Mutex<Option<u64>>>,
+    calls: Arc<Mutex<usize>>,
+}
+impl MatchChangeHandler for Unsubscriber {
+    fn handle(&self, _change_type: MatchChangeType, _node_ref: Arc<NodeRef>, _data: NodeData) {
+        *self.calls.lock().unwrap() += 1;
+        let Some(doc) = self.doc.upgrade() else {
+            return;
+        };
+        if let Some(id) = self.id.lock().unwrap().take() {
+            assert!(doc.unsubscribe(id));
+        }
+    }
+}
+
+#[test]
+fn ffi_handlers_can_use_document() {
+    let doc = Document::parse_fragment_json(
+        r#"{"0": {"d": [], "s": ["<Item id=\"", "\"/>"]}, "s": ["<List>", "</List>"]}"#.into(),
+    )
+    .unwrap();
+    let seen = Arc::new(Mutex::new(vec![]));
+    doc.set_event_handler(Box::new(Reader {
+        doc: Arc::downgrade(&doc),
+        seen: seen.clone(),
+    }));
+    let id = Arc::new(Mutex::new(None));
+    let calls = Arc::new(Mutex::new(0));
+    let subscription = doc
+        .subscribe(
+            "Item".into(),
+            Box::new(Unsubscriber {
+                doc: Arc::downgrade(&doc),
+                id: id.clone(),
+                calls: calls.clone(),
+            }),
+        )
+        .unwrap();
+    *id.lock().unwrap() = Some(subscription);
+
+    // Notifications are delivered once the merge is complete
+    doc.merge_fragment_json(list(3)).unwrap();
+    assert_eq!(*seen.lock().unwrap(), vec![3; 3]);
+    assert_eq!(*calls.lock().unwrap(), 3);
+
+    doc.merge_fragment_json(list(1)).unwrap();
+    assert_eq!(*seen.lock().unwrap(), vec![3, 3, 3, 1, 1]);
+    assert_eq!(*calls.lock().unwrap(), 3);
+    assert!(!doc.unsubscribe(subscription));
+
+    // The handlers don't keep the document alive once the host drops it
+    let inner = Arc::downgrade(&doc.inner);
+    let outbox = Arc::downgrade(&doc.outbox);
+    drop(doc);
+    assert!(inner.upgrade().is_none());
+    assert!(outbox.upgrade().is_none());
+}
+
+/// Returns the ids of the items in the list of `snapshot`, in order
+fn snapshot_items(snapshot: &Snapshot) -> Vec<String> {
+    let root = snapshot.root();
+    let list = snapshot.children(root)[0].clone();
+    snapshot
+        .children(list)
+        .into_iter()
+        .filter_map(|item| snapshot.get_attributes(item).into_iter().next())
+        .filter_map(|attr| attr.value)
+        .collect()
+}
+
+#[test]
+fn ffi_snapshots() {
+    let doc = Document::parse_fragment_json(
+        r#"{"0": {"d": [], "s": ["<Item id=\"", "\"/>"]}, "s": ["<List>", "</List>"]}"#.into(),
+    )
+    .unwrap();
+    doc.merge_fragment_json(list(2)).unwrap();
+    let before = doc.snapshot();
+    assert_eq!(before.version(), doc.snapshot().version());
+    assert_eq!(snapshot_items(&before), vec!["0", "1"]);
+
+    // Snapshots are unaffected by later changes
+    let rendered = before.render();
+    doc.merge_fragment_json(list(3)).unwrap();
+    assert_eq!(before.render(), rendered);
+    assert_eq!(snapshot_items(&before), vec!["0", "1"]);
+    let after = doc.snapshot();
+    assert!(after.version() > before.version());
+    assert_eq!(snapshot_items(&after), vec!["0", "1", "2"]);
+    assert_eq!(after.render(), doc.render());
+    let item = after.query(r#"Item[id="2"]"#.into()).unwrap().unwrap();
+    assert_eq!(after.path_of(item.clone()).unwrap(), "/List[1]/Item[3]");
+    assert_eq!(before.resolve_path("/List[1]/Item[3]".into()), Ok(None));
+
+    // Every snapshot taken during a stream of merges is a consistent tree
+    let readers = (0..4)
+        .map(|_| {
+            let doc = doc.clone();
+            thread::spawn(move || {
+                for _ in 0..MERGES {
+                    let snapshot = doc.snapshot();
+                    let ids = snapshot_items(&snapshot);
+                    let expected = (0..ids.len()).map(|i| i.to_string()).collect::<Vec<_>>();
+                    assert_eq!(ids, expected, "snapshot of a partially merged document");
+                    assert_eq!(snapshot_items(&snapshot), ids);
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for i in 0..MERGES {
+        doc.merge_fragment_json(list(i % (ITEMS + 1))).unwrap();
+    }
+    for reader in readers {
+        reader.join().expect("reader panicked");
+    }
+}
+
+/// Records the type and path of each change it is notified of, and the attribute changes
+#[derive(Default)]
+struct Changes(
+    Mutex<Vec<&'static str>>,
+    Mutex<Vec<AttributeChange>>,
+    Mutex<Vec<Option<String>>>,
+);
+impl DocumentChangeHandler for Arc<Changes> {
+    fn handle(
+        &self,
+        change_type: ChangeType,
+        _node_ref: Arc<NodeRef>,
+        _node_data: NodeData,
+        _parent: Option<Arc<NodeRef>>,
+        attributes: Vec<AttributeChange>,
+        path: Option<String>,
+    ) {
+        self.1.lock().unwrap().extend(attributes);
+        self.2.lock().unwrap().push(path);
+        let name = match change_type {
+            ChangeType::Change => "change",
+            ChangeType::Add => "add",
+            ChangeType::Remove => "remove",
+            ChangeType::Replace => "replace",
+            ChangeType::Move => "move",
+            ChangeType::UpdateText => "update-text",
+        };
+        self.0.lock().unwrap().push(name);
+    }
+}
+
+fn render(html: &str) -> String {
+    super::super::Document::parse(html).unwrap().to_string()
+}
+
+#[test]
+fn ffi_edits() {
+    let doc = Document::parse("<VStack><Spacer/></VStack>".into()).unwrap();
+    let changes = Arc::new(Changes::default());
+    doc.set_event_handler(Box::new(changes.clone()));
+    let root = doc.root();
+    let stack = doc.children(root.clone())[0].clone();
+    let spacer = doc.children(stack.clone())[0].clone();
+
+    let text = doc
+        .append_child(stack.clone(), NodeData::new("Text"))
+        .unwrap();
+    let leaf = doc
+        .append_child(text.clone(), NodeData::Leaf { value: "a".into() })
+        .unwrap();
+    let image = doc
+        .insert_before(spacer.clone(), NodeData::new("Image"))
+        .unwrap();
+    let button = doc
+        .insert_after(spacer.clone(), NodeData::new("Button"))
+        .unwrap();
+    assert_eq!(doc.get_parent(image.clone()), Some(stack.clone()));
+    assert_eq!(
+        doc.children(stack.clone()),
+        vec![image.clone(), spacer.clone(), button.clone(), text.clone()]
+    );
+
+    doc.set_attribute(button.clone(), "id".into(), Some("b".into()))
+        .unwrap();
+    doc.set_attribute(button.clone(), "class".into(), Some("x".into()))
+        .unwrap();
+    doc.remove_attribute(button.clone(), "id".into()).unwrap();
+    doc.replace_attributes(
+        image.clone(),
+        vec![Attribute::new("src", Some("a.png".into()))],
+    )
+    .unwrap();
+    doc.set_text(leaf.clone(), "b".into()).unwrap();
+    doc.remove(spacer.clone()).unwrap();
+    assert_eq!(
+        doc.render(),
+        render(r#"<VStack><Image src="a.png"/><Button class="x"/><Text>b</Text></VStack>"#)
+    );
+    assert_eq!(doc.version(), 10);
+    assert_eq!(
+        *changes.0.lock().unwrap(),
+        vec![
+            "add",
+            "add",
+            "add",
+            "add",
+            "change",
+            "change",
+            "change",
+            "change",
+            "update-text",
+            "remove"
+        ]
+    );
+    assert_eq!(
+        *changes.1.lock().unwrap(),
+        vec![
+            AttributeChange::Added {
+                name: "id".into(),
+                value: Some("b".into())
+            },
+            AttributeChange::Added {
+                name: "class".into(),
+                value: Some("x".into())
+            },
+            AttributeChange::Removed {
+                name: "id".into(),
+                old_value: Some("b".into())
+            },
+            AttributeChange::Added {
+                name: "src".into(),
+                value: Some("a.png".into())
+            },
+        ]
+    );
+    let paths = changes.2.lock().unwrap().clone();
+    assert_eq!(
+        paths.iter().map(|path| path.as_deref()).collect::<Vec<_>>(),
+        vec![
+            Some("/VStack[1]/Text[1]"),
+            Some("/VStack[1]/Text[1]/text()[1]"),
+            Some("/VStack[1]/Image[1]"),
+            Some("/VStack[1]/Button[1]"),
+            Some("/VStack[1]/Button[1]"),
+            Some("/VStack[1]/Button[1]"),
+            Some("/VStack[1]/Button[1]"),
+            Some("/VStack[1]/Image[1]"),
+            Some("/VStack[1]/Text[1]/text()[1]"),
+            Some("/VStack[1]/Spacer[1]"),
+        ]
+    );
+
+    // Edits which would break the tree are rejected, leaving the document as it was
+    let rendered = doc.render();
+    let rejected = [
+        doc.append_child(leaf.clone(), NodeData::new("Text")).err(),
+        doc.append_child(stack.clone(), NodeData::Root).err(),
+        doc.insert_before(text.clone(), NodeData::Root).err(),
+        doc.insert_after(text.clone(), NodeData::Root).err(),
+        doc.insert_before(root.clone(), NodeData::new("Text")).err(),
+        doc.set_text(text.clone(), "c".into()).err(),
+        doc.set_attribute(leaf.clone(), "id".into(), None).err(),
+        doc.remove(root.clone()).err(),
+        doc.remove(spacer.clone()).err(),
+    ];
+    for err in rejected {
+        assert!(
+            matches!(
+                err,
+                Some(PatchError::Invalid { .. } | PatchError::UnknownNode { .. })
+            ),
+            "{err:?}"
+        );
+    }
+    assert_eq!(doc.render(), rendered);
+    assert_eq!(doc.version(), 10);
+}
+
+#[test]
+fn ffi_inserted_ids() {
+    let doc = Document::parse(r#"<VStack id="stack"><Spacer/></VStack>"#.into()).unwrap();
+    let stack = doc.query("#stack".into()).unwrap().unwrap();
+    let spacer = doc.children(stack.clone())[0].clone();
+    let element = |name: &str, id: &str| {
+        let mut element = Element::new(name.into());
+        element.set_attribute("id".into(), Some(id.into()));
+        NodeData::from(element)
+    };
+
+    // Inserted elements are registered with their id, however they are inserted
+    let text = doc
+        .append_child(stack.clone(), element("Text", "text"))
+        .unwrap();
+    let image = doc
+        .insert_before(spacer.clone(), element("Image", "image"))
+        .unwrap();
+    let button = doc
+        .insert_after(spacer.clone(), element("Button", "button"))
+        .unwrap();
+    let inner = doc.inner();
+    assert_eq!(inner.get_by_id("text"), Some(*text));
+    assert_eq!(inner.get_by_id("image"), Some(*image));
+    assert_eq!(inner.get_by_id("button"), Some(*button));
+    assert_eq!(inner.get_by_id("stack"), Some(*stack));
+}
+
+fn ids(nodes: impl IntoIterator<Item = Arc<Node>>) -> Vec<String> {
+    nodes
+        .into_iter()
+        .map(|node| match node.data() {
+            NodeData::NodeElement { element } => element.id().unwrap_or(element.name.name),
+            NodeData::Leaf { value } => value,
+            NodeData::Root => "root".into(),
+        })
+        .collect()
+}
+
+fn collect(iter: Arc<NodeIterator>) -> Vec<Arc<Node>> {
+    std::iter::from_fn(|| iter.next()).collect()
+}
+
+#[test]
+fn ffi_node_navigation() {
+    let doc = Document::parse(
+        r#"<VStack id="stack"><Text id="a">one</Text><HStack id="b"><Text id="c">two</Text>three</HStack><Spacer id="d"/></VStack>"#.into(),
+    )
+    .unwrap();
+    let node = |id: &str| {
+        let node_ref = doc.query(format!("#{id}")).unwrap().unwrap();
+        doc.get_node(node_ref)
+    };
+    let stack = node("stack");
+    let b = node("b");
+    let c = node("c");
+
+    assert_eq!(ids(b.parent()), vec!["stack"]);
+    assert_eq!(ids(b.previous_sibling()), vec!["a"]);
+    assert_eq!(ids(b.next_sibling()), vec!["d"]);
+    assert_eq!(ids(node("a").previous_sibling()), Vec::<String>::new());
+    assert_eq!(ids(node("d").next_sibling()), Vec::<String>::new());
+    assert_eq!(b.index_in_parent(), Some(1));
+    assert_eq!(stack.parent().unwrap().index_in_parent(), None);
+    assert_eq!(c.depth(), 3);
+    assert_eq!(ids(c.ancestors()), vec!["b", "stack", "root"]);
+    assert_eq!(ids(collect(c.iter_ancestors())), vec!["b", "stack", "root"]);
+
+    assert_eq!(stack.text_content(), "onetwothree");
+    assert_eq!(node("a").text_content(), "one");
+    assert_eq!(b.inner_html(), r#"<Text id="c">two</Text>three"#);
+
+    assert_eq!(ids(collect(stack.iter_children())), vec!["a", "b", "d"]);
+    assert_eq!(
+        ids(collect(stack.iter_depth_first())),
+        vec!["a", "one", "b", "c", "two", "three", "d"]
+    );
+    assert_eq!(
+        ids(stack.get_depth_first_children()),
+        ids(collect(stack.iter_depth_first()))
+    );
+
+    // Iterators read the document as they go
+    let children = stack.iter_children();
+    assert_eq!(ids(children.next()), vec!["a"]);
+    doc.remove(Arc::new(b.id())).unwrap();
+    assert_eq!(ids(collect(children)), vec!["d"]);
+}
@@ -0,0 +1,202 @@
+use std::collections::BTreeSet;
+
+use cranelift_entity::PrimaryMap;
+use fxhash::{FxHashMap, FxHashSet};
+
+use super::{AttributeName, Document, ElementName, NodeData, NodeRef, Selector};
+
+/// Secondary indexes of the elements of a [`Document`], by tag name, attribute name and class
+///
+/// Every element of the document is indexed until it is deleted, whether or not it is attached, so
+/// selections filter the candidates they get from here down to those in the subtree being searched.
+#[derive(Debug, Clone, Default)]
+pub(super) struct Indexes {
+    tags: FxHashMap<ElementName, BTreeSet<NodeRef>>,
+    attributes: FxHashMap<AttributeName, BTreeSet<NodeRef>>,
+    classes: FxHashMap<String, BTreeSet<NodeRef>>,
+    /// A node handed out by [`Document::get_mut`], which is left out of the indexes until the next
+    /// call, as it may be changed in any way in between
+    pending: Option<NodeRef>,
+}
+impl Indexes {
+    /// Indexes every element of `doc`
+    pub fn build(doc: &Document) -> Self {
+        let mut indexes = Self::default();
+        for (node, data) in doc.nodes.iter() {
+            indexes.insert(node, data);
+        }
+        indexes
+    }
+
+    /// Adds `node` to the indexes, if it is an element
+    pub fn insert(&mut self, node: NodeRef, data: &NodeData) {
+        let NodeData::NodeElement { element } = data else {
+            return;
+        };
+        self.tags
+            .entry(element.name.clone())
+            .or_default()
+            .insert(node);
+        for attr in element.attributes.iter() {
+            self.attributes
+                .entry(attr.name.clone())
+                .or_default()
+                .insert(node);
+        }
+        for class in element.classes() {
+            self.classes
+                .entry(class.to_string())
+                .or_default()
+                .insert(node);
+        }
+    }
+
+    /// Removes `node` from the indexes, given the data it was indexed with
+    pub fn remove(&mut self, node: NodeRef, data: &NodeData) {
+        let NodeData::NodeElement { element } = data else {
+            return;
+        };
+        remove_from(&mut self.tags, &element.name, node);
+        for attr in element.attributes.iter() {
+            remove_from(&mut self.attributes, &attr.name, node);
+        }
+        for class in element.classes() {
+            remove_from(&mut self.classes, class, node);
+        }
+    }
+
+    /// Removes the deleted `node` from the indexes for good
+    pub fn delete(&mut self, node: NodeRef, data: &NodeData) {
+        // A node handed out by `get_mut` is already out of the indexes
+        if self.pending == Some(node) {
+            self.pending = None;
+        } else {
+            self.remove(node, data);
+        }
+    }
+
+    /// Re-indexes the node handed out by the previous call to [`Document::get_mut`], and takes `node`
+    /// out of the indexes in its place
+    pub fn checkout(&mut self, nodes: &PrimaryMap<NodeRef, NodeData>, node: NodeRef) {
+        if let Some(pending) = self.pending.take() {
+            self.insert(pending, &nodes[pending]);
+        }
+        self.remove(node, &nodes[node]);
+        self.pending = Some(node);
+    }
+
+    /// Returns every node which may match `selector`, or `None` if it can't be narrowed down using the indexes
+    pub fn candidates(&self, selector: &Selector) -> Option<BTreeSet<NodeRef>> {
+        let sets = self.plan(selector)?;
+        let mut candidates = BTreeSet::new();
+        for set in sets {
+            candidates.extend(set.iter().copied());
+        }
+        candidates.extend(self.pending);
+        Some(candidates)
+    }
+
+    /// Returns the index entries whose union contains every element matching `selector`, picking the
+    /// smallest entries for compound selectors
+    fn plan<'a>(&'a self, selector: &Selector) -> Option<Vec<&'a BTreeSet<NodeRef>>> {
+        let sets = match selector {
+            Selector::Tag(name) => self.tags.get(name).into_iter().collect(),
+            Selector::Class(class) => self.classes.get(class.as_ref()).into_iter().collect(),
+            Selector::AttributeValueWhitespacedContains(name, class)
+                if name.namespace.is_none() && name.name == "class" =>
+            {
+                self.classes.get(class.as_ref()).into_iter().collect()
+            }
+            Selector::Attribute(name)
+            | Selector::AttributeValue(name, _)
+            | Selector::AttributeValueWhitespacedContains(name, _)
+            | Selector::AttributeValueStartsWith(name, _)
+            | Selector::AttributeValueEndsWith(name, _)
+            | Selector::AttributeValueSubstring(name, _) => {
+                self.attributes.get(name).into_iter().collect()
+            }
+            Selector::And(l, r) => match (self.plan(l), self.plan(r)) {
+                (Some(l), Some(r)) => {
+                    let len = |sets: &Vec<&BTreeSet<NodeRef>>| -> usize {
+                        sets.iter().map(|set| set.len()).sum()
+                    };
+                    if len(&l) <= len(&r) {
+                        l
+                    } else {
+                        r
+                    }
+                }
+                (l, r) => l.or(r)?,
+            },
+            Selector::Or(l, r) => {
+                let mut sets = self.plan(l)?;
+                sets.extend(self.plan(r)?);
+                sets
+            }
+            Selector::Descendant(_, selector)
+            | Selector::Child(_, selector)
+            | Selector::Adjacent(_, selector)
+            | Selector::Sibling(_, selector) => self.plan(selector)?,
+            _ => return None,
+        };
+        Some(sets)
+    }
+}
+
+fn remove_from<K, Q>(index: &mut FxHashMap<K, BTreeSet<NodeRef>>, key: &Q, node: NodeRef)
+where
+    K: std::borrow::Borrow<Q> + std::hash::Hash + Eq,
+    Q: std::hash::Hash + Eq + ?Sized,
+{
+    if let Some(nodes) = index.get_mut(key) {
+        nodes.remove(&node);
+        if nodes.is_empty() {
+            index.remove(key);
+        }
+    }
+}
+
+/// Returns the candidates which are `start` or one of its descendants, in the order a depth-first
+/// traversal from `start` visits them
+pub(super) fn in_traversal_order(
+    doc: &Document,
+    start: NodeRef,
+    candidates: BTreeSet<NodeRef>,
+) -> Vec<NodeRef> {
+    // Connect each candidate to `start` through its ancestors, remembering for every node on the way
+    // whether it is under `start`, so ancestors shared by several candidates are only visited once
+    let mut under_start = FxHashMap::default();
+    under_start.insert(start, true);
+    let mut selected = FxHashSet::default();
+    let mut chain = vec![];
+    for candidate in candidates {
+        let mut node = candidate;
+        let found = loop {
+            if let Some(found) = under_start.get(&node) {
+                break *found;
+            }
+            chain.push(node);
+            match doc.parent(node) {
+                Some(parent) => node = parent,
+                None => break false,
+            }
+        };
+        under_start.extend(chain.drain(..).map(|node| (node, found)));
+        if found {
+            selected.insert(candidate);
+        }
+    }
+
+    // Then traverse just the part of the tree spanning the candidates; like the full traversal, this
+    // visits the children of a node from last to first
+    let mut ordered = Vec::with_capacity(selected.len());
+    let mut stack = vec![start];
+    while let Some(node) = stack.pop() {
+        if selected.contains(&node) {
+            ordered.push(node);
+        }
+        let children = doc.children(node).iter().copied();
+        stack.extend(children.filter(|child| under_start.get(child) == Some(&true)));
+    }
+    ordered
+}
@@ -1,39 +1,64 @@
 mod attribute;
+mod binary;
+mod changes;
+pub(crate) mod failpoint;
 mod ffi;
+mod index;
 mod node;
+mod path;
 mod printer;
 mod select;
+mod subscriptions;
 
 use std::{
-    collections::{BTreeMap, VecDeque},
+    collections::{BTreeMap, BTreeSet, VecDeque},
     fmt, mem,
     ops::{Deref, DerefMut},
     path::Path,
     sync::Arc,
 };
 
-use cranelift_entity::{packed_option::PackedOption, EntityRef, PrimaryMap, SecondaryMap};
+use cranelift_entity::{
+    packed_option::PackedOption, EntityRef, EntitySet, PrimaryMap, SecondaryMap,
+};
 use fixedbitset::FixedBitSet;
 use fxhash::{FxBuildHasher, FxHashMap};
 use petgraph::Direction;
 use smallstr::SmallString;
 use smallvec::SmallVec;
 
-use self::printer::Printer;
 pub use self::{
-    attribute::{Attribute, AttributeName, AttributeValue},
+    attribute::{Attribute, AttributeChange, AttributeName, AttributeValue},
+    binary::{DecodeError, BINARY_VERSION},
+    changes::{ChangeSet, NodeChange},
     node::{Element, ElementName, NodeData, NodeRef},
+    path::{NodePath, PathError, PathStep},
     printer::PrintOptions,
-    select::{SelectionIter, Selector},
+    select::{SelectionIter, Selector, SelectorError},
+    subscriptions::{MatchChangeHandler, MatchChangeType},
+};
+use self::{
+    changes::{ChangeRecorder, History, Touched},
+    failpoint::{failpoint, Stage},
+    index::Indexes,
+    printer::Printer,
+    subscriptions::Subscriptions,
 };
 use crate::{
     diff::{
-        fragment::{FragmentMerge, RenderError, Root, RootDiff},
-        PatchResult,
+        fragment::{RenderError, RenderOptions, Root, RootChanges, RootDiff, PHX_COMPONENT},
+        IdKey, NodeKey, Patch, PatchError, PatchResult, UndoLog,
     },
     parser,
 };
 
+/// How a document is rendered from its fragment template
+///
+/// Components are tagged with their CID, so that a changed component can be found and patched on its own.
+const FRAGMENT_RENDER: RenderOptions = RenderOptions {
+    component_ids: true,
+};
+
 /// A `Document` represents a virtual DOM, and supports common operations typically performed against them.
 ///
 /// While I'm referring to it as a DOM because it conjures the familiar notion of an HTML document, what we're
@@ -71,8 +96,13 @@ pub struct Document {
     /// The fragment template.
     pub fragment_template: Option<Root>,
     pub event_callback: Option<Arc<dyn DocumentChangeHandler>>,
+    pub component_callback: Option<Arc<dyn ComponentChangeHandler>>,
+    /// How elements are matched up when patching this document to a newly rendered one, `id` by default
+    pub node_key: Option<Arc<dyn NodeKey>>,
     /// A map from node reference to node data
     nodes: PrimaryMap<NodeRef, NodeData>,
+    /// Nodes which have been deleted, whose data is kept in `nodes` but which can no longer be used
+    deleted: EntitySet<NodeRef>,
     /// A map from a node to its parent node, if it currently has one
     parents: SecondaryMap<NodeRef, PackedOption<NodeRef>>,
     /// A map from a node to its child nodes
@@ -82,6 +112,14 @@ pub struct Document {
     /// This allows for looking up a node directly and modifying it, rather than needing to traverse the
     /// document.
     ids: BTreeMap<SmallString<[u8; 16]>, NodeRef>,
+    /// Secondary indexes used to speed up selections, see [`Document::enable_indexes`]
+    indexes: Option<Box<Indexes>>,
+    /// Selectors whose matches are reported as they change, see [`Document::subscribe`]
+    subscriptions: Subscriptions,
+    /// The current version of this document, see [`Document::version`]
+    version: u64,
+    /// The version in which each node was last changed, see [`Document::changed_since`]
+    history: History,
 }
 impl fmt::Debug for Document {
     #[inline]
@@ -125,11 +163,18 @@ impl Document {
         Self {
             root,
             nodes,
+            deleted: EntitySet::new(),
             parents: SecondaryMap::new(),
             children: SecondaryMap::new(),
             ids: Default::default(),
+            indexes: None,
+            subscriptions: Default::default(),
+            version: 0,
+            history: History::default(),
             fragment_template: None,
             event_callback: None,
+            component_callback: None,
+            node_key: None,
         }
     }
 
@@ -149,6 +194,8 @@ impl Document {
     }
 
     /// Obtains a `DocumentBuilder` with which you can extend/modify this document
+    ///
+    /// Changes made this way don't bump the version, see [`Document::version`].
     #[inline]
     pub fn edit(&mut self) -> Editor<'_> {
         Editor::new(self)
@@ -158,9 +205,17 @@ impl Document {
     pub fn clear(&mut self) {
         self.nodes.clear();
         self.root = self.nodes.push(NodeData::Root);
+        self.deleted.clear();
         self.parents.clear();
         self.children.clear();
         self.ids.clear();
+        if let Some(indexes) = self.indexes.as_deref_mut() {
+            *indexes = Indexes::default();
+        }
+        self.version += 1;
+        self.history.clear();
+        self.history
+            .record(self.version, vec![self.root], self.nodes.len());
     }
 
     /// Returns true if this document is empty (contains no nodes)
@@ -168,6 +223,13 @@ impl Document {
         self.children[self.root].is_empty()
     }
 
+    /// Returns true if `node` refers to a node of this document, whether or not it is attached
+    ///
+    /// Deleted nodes are no longer part of the document, even though their `NodeRef`s aren't reused.
+    pub fn contains(&self, node: NodeRef) -> bool {
+        self.nodes.is_valid(node) && !self.deleted.contains(node)
+    }
+
     /// Returns the root node of the document
     ///
     /// The root node can be used in insertion operations, but can not have attributes applied to it
@@ -193,11 +255,134 @@ impl Document {
     }
 
     /// Returns the data associated with the given `NodeRef`, mutably
+    ///
+    /// When indexes are enabled, `node` is re-indexed by the next call to this function, and is considered
+    /// by every selection until then. Changes made this way don't bump the version, see [`Document::version`].
     #[inline]
     pub fn get_mut(&mut self, node: NodeRef) -> &mut NodeData {
+        if let Some(indexes) = self.indexes.as_deref_mut() {
+            indexes.checkout(&self.nodes, node);
+        }
         &mut self.nodes[node]
     }
 
+    /// Builds indexes of the elements of this document by tag name, attribute name and class
+    ///
+    /// The indexes are kept up to date as the document is changed, and let [`Document::select`] visit only
+    /// the elements which may match, rather than the whole document. This speeds up selections on large
+    /// documents, at the cost of slowing down changes.
+    pub fn enable_indexes(&mut self) {
+        if self.indexes.is_none() {
+            self.indexes = Some(Box::new(Indexes::build(self)));
+        }
+    }
+
+    /// Drops the indexes built by [`Document::enable_indexes`]
+    pub fn disable_indexes(&mut self) {
+        self.indexes = None;
+    }
+
+    /// Returns true if this document has indexes, see [`Document::enable_indexes`]
+    pub fn has_indexes(&self) -> bool {
+        self.indexes.is_some()
+    }
+
+    /// Reports changes to the nodes matching `selector` to `handler`, returning an id for the subscription
+    ///
+    /// Nodes which already match aren't reported. From then on, whenever a merge or a list of patches is
+    /// applied, `handler` is notified of each node which started or stopped matching, or which still matches
+    /// but was changed. Only the nodes near those touched by the patches are matched again, rather than the
+    /// whole document. Changes made through [`Document::edit`] aren't tracked.
+    pub fn subscribe(
+        &mut self,
+        selector: &str,
+        handler: Arc<dyn MatchChangeHandler>,
+    ) -> Result<u64, SelectorError> {
+        let mut subscriptions = mem::take(&mut self.subscriptions);
+        let id = subscriptions.subscribe(self, selector, handler);
+        self.subscriptions = subscriptions;
+        id
+    }
+
+    /// Removes the subscription with `id`, returning false if there is none
+    pub fn unsubscribe(&mut self, id: u64) -> bool {
+        self.subscriptions.unsubscribe(id)
+    }
+
+    /// Returns the version of this document
+    ///
+    /// The version starts at 0, and is bumped by every merge or list of patches which changes the document,
+    /// so a host can tell whether what it last read is stale. Changes made through [`Document::edit`] or
+    /// [`Document::get_mut`] aren't tracked, so apply a [`Patch`] for changes the host needs to see.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Returns the version in which `node` was last changed, or 0 if it hasn't been since the document was loaded
+    ///
+    /// A node is changed when it is added, moved, or its data is changed, and its parent is changed when it
+    /// gains or loses a child.
+    pub fn modified_version(&self, node: NodeRef) -> u64 {
+        self.history.modified(node)
+    }
+
+    /// Returns the nodes in the tree which have been changed since `version`, in no particular order
+    ///
+    /// Removed nodes aren't listed, but the parents they were removed from are. This takes time proportional
+    /// to the number of nodes changed since `version`, not to the size of the document.
+    ///
+    /// Like [`Document::version`], this only covers merges and patches: changes made through
+    /// [`Document::edit`] or [`Document::get_mut`] don't bump the version, and aren't listed.
+    pub fn changed_since(&self, version: u64) -> Vec<NodeRef> {
+        self.history
+            .since(version)
+            .filter(|node| changes::is_attached(self, *node))
+            .collect()
+    }
+
+    /// Bumps the version of this document, if the changes in `recorder` touched any nodes, stamping them
+    /// with it and notifying subscriptions of them
+    fn commit(&mut self, recorder: &ChangeRecorder) {
+        let touched = recorder.touched(self);
+        if touched.is_empty() {
+            return;
+        }
+        self.version += 1;
+        let mut nodes = vec![];
+        for node in touched.attached.iter() {
+            nodes.extend(changes::subtree(self, *node));
+        }
+        nodes.extend(touched.changed.iter().chain(touched.parents.iter()));
+        self.history.record(self.version, nodes, self.nodes.len());
+        self.notify_subscriptions(&touched);
+    }
+
+    /// Notifies subscriptions of the changes to the `touched` nodes
+    fn notify_subscriptions(&mut self, touched: &Touched) {
+        if self.subscriptions.is_empty() {
+            return;
+        }
+        let changes = self.subscriptions.evaluate(self, touched);
+        self.subscriptions.update(&changes);
+        for change in changes {
+            let data = self.get(change.node).clone();
+            change
+                .handler
+                .handle(change.change_type, change.node.into(), data);
+        }
+    }
+
+    /// Updates the indexes after the element `node` was changed by `f`
+    fn reindex<T>(&mut self, node: NodeRef, f: impl FnOnce(&mut NodeData) -> T) -> T {
+        let Some(indexes) = self.indexes.as_deref_mut() else {
+            return f(&mut self.nodes[node]);
+        };
+        indexes.remove(node, &self.nodes[node]);
+        let result = f(&mut self.nodes[node]);
+        indexes.insert(node, &self.nodes[node]);
+        result
+    }
+
     /// Returns the set of attribute refs associated with `node`
     pub fn attributes(&self, node: NodeRef) -> Vec<Attribute> {
         match &self.nodes[node] {
@@ -239,6 +424,18 @@ impl Document {
         self.ids.get(id.as_ref()).copied()
     }
 
+    /// Returns the path from the root to `node`, or `None` if it is not part of the tree
+    ///
+    /// Unlike `node` itself, the path identifies the same node in any copy of this document, see [`NodePath`].
+    pub fn path_of(&self, node: NodeRef) -> Option<NodePath> {
+        NodePath::of(self, node)
+    }
+
+    /// Returns the node at `path`, if there is one
+    pub fn resolve_path(&self, path: &NodePath) -> Option<NodeRef> {
+        path.resolve(self)
+    }
+
     /// Returns an iterator over all nodes in this document which match `selector`
     ///
     /// The nodes are visited in depth-first order, and the iterator terminates as soon as the selection is considered fully matched
@@ -273,11 +470,11 @@ impl Document {
             match v {
                 NodeData::Root => continue,
                 v @ NodeData::Leaf { value: _ } => {
-                    let new_k = self.nodes.push(v);
+                    let new_k = self.push_node(v);
                     node_mapping.insert(k, new_k);
                 }
                 NodeData::NodeElement { element: elem } => {
-                    let new_k = self.nodes.push(NodeData::NodeElement { element: elem });
+                    let new_k = self.push_node(NodeData::NodeElement { element: elem });
                     node_mapping.insert(k, new_k);
                 }
             }
@@ -398,8 +595,17 @@ impl Document {
         stack.push_back(node);
 
         while let Some(node) = stack.pop_front() {
-            // Detach node from its parent
+            // Detach node from its parent, and forget its id
             self.detach(node);
+            self.deleted.insert(node);
+            if let Some(indexes) = self.indexes.as_deref_mut() {
+                indexes.delete(node, &self.nodes[node]);
+            }
+            if let Some(id) = self.nodes[node].id() {
+                if self.ids.get(id.as_str()) == Some(&node) {
+                    self.ids.remove(id.as_str());
+                }
+            }
 
             // Remove the children from the document and add them to the stack to be visited
             //
@@ -422,10 +628,18 @@ impl Document {
 
     /// Adds a node to this document, returning the corresponding NodeRef.
     ///
-    /// This operation adds `node` to the document without inserting it in the tree, i.e. it is initially detached
+    /// This operation adds `node` to the document without inserting it in the tree, i.e. it is initially detached.
+    /// If `node` is an element with an `id`, it is registered with that id, see [`Document::get_by_id`].
     #[inline]
     pub fn push_node<N: Into<NodeData>>(&mut self, node: N) -> NodeRef {
-        self.nodes.push(node.into())
+        let node = self.nodes.push(node.into());
+        if let Some(indexes) = self.indexes.as_deref_mut() {
+            indexes.insert(node, &self.nodes[node]);
+        }
+        if let Some(id) = self.nodes[node].id() {
+            self.ids.insert(id.into(), node);
+        }
+        node
     }
 
     /// Sets the attribute `name` on `node` with `value`.
@@ -437,28 +651,32 @@ impl Document {
         name: K,
         value: V,
     ) -> bool {
-        if let NodeData::NodeElement {
-            element: ref mut elem,
-        } = &mut self.nodes[node]
-        {
-            let name = name.into();
-            let value = value.into();
-            elem.set_attribute(name, value);
-            true
-        } else {
-            false
-        }
+        self.reindex(node, |data| {
+            if let NodeData::NodeElement {
+                element: ref mut elem,
+            } = data
+            {
+                let name = name.into();
+                let value = value.into();
+                elem.set_attribute(name, value);
+                true
+            } else {
+                false
+            }
+        })
     }
 
     /// Removes the attribute `name` from `node`.
     pub fn remove_attribute<K: Into<AttributeName>>(&mut self, node: NodeRef, name: K) {
-        if let NodeData::NodeElement {
-            element: ref mut elem,
-        } = &mut self.nodes[node]
-        {
-            let name = name.into();
-            elem.remove_attribute(&name);
-        }
+        self.reindex(node, |data| {
+            if let NodeData::NodeElement {
+                element: ref mut elem,
+            } = data
+            {
+                let name = name.into();
+                elem.remove_attribute(&name);
+            }
+        })
     }
 
     /// If node is an element, replace attributes and return previous
@@ -467,14 +685,16 @@ impl Document {
         node: NodeRef,
         attributes: Vec<Attribute>,
     ) -> Option<Vec<Attribute>> {
-        if let NodeData::NodeElement {
-            element: ref mut elem,
-        } = &mut self.nodes[node]
-        {
-            Some(mem::replace(&mut elem.attributes, attributes))
-        } else {
-            None
-        }
+        self.reindex(node, |data| {
+            if let NodeData::NodeElement {
+                element: ref mut elem,
+            } = data
+            {
+                Some(mem::replace(&mut elem.attributes, attributes))
+            } else {
+                None
+            }
+        })
     }
 
     /// Removes all attributes from `node` for which `predicate` returns false.
@@ -482,12 +702,14 @@ impl Document {
     where
         P: FnMut(&Attribute) -> bool,
     {
-        if let NodeData::NodeElement {
-            element: ref mut elem,
-        } = &mut self.nodes[node]
-        {
-            elem.attributes.retain(predicate);
-        }
+        self.reindex(node, |data| {
+            if let NodeData::NodeElement {
+                element: ref mut elem,
+            } = data
+            {
+                elem.attributes.retain(predicate);
+            }
+        })
     }
 
     /// Prints this document using the given writer and options
@@ -507,64 +729,388 @@ impl Document {
     }
 
     /// Parses a `RootDiff` and returns a `Document`
+    ///
+    /// The root element of each component is tagged with its CID in a `data-phx-component` attribute, like
+    /// the JS client does, see [`Document::component_nodes`].
     pub fn parse_fragment_json(input: String) -> Result<Self, RenderError> {
         let fragment: RootDiff = serde_json::from_str(&input).map_err(RenderError::from)?;
         let root: Root = fragment.try_into()?;
-        let rendered: String = root.clone().try_into()?;
-        let mut document = crate::parser::parse(&rendered)?;
+        let mut document = root.render_document(FRAGMENT_RENDER)?;
         document.fragment_template = Some(root);
         Ok(document)
     }
 
-    pub fn merge_fragment_json(&mut self, json: String) -> Result<(), RenderError> {
+    /// Merges a `RootDiff` into the fragment template of this document, and patches the document to match
+    ///
+    /// Returns every change made to the document, so it can be applied by the host as one transaction. The
+    /// event handler, if set, is still notified of each change as it is made.
+    ///
+    /// If the diff can't be decoded, merged, rendered or applied, the error is returned and both the document
+    /// and its fragment template are left as they were. Should that happen after the document was patched,
+    /// the patches are undone, and the event handler is notified of the changes which undo them. Should
+    /// undoing them fail as well, which means the undo log is out of step with the document, the error is
+    /// returned as [`RenderError::UndoFailed`], and the document is left partially patched.
+    pub fn merge_fragment_json(&mut self, json: String) -> Result<ChangeSet, RenderError> {
         let fragment: RootDiff = serde_json::from_str(&json).map_err(RenderError::from)?;
+        failpoint(Stage::Decode)?;
 
-        let root = if let Some(root) = &self.fragment_template {
-            root.clone().merge(fragment)?
+        let (root, changes) = if let Some(root) = &self.fragment_template {
+            root.clone().merge_with_changes(fragment)?
         } else {
-            fragment.try_into()?
+            let root: Root = fragment.try_into()?;
+            let changes = RootChanges {
+                fragment_changed: true,
+                ..Default::default()
+            };
+            (root, changes)
         };
-        self.fragment_template = Some(root.clone());
+        failpoint(Stage::Merge)?;
+
+        // The template is only replaced once the document has been patched to match it, and if
+        // patching fails partway, the patches applied so far are undone
+        let mut recorder = ChangeRecorder::default();
+        let mut undo = UndoLog::default();
+        let len = self.nodes.len();
+        if let Err(error) = self.merge_root(&root, &changes, &mut recorder, &mut undo) {
+            if let Err(undo) = self.roll_back(undo, len) {
+                return Err(RenderError::UndoFailed {
+                    error: Box::new(error),
+                    undo,
+                });
+            }
+            return Err(error);
+        }
 
-        let rendered_root: String = root.clone().try_into()?;
-        let new_doc = Self::parse(rendered_root)?;
+        // Components which are no longer rendered stay in the template until they are destroyed, as
+        // the next diff may render them again, see `Document::destroy_components`
+        self.fragment_template = Some(root);
 
-        let patches = crate::diff::diff(self, &new_doc);
-        if patches.is_empty() {
+        if let Some(handler) = self.component_callback.clone() {
+            let nodes = self.component_nodes();
+            for cid in changes.components.iter() {
+                if let Some(node) = nodes.get(cid) {
+                    handler.handle(ComponentChangeType::Change, *cid, Some((*node).into()));
+                }
+            }
+        }
+        self.commit(&recorder);
+        let changes = recorder.finish(self);
+        self.forget(&undo);
+        Ok(changes)
+    }
+
+    /// Returns the CIDs of the components in the fragment template which are no longer rendered
+    ///
+    /// These are the components to send to the server in `cids_will_destroy`, see [`Document::destroy_components`].
+    pub fn orphaned_components(&self) -> Vec<i32> {
+        self.fragment_template
+            .as_ref()
+            .map(|root| root.orphaned_components().into_iter().collect())
+            .unwrap_or_default()
+    }
+
+    /// Drops the components with `cids` from the fragment template, once the server acknowledged their
+    /// destruction with `cids_destroyed`, returning the CIDs which were dropped
+    ///
+    /// A component which was rendered again by a diff merged since it was orphaned is kept, as the JS
+    /// client does. The component handler is notified with [`ComponentChangeType::Destroy`] of each
+    /// component which was dropped.
+    pub fn destroy_components(&mut self, cids: Vec<i32>) -> Vec<i32> {
+        let Some(root) = self.fragment_template.as_mut() else {
+            return vec![];
+        };
+        let orphaned = root.orphaned_components();
+        let destroyed = cids
+            .into_iter()
+            .filter(|cid| orphaned.contains(cid))
+            .collect::<BTreeSet<_>>();
+        root.prune_components(destroyed.iter().copied());
+        if let Some(handler) = self.component_callback.clone() {
+            for cid in destroyed.iter() {
+                handler.handle(ComponentChangeType::Destroy, *cid, None);
+            }
+        }
+        destroyed.into_iter().collect()
+    }
+
+    /// Patches this document to match `root`, recording the inverse of every patch applied in `undo`
+    fn merge_root(
+        &mut self,
+        root: &Root,
+        changes: &RootChanges,
+        recorder: &mut ChangeRecorder,
+        undo: &mut UndoLog,
+    ) -> Result<(), RenderError> {
+        // When only components changed, re-render and patch just the subtrees of those components
+        if !changes.fragment_changed && self.merge_components(root, changes, recorder, undo)? {
             return Ok(());
         }
+        let new_doc = root.render_document(FRAGMENT_RENDER)?;
+        failpoint(Stage::Render)?;
+
+        let patches = match self.node_key.clone() {
+            Some(keys) => crate::diff::diff_with_keys(self, &new_doc, keys.as_ref()),
+            None => crate::diff::diff(self, &new_doc),
+        };
+        self.apply_patches(patches, recorder, Some(undo))?;
+        Ok(())
+    }
+
+    /// Returns the root node of every rendered component in this document, keyed by CID
+    pub fn component_nodes(&self) -> BTreeMap<i32, NodeRef> {
+        let selector = Selector::Attribute(AttributeName::new(PHX_COMPONENT));
+        let mut nodes = BTreeMap::new();
+        for node in self.select(selector) {
+            let cid = self
+                .get_attribute_by_name(node, PHX_COMPONENT)
+                .and_then(|attr| attr.value.as_deref()?.parse::<i32>().ok());
+            if let Some(cid) = cid {
+                nodes.entry(cid).or_insert(node);
+            }
+        }
+        nodes
+    }
+
+    /// Re-renders each changed component of `root` and morphs the corresponding subtree of this document.
+    ///
+    /// Returns `false` without modifying the document if any component can't be patched in isolation,
+    /// in which case the whole document must be re-rendered instead.
+    fn merge_components(
+        &mut self,
+        root: &Root,
+        changes: &RootChanges,
+        recorder: &mut ChangeRecorder,
+        undo: &mut UndoLog,
+    ) -> Result<bool, RenderError> {
+        let nodes = self.component_nodes();
+        let mut targets = Vec::with_capacity(changes.components.len());
+        for cid in changes.components.iter() {
+            // Components which aren't currently rendered are either unused, or are new and
+            // rendered as part of another changed component
+            let Some(node) = nodes.get(cid).copied() else {
+                continue;
+            };
+            let new_doc = root.render_component_document(*cid, FRAGMENT_RENDER)?;
+            failpoint(Stage::Render)?;
+            let new_node = match new_doc.children(new_doc.root()) {
+                [new_node] => *new_node,
+                _ => return Ok(false),
+            };
+            let keys = self.node_key.clone().unwrap_or_else(|| Arc::new(IdKey));
+            let compatible = match (self.get(node), new_doc.get(new_node)) {
+                (
+                    NodeData::NodeElement { element: from },
+                    NodeData::NodeElement { element: to },
+                ) => from.name == to.name && keys.key(from) == keys.key(to),
+                _ => false,
+            };
+            if !compatible {
+                return Ok(false);
+            }
+            targets.push((node, new_doc, new_node));
+        }
+
+        for (node, new_doc, new_node) in targets.into_iter() {
+            // A component nested in another changed component may have been replaced already
+            if self.parent(node).is_none() {
+                continue;
+            }
+            let patches = match self.node_key.clone() {
+                Some(keys) => crate::diff::diff_subtree_with_keys(
+                    self,
+                    node,
+                    &new_doc,
+                    new_node,
+                    keys.as_ref(),
+                ),
+                None => crate::diff::diff_subtree(self, node, &new_doc, new_node),
+            };
+            self.apply_patches(patches, recorder, Some(undo))?;
+        }
+        Ok(true)
+    }
+
+    /// Applies `patches` computed against a copy of this document, such as by another process
+    ///
+    /// Each patch is checked before it is applied, so a list which refers to nodes this document doesn't
+    /// have, or which doesn't fit its structure, is rejected. The patches applied before the rejected one are
+    /// undone, and the event handler is notified of the changes which undo them, so the document is left as
+    /// it was.
+    pub fn try_apply_patches(&mut self, patches: Vec<Patch>) -> Result<ChangeSet, PatchError> {
+        let (changes, undo) = self.apply_patches_atomically(patches)?;
+        self.forget(&undo);
+        Ok(changes)
+    }
+
+    /// Applies a single `patch`, such as an edit made by the host
+    ///
+    /// The patch is checked before it is applied, so if it is rejected the document is left as it was, without
+    /// keeping the undo log which [`Document::try_apply_patches`] needs to roll back longer lists.
+    ///
+    /// Along with the changes, returns the result of the patch, if it produces one, which e.g. identifies the
+    /// node it added.
+    pub fn try_apply_patch(
+        &mut self,
+        patch: Patch,
+    ) -> Result<(ChangeSet, Option<PatchResult>), PatchError> {
+        let mut recorder = ChangeRecorder::default();
+        let result = self.apply_patches(vec![patch], &mut recorder, None)?;
+        self.commit(&recorder);
+        Ok((recorder.finish(self), result))
+    }
+
+    /// Like [`Document::try_apply_patches`], but also returns the patches which undo the changes
+    ///
+    /// Applying the returned patches restores the document, down to its `NodeRef`s, as long as it hasn't
+    /// been changed in between. This allows a change to be shown optimistically, and reverted if the server
+    /// disagrees.
+    pub fn try_apply_patches_with_undo(
+        &mut self,
+        patches: Vec<Patch>,
+    ) -> Result<(ChangeSet, Vec<Patch>), PatchError> {
+        let (changes, undo) = self.apply_patches_atomically(patches)?;
+        Ok((changes, undo.into_patches()))
+    }
+
+    /// Applies all of `patches`, or if one of them is rejected, none of them
+    fn apply_patches_atomically(
+        &mut self,
+        patches: Vec<Patch>,
+    ) -> Result<(ChangeSet, UndoLog), PatchError> {
+        let mut recorder = ChangeRecorder::default();
+        let mut undo = UndoLog::default();
+        let len = self.nodes.len();
+        if let Err(error) = self.apply_patches(patches, &mut recorder, Some(&mut undo)) {
+            self.roll_back(undo, len)?;
+            return Err(error);
+        }
+        self.commit(&recorder);
+        Ok((recorder.finish(self), undo))
+    }
+
+    /// Undoes the patches recorded in `undo`, and deletes the nodes created since the document had `len` nodes
+    fn roll_back(&mut self, undo: UndoLog, len: usize) -> Result<(), PatchError> {
+        self.apply_patches(undo.into_patches(), &mut ChangeRecorder::default(), None)?;
+        for index in len..self.nodes.len() {
+            self.delete(NodeRef::new(index));
+        }
+        Ok(())
+    }
+
+    /// Deletes the nodes removed by the patches recorded in `undo`, once they are not going to be undone
+    ///
+    /// Patches applied with an undo log only detach the nodes they remove, so that they can be restored.
+    fn forget(&mut self, undo: &UndoLog) {
+        for node in undo.removed().iter().copied() {
+            // The node may have been attached again by a later patch
+            if self.parent(node).is_none() {
+                self.delete(node);
+            }
+        }
+    }
+
+    /// Applies `patches` to this document, notifying the event handler of every change
+    ///
+    /// If `undo` is given, the patches which undo the changes are recorded in it. Each patch is checked
+    /// before it is applied, and the first invalid patch stops application, leaving the patches before
+    /// it applied.
+    ///
+    /// Returns the result of the last patch which produced one.
+    fn apply_patches(
+        &mut self,
+        patches: Vec<Patch>,
+        recorder: &mut ChangeRecorder,
+        mut undo: Option<&mut UndoLog>,
+    ) -> Result<Option<PatchResult>, PatchError> {
+        let mut last = None;
+        if patches.is_empty() {
+            return Ok(last);
+        }
         let handler = self.event_callback.clone();
 
         let mut stack = vec![];
         let mut editor = self.edit();
-        for patch in patches.into_iter() {
-            let patch_result = patch.apply(&mut editor, &mut stack);
-            match patch_result {
-                None => (),
-                Some(PatchResult::Add { node, parent, data }) => {
-                    if let Some(ref handler) = handler {
-                        handler.handle(ChangeType::Add, node.into(), data, Some(parent.into()));
-                    }
-                }
-                Some(PatchResult::Remove { node, parent, data }) => {
-                    if let Some(ref handler) = handler {
-                        handler.handle(ChangeType::Remove, node.into(), data, Some(parent.into()));
-                    }
+        for (index, patch) in patches.into_iter().enumerate() {
+            let checked = patch
+                .check(index, &editor, &stack)
+                .and_then(|_| failpoint::patch(index));
+            if let Err(err) = checked {
+                editor.finish();
+                return Err(err);
+            }
+            recorder.before(editor.document(), &patch);
+            // A removed node is no longer in the tree afterwards, so its path is found beforehand
+            let removed_path = match (&handler, &patch) {
+                (Some(_), Patch::Remove { node }) => editor.document().path_of(*node),
+                _ => None,
+            };
+            let patch_result = match undo.as_deref_mut() {
+                Some(undo) => patch.apply_reversible(&mut editor, &mut stack, undo),
+                None => patch.apply(&mut editor, &mut stack),
+            };
+            let Some(patch_result) = patch_result.map(|result| recorder.record(result)) else {
+                continue;
+            };
+            let patch_result = last.insert(patch_result);
+            let Some(ref handler) = handler else {
+                continue;
+            };
+            let d = editor.document();
+            let (change_type, node, data, parent, attributes) = match patch_result {
+                PatchResult::Add { node, parent, data } => {
+                    (ChangeType::Add, *node, data.clone(), Some(*parent), vec![])
                 }
-                Some(PatchResult::Change { node, data }) => {
-                    if let Some(ref handler) = handler {
-                        handler.handle(ChangeType::Change, node.into(), data, None);
-                    }
+                PatchResult::Remove { node, parent, data } => (
+                    ChangeType::Remove,
+                    *node,
+                    data.clone(),
+                    Some(*parent),
+                    vec![],
+                ),
+                PatchResult::Change {
+                    node,
+                    data,
+                    attributes,
+                } => (
+                    ChangeType::Change,
+                    *node,
+                    data.clone(),
+                    None,
+                    attributes.clone(),
+                ),
+                PatchResult::Replace { node, parent, data } => (
+                    ChangeType::Replace,
+                    *node,
+                    data.clone(),
+                    Some(*parent),
+                    vec![],
+                ),
+                PatchResult::UpdateText { node, parent, .. } => {
+                    let data = d.get(*node).clone();
+                    (ChangeType::UpdateText, *node, data, Some(*parent), vec![])
                 }
-                Some(PatchResult::Replace { node, parent, data }) => {
-                    if let Some(ref handler) = handler {
-                        handler.handle(ChangeType::Replace, node.into(), data, Some(parent.into()));
-                    }
+                PatchResult::Move {
+                    node, new_parent, ..
+                } => {
+                    let data = d.get(*node).clone();
+                    (ChangeType::Move, *node, data, Some(*new_parent), vec![])
                 }
-            }
+            };
+            let path = match change_type {
+                ChangeType::Remove => removed_path,
+                _ => d.path_of(node),
+            };
+            handler.handle(
+                change_type,
+                node.into(),
+                data,
+                parent.map(Into::into),
+                attributes,
+                path.map(|path| path.to_string()),
+            );
         }
         editor.finish();
-        Ok(())
+        Ok(last)
     }
 }
 
@@ -575,6 +1121,10 @@ pub enum ChangeType {
     Add = 1,
     Remove = 2,
     Replace = 3,
+    /// The node was moved, the parent is its new parent
+    Move = 4,
+    /// The text of a leaf node changed in place, the data is the new text
+    UpdateText = 5,
 }
 
 #[derive(Copy, Clone, uniffi::Enum)]
@@ -584,15 +1134,38 @@ pub enum EventType {
 
 #[uniffi::export(callback_interface)]
 pub trait DocumentChangeHandler: Send + Sync {
+    /// Called for each change made to the document, as it is made
+    ///
+    /// For a [`ChangeType::Change`], `attributes` lists each attribute which changed, with its old and new
+    /// value; for other types of change it is empty. `path` is the path of the node after the change, or for
+    /// a removed node, before it, see [`Document::path_of`].
     fn handle(
         &self,
         change_type: ChangeType,
         node_ref: Arc<NodeRef>,
         node_data: NodeData,
         parent: Option<Arc<NodeRef>>,
+        attributes: Vec<AttributeChange>,
+        path: Option<String>,
     );
 }
 
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, uniffi::Enum)]
+pub enum ComponentChangeType {
+    /// The component was re-rendered, `node_ref` is the root element of the component
+    Change = 0,
+    /// The component is no longer rendered and was destroyed, `node_ref` is `None`, see
+    /// [`Document::destroy_components`]
+    Destroy = 1,
+}
+
+/// Receives a notification for every component affected by a merge, so views can re-render per component
+#[uniffi::export(callback_interface)]
+pub trait ComponentChangeHandler: Send + Sync {
+    fn handle(&self, change_type: ComponentChangeType, cid: i32, node_ref: Option<Arc<NodeRef>>);
+}
+
 /// This trait is used to provide functionality common to construction/mutating documents
 pub trait DocumentBuilder {
     fn document(&self) -> &Document;
@@ -745,7 +1318,7 @@ pub trait DocumentBuilder {
     /// Appends `node` as a child of `to`
     fn append_child<N: Into<NodeData>>(&mut self, to: NodeRef, node: N) -> NodeRef {
         let doc = self.document_mut();
-        let nr = doc.nodes.push(node.into());
+        let nr = doc.push_node(node);
         doc.append_child(to, nr);
         nr
     }
@@ -762,7 +1335,7 @@ pub trait DocumentBuilder {
     /// Inserts `node` as a sibling of `after`, immediately following it in the document
     fn insert_after<N: Into<NodeData>>(&mut self, node: N, after: NodeRef) -> NodeRef {
         let doc = self.document_mut();
-        let nr = doc.nodes.push(node.into());
+        let nr = doc.push_node(node);
         doc.insert_after(nr, after);
         nr
     }
@@ -770,7 +1343,7 @@ pub trait DocumentBuilder {
     /// Inserts `node` as a sibling of `before`, immediately preceding it in the document
     fn insert_before<N: Into<NodeData>>(&mut self, node: N, before: NodeRef) -> NodeRef {
         let doc = self.document_mut();
-        let nr = doc.nodes.push(node.into());
+        let nr = doc.push_node(node);
         doc.insert_before(nr, before);
         nr
     }
@@ -1023,7 +1596,7 @@ impl petgraph::visit::NodeIndexable for Document {
 impl petgraph::data::Build for Document {
     #[inline]
     fn add_node(&mut self, weight: Self::NodeWeight) -> Self::NodeId {
-        self.nodes.push(weight)
+        self.push_node(weight)
     }
 
     fn update_edge(
@@ -1060,7 +1633,7 @@ impl petgraph::data::DataMap for Document {
 impl petgraph::data::DataMapMut for Document {
     #[inline]
     fn node_weight_mut(&mut self, id: Self::NodeId) -> Option<&mut Self::NodeWeight> {
-        Some(&mut self.nodes[id])
+        Some(self.get_mut(id))
     }
 
     fn edge_weight_mut(&mut self, _id: Self::EdgeId) -> Option<&mut Self::EdgeWeight> {
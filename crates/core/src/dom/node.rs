@@ -1,13 +1,20 @@
-use std::{fmt, sync::Arc};
+use std::{
+    fmt,
+    sync::{Arc, Mutex, PoisonError},
+};
 
 use cranelift_entity::entity_impl;
 use petgraph::graph::{IndexType, NodeIndex};
+use serde::{Deserialize, Serialize};
 use smallstr::SmallString;
 
-use super::{ffi::Document as FFiDocument, Attribute, AttributeName};
+use super::{ffi::Document as FFiDocument, Attribute, AttributeName, PrintOptions};
 use crate::{InternedString, Symbol};
 
-#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, uniffi::Object)]
+#[derive(
+    Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, uniffi::Object,
+)]
+#[serde(transparent)]
 pub struct NodeRef(pub(crate) u32);
 
 entity_impl!(NodeRef, "node");
@@ -52,7 +59,7 @@ unsafe impl IndexType for NodeRef {
 }
 
 /// This enum represents the valid node types of a `Document` tree
-#[derive(Debug, Clone, PartialEq, uniffi::Enum)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, uniffi::Enum)]
 pub enum NodeData {
     /// A marker node that indicates the root of a document
     ///
@@ -88,22 +95,102 @@ impl Node {
         }
     }
     pub fn get_children(&self) -> Vec<Arc<Node>> {
-        self.document
-            .children(self.id.into())
+        let doc = self.document.inner();
+        doc.children(self.id)
             .iter()
-            .map(|node_ref| self.document.get_node(node_ref.clone()).into())
+            .map(|child| self.node(&doc, *child))
             .collect()
     }
 
+    /// Returns every descendant of this node, in document order
     pub fn get_depth_first_children(&self) -> Vec<Arc<Node>> {
-        let mut out: Vec<Arc<Node>> = Vec::new();
-        //out.push(self.clone().into());
-        for child in self.get_children() {
-            out.push(child.clone());
-            let depth = child.get_depth_first_children();
-            out.extend(depth);
+        let iter = self.iter_depth_first();
+        std::iter::from_fn(|| iter.next()).collect()
+    }
+
+    pub fn parent(&self) -> Option<Arc<Node>> {
+        let doc = self.document.inner();
+        doc.parent(self.id).map(|parent| self.node(&doc, parent))
+    }
+
+    pub fn next_sibling(&self) -> Option<Arc<Node>> {
+        let doc = self.document.inner();
+        sibling(&doc, self.id, 1).map(|sibling| self.node(&doc, sibling))
+    }
+
+    pub fn previous_sibling(&self) -> Option<Arc<Node>> {
+        let doc = self.document.inner();
+        sibling(&doc, self.id, -1).map(|sibling| self.node(&doc, sibling))
+    }
+
+    /// Returns the position of this node among the children of its parent, if it has one
+    pub fn index_in_parent(&self) -> Option<u32> {
+        let doc = self.document.inner();
+        let parent = doc.parent(self.id)?;
+        let index = doc
+            .children(parent)
+            .iter()
+            .position(|child| *child == self.id)?;
+        Some(index as u32)
+    }
+
+    /// Returns the number of ancestors of this node, which is 0 for the root
+    pub fn depth(&self) -> u32 {
+        let doc = self.document.inner();
+        std::iter::successors(doc.parent(self.id), |node| doc.parent(*node)).count() as u32
+    }
+
+    /// Returns the ancestors of this node, starting with its parent
+    pub fn ancestors(&self) -> Vec<Arc<Node>> {
+        let doc = self.document.inner();
+        std::iter::successors(doc.parent(self.id), |node| doc.parent(*node))
+            .map(|ancestor| self.node(&doc, ancestor))
+            .collect()
+    }
+
+    /// Returns the text of every leaf in the subtree of this node, concatenated in document order
+    pub fn text_content(&self) -> String {
+        let doc = self.document.inner();
+        let mut text = String::new();
+        let mut stack = vec![self.id];
+        while let Some(node) = stack.pop() {
+            if let NodeData::Leaf { value } = doc.get(node) {
+                text.push_str(value);
+            }
+            stack.extend(doc.children(node).iter().rev());
+        }
+        text
+    }
+
+    /// Returns the markup of the children of this node, without extra whitespace
+    pub fn inner_html(&self) -> String {
+        let doc = self.document.inner();
+        let mut html = String::new();
+        for child in doc.children(self.id) {
+            doc.print_node(*child, &mut html, PrintOptions::Minified)
+                .expect("printing to a string can't fail");
         }
-        out
+        html
+    }
+
+    /// Returns an iterator over the children of this node
+    pub fn iter_children(&self) -> Arc<NodeIterator> {
+        self.iter(Walk::Children {
+            parent: self.id,
+            previous: None,
+        })
+    }
+
+    /// Returns an iterator over the descendants of this node, in document order
+    pub fn iter_depth_first(&self) -> Arc<NodeIterator> {
+        let children = self.document.inner().children(self.id).to_vec();
+        self.iter(Walk::DepthFirst(children.into_iter().rev().collect()))
+    }
+
+    /// Returns an iterator over the ancestors of this node, starting with its parent
+    pub fn iter_ancestors(&self) -> Arc<NodeIterator> {
+        let parent = self.document.inner().parent(self.id);
+        self.iter(Walk::Ancestors(parent))
     }
     pub fn document(&self) -> FFiDocument {
         self.document.clone()
@@ -127,6 +214,85 @@ impl Node {
         format!("{self}")
     }
 }
+impl Node {
+    fn node(&self, doc: &super::Document, id: NodeRef) -> Arc<Node> {
+        Arc::new(Node {
+            document: self.document.clone(),
+            id,
+            data: doc.get(id).clone(),
+        })
+    }
+
+    fn iter(&self, walk: Walk) -> Arc<NodeIterator> {
+        Arc::new(NodeIterator {
+            document: self.document.clone(),
+            walk: Mutex::new(walk),
+        })
+    }
+}
+
+/// Returns the sibling `offset` positions away from `node`, if there is one
+fn sibling(doc: &super::Document, node: NodeRef, offset: isize) -> Option<NodeRef> {
+    let siblings = doc.children(doc.parent(node)?);
+    let index = siblings.iter().position(|child| *child == node)?;
+    siblings.get(index.checked_add_signed(offset)?).copied()
+}
+
+/// Visits the nodes related to a [`Node`] one at a time
+///
+/// Each step reads the document as it is at the time, so the iterator follows changes made in between,
+/// and ends early if it reaches a node which was removed.
+#[derive(uniffi::Object)]
+pub struct NodeIterator {
+    document: FFiDocument,
+    walk: Mutex<Walk>,
+}
+
+enum Walk {
+    /// The children of `parent`, continuing after the `previous` one visited
+    Children {
+        parent: NodeRef,
+        previous: Option<NodeRef>,
+    },
+    /// The next ancestor to visit
+    Ancestors(Option<NodeRef>),
+    /// The nodes left to visit, the next on top
+    DepthFirst(Vec<NodeRef>),
+}
+
+#[uniffi::export]
+impl NodeIterator {
+    /// Returns the next node, or nothing once every node has been visited
+    pub fn next(&self) -> Option<Arc<Node>> {
+        let doc = self.document.inner();
+        let mut walk = self.walk.lock().unwrap_or_else(PoisonError::into_inner);
+        let node = match &mut *walk {
+            Walk::Children { parent, previous } => {
+                let node = match previous {
+                    Some(previous) => sibling(&doc, *previous, 1)?,
+                    None => *doc.children(*parent).first()?,
+                };
+                *previous = Some(node);
+                node
+            }
+            Walk::Ancestors(next) => {
+                let node = next.take()?;
+                *next = doc.parent(node);
+                node
+            }
+            Walk::DepthFirst(stack) => {
+                let node = stack.pop()?;
+                stack.extend(doc.children(node).iter().rev());
+                node
+            }
+        };
+        Some(Arc::new(Node {
+            document: self.document.clone(),
+            id: node,
+            data: doc.get(node).clone(),
+        }))
+    }
+}
 impl NodeData {
     /// Returns a slice of Attributes for this node, if applicable
     pub fn attributes(&self) -> Vec<Attribute> {
@@ -188,7 +354,9 @@ impl From<SmallString<[u8; 16]>> for NodeData {
 }
 
 /// Represents the fully-qualified name of an element
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, uniffi::Record)]
+#[derive(
+    Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, uniffi::Record,
+)]
 pub struct ElementName {
     pub namespace: Option<String>,
     pub name: String,
@@ -257,7 +425,7 @@ impl PartialEq<InternedString> for ElementName {
 }
 
 /// An `Element` is a typed node in a document, with the ability to carry attributes and contain other nodes.
-#[derive(Debug, Clone, PartialEq, uniffi::Record)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, uniffi::Record)]
 pub struct Element {
     pub name: ElementName,
     pub attributes: Vec<Attribute>,
@@ -288,6 +456,15 @@ impl Element {
         self.attributes.clone()
     }
 
+    /// Returns the whitespace-separated classes in the `class` attribute of this element
+    pub fn classes(&self) -> impl Iterator<Item = &str> {
+        self.attributes
+            .iter()
+            .filter(|attr| attr.name.namespace.is_none() && attr.name.name == "class")
+            .filter_map(|attr| attr.value.as_deref())
+            .flat_map(str::split_whitespace)
+    }
+
     /// Sets the attribute named `name` on this element.
     ///
     /// If the attribute is already associated with this element, the value is replaced.
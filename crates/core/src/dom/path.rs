@@ -0,0 +1,313 @@
+use std::{fmt, str::FromStr};
+
+use fxhash::FxHashMap;
+
+use super::{changes::ancestors, Document, ElementName, NodeData, NodeRef};
+
+/// The location of a node in a document, as the steps from the root down to it
+///
+/// A path is written like a simple XPath, e.g. `/VStack[1]/Text[2]/text()[1]`, where each step names an
+/// element, or `text()` for a leaf, and gives its position among the siblings of the same kind, starting at 1.
+/// Unlike a `NodeRef`, a path means the same thing in any copy of a document, so it can be used to refer to
+/// a node in logs, recorded sessions and error messages. The root is written `/`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct NodePath {
+    steps: Vec<PathStep>,
+}
+
+/// A single step of a [`NodePath`]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum PathStep {
+    /// The `index`th child element named `name`, counting from 1
+    Element { name: ElementName, index: usize },
+    /// The `index`th child leaf, counting from 1
+    Text { index: usize },
+}
+impl PathStep {
+    fn of(data: &NodeData, index: usize) -> Self {
+        match data {
+            NodeData::NodeElement { element } => Self::Element {
+                name: element.name.clone(),
+                index,
+            },
+            _ => Self::Text { index },
+        }
+    }
+
+    fn index(&self) -> usize {
+        match self {
+            Self::Element { index, .. } | Self::Text { index } => *index,
+        }
+    }
+
+    fn set_index(&mut self, position: usize) {
+        match self {
+            Self::Element { index, .. } | Self::Text { index } => *index = position,
+        }
+    }
+
+    /// Returns true if `data` is of the kind of node this step counts
+    fn counts(&self, data: &NodeData) -> bool {
+        match (self, data) {
+            (Self::Element { name, .. }, NodeData::NodeElement { element }) => {
+                element.name == *name
+            }
+            (Self::Text { .. }, NodeData::NodeElement { .. }) => false,
+            (Self::Text { .. }, _) => true,
+            _ => false,
+        }
+    }
+}
+impl fmt::Display for PathStep {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Element { name, index } => write!(f, "{name}[{index}]"),
+            Self::Text { index } => write!(f, "text()[{index}]"),
+        }
+    }
+}
+
+impl NodePath {
+    /// Parses a path as written by its `Display` implementation
+    ///
+    /// The position of a step may be left out, in which case it is the first node of its kind.
+    pub fn parse(input: &str) -> Result<Self, PathError> {
+        PathParser { input, pos: 0 }.path()
+    }
+
+    pub fn steps(&self) -> &[PathStep] {
+        self.steps.as_slice()
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    /// Returns the path of `node` in `doc`, or `None` if it is not part of the tree under the root
+    pub(super) fn of(doc: &Document, node: NodeRef) -> Option<Self> {
+        let mut steps = vec![];
+        let mut current = node;
+        for parent in ancestors(doc, node) {
+            let mut step = PathStep::of(doc.get(current), 1);
+            let preceding = doc
+                .children(parent)
+                .iter()
+                .take_while(|sibling| **sibling != current)
+                .filter(|sibling| step.counts(doc.get(**sibling)))
+                .count();
+            step.set_index(preceding + 1);
+            steps.push(step);
+            current = parent;
+        }
+        if current != doc.root() {
+            return None;
+        }
+        steps.reverse();
+        Some(Self { steps })
+    }
+
+    /// Returns the node at this path in `doc`, if there is one
+    pub(super) fn resolve(&self, doc: &Document) -> Option<NodeRef> {
+        let mut node = doc.root();
+        for step in self.steps.iter() {
+            node = doc
+                .children(node)
+                .iter()
+                .copied()
+                .filter(|child| step.counts(doc.get(*child)))
+                .nth(step.index().checked_sub(1)?)?;
+        }
+        Some(node)
+    }
+}
+impl fmt::Display for NodePath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.steps.is_empty() {
+            return f.write_str("/");
+        }
+        for step in self.steps.iter() {
+            write!(f, "/{step}")?;
+        }
+        Ok(())
+    }
+}
+impl FromStr for NodePath {
+    type Err = PathError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+/// Finds the paths of many nodes of a document which isn't changing in between
+///
+/// Finding the path of one node scans the siblings of each of its ancestors, so the cache numbers all of the
+/// children of a parent at once, and reuses that for their siblings and descendants.
+#[derive(Default)]
+pub(super) struct PathCache {
+    /// The path of each node numbered so far, with its position among all of its siblings, or `None` if it
+    /// isn't part of the tree under the root
+    entries: FxHashMap<NodeRef, Option<(NodePath, usize)>>,
+}
+impl PathCache {
+    /// Returns the path of `node` in `doc` and its position among its siblings, if it is under the root
+    pub fn get(&mut self, doc: &Document, node: NodeRef) -> Option<(NodePath, usize)> {
+        if node == doc.root() {
+            return Some((NodePath::default(), 0));
+        }
+        if let Some(entry) = self.entries.get(&node) {
+            return entry.clone();
+        }
+        let Some((path, _)) = doc.parent(node).and_then(|parent| self.get(doc, parent)) else {
+            self.entries.insert(node, None);
+            return None;
+        };
+        let parent = doc.parent(node).unwrap();
+        let mut counts = FxHashMap::<Option<&ElementName>, usize>::default();
+        for (index, child) in doc.children(parent).iter().copied().enumerate() {
+            let data = doc.get(child);
+            let name = match data {
+                NodeData::NodeElement { element } => Some(&element.name),
+                _ => None,
+            };
+            let count = counts.entry(name).or_default();
+            *count += 1;
+            let mut path = path.clone();
+            path.steps.push(PathStep::of(data, *count));
+            self.entries.insert(child, Some((path, index)));
+        }
+        self.entries[&node].clone()
+    }
+}
+
+/// Represents the ways in which a path can fail to parse
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error, uniffi::Error)]
+#[uniffi(flat_error)]
+pub enum PathError {
+    #[error("expected {expected} at offset {offset}, but the path ended")]
+    UnexpectedEnd {
+        offset: usize,
+        expected: &'static str,
+    },
+    #[error("expected {expected} at offset {offset}, found {found:?}")]
+    UnexpectedChar {
+        offset: usize,
+        found: char,
+        expected: &'static str,
+    },
+    #[error("invalid position at offset {offset}, positions start at 1")]
+    InvalidIndex { offset: usize },
+}
+
+struct PathParser<'a> {
+    input: &'a str,
+    pos: usize,
+}
+impl<'a> PathParser<'a> {
+    fn path(&mut self) -> Result<NodePath, PathError> {
+        if !self.eat('/') {
+            return Err(self.unexpected("`/`"));
+        }
+        let mut steps = vec![];
+        if self.peek().is_none() {
+            return Ok(NodePath { steps });
+        }
+        loop {
+            steps.push(self.step()?);
+            if self.peek().is_none() {
+                return Ok(NodePath { steps });
+            }
+            if !self.eat('/') {
+                return Err(self.unexpected("`/` or `[`"));
+            }
+        }
+    }
+
+    fn step(&mut self) -> Result<PathStep, PathError> {
+        let name = self.name("an element name or `text()`")?;
+        if name == "text" && self.eat('(') {
+            if !self.eat(')') {
+                return Err(self.unexpected("`)`"));
+            }
+            let index = self.index()?;
+            return Ok(PathStep::Text { index });
+        }
+        let name = if self.eat(':') {
+            ElementName::new_with_namespace(name, self.name("an element name")?)
+        } else {
+            ElementName::new(name)
+        };
+        let index = self.index()?;
+        Ok(PathStep::Element { name, index })
+    }
+
+    fn name(&mut self, expected: &'static str) -> Result<&'a str, PathError> {
+        let start = self.pos;
+        while self.peek().is_some_and(is_name_char) {
+            self.bump();
+        }
+        if self.pos == start {
+            return Err(self.unexpected(expected));
+        }
+        Ok(&self.input[start..self.pos])
+    }
+
+    /// Parses an optional `[n]` position, which defaults to 1
+    fn index(&mut self) -> Result<usize, PathError> {
+        if !self.eat('[') {
+            return Ok(1);
+        }
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.bump();
+        }
+        if self.pos == start {
+            return Err(self.unexpected("a position"));
+        }
+        let index = match self.input[start..self.pos].parse::<usize>() {
+            Ok(index) if index > 0 => index,
+            _ => return Err(PathError::InvalidIndex { offset: start }),
+        };
+        if !self.eat(']') {
+            return Err(self.unexpected("`]`"));
+        }
+        Ok(index)
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.input[self.pos..].chars().next()
+    }
+
+    fn bump(&mut self) {
+        if let Some(c) = self.peek() {
+            self.pos += c.len_utf8();
+        }
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        let eaten = self.peek() == Some(c);
+        if eaten {
+            self.bump();
+        }
+        eaten
+    }
+
+    fn unexpected(&self, expected: &'static str) -> PathError {
+        match self.peek() {
+            None => PathError::UnexpectedEnd {
+                offset: self.pos,
+                expected,
+            },
+            Some(found) => PathError::UnexpectedChar {
+                offset: self.pos,
+                found,
+                expected,
+            },
+        }
+    }
+}
+
+/// Element names may contain anything but the characters which delimit the steps of a path
+fn is_name_char(c: char) -> bool {
+    !c.is_whitespace() && !matches!(c, '/' | '[' | ']' | ':' | '(' | ')')
+}
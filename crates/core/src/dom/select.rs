@@ -1,3 +1,6 @@
+use std::borrow::Cow;
+
+use super::index;
 use crate::dom::*;
 
 /// Represents a selector over elements in a `Document`
@@ -6,9 +9,11 @@ pub enum Selector<'a> {
     /// Selects an element with the given tag name, e.g. `foo`
     Tag(ElementName),
     /// Selects an element with the given unique id, e.g. `#id`
-    Id(&'a str),
+    Id(Cow<'a, str>),
     /// Selects all elements, e.g. `*`
     All,
+    /// Selects elements whose `class` attribute contains the given class, e.g. `.foo`
+    Class(Cow<'a, str>),
     /// Selects elements which match both sub-selectors, e.g. `.foo.bar`
     And(Box<Selector<'a>>, Box<Selector<'a>>),
     /// Selects elements which match either sub-selector, e.g. `.foo, .bar`
@@ -17,20 +22,83 @@ pub enum Selector<'a> {
     Descendant(Box<Selector<'a>>, Box<Selector<'a>>),
     /// Selects elements which are direct children of the first sub-selector and match the second sub-selector, e.g. `ul.foo > li`
     Child(Box<Selector<'a>>, Box<Selector<'a>>),
+    /// Selects elements which immediately follow a sibling matching the first sub-selector and match the second sub-selector, e.g. `h1 + p`
+    Adjacent(Box<Selector<'a>>, Box<Selector<'a>>),
+    /// Selects elements which follow a sibling matching the first sub-selector and match the second sub-selector, e.g. `h1 ~ p`
+    Sibling(Box<Selector<'a>>, Box<Selector<'a>>),
     /// Selects elements which have an attribute with the given name, e.g. `a[href]`
     Attribute(AttributeName),
     /// Selects elements which have an attribute with the given name and value, e.g. `a[href="https://example.org]`
     AttributeValue(AttributeName, AttributeValue),
     /// Selects elements which have an attribute with the given name whose value is a whitespace-separated list of values containing the given string, e.g. `[attr~=value]`
-    AttributeValueWhitespacedContains(AttributeName, &'a str),
+    AttributeValueWhitespacedContains(AttributeName, Cow<'a, str>),
     /// Selects elements which have an attribute with the given name whose value is prefixed by the given string, e.g. `[attr^=value]`
-    AttributeValueStartsWith(AttributeName, &'a str),
+    AttributeValueStartsWith(AttributeName, Cow<'a, str>),
     /// Selects elements which have an attribute with the given name whose value is suffixed by the given string, e.g. `[attr$=value]`
-    AttributeValueEndsWith(AttributeName, &'a str),
+    AttributeValueEndsWith(AttributeName, Cow<'a, str>),
     /// Selects elements which have an attribute with the given name whose value contains the given string, e.g. `[attr*=value]`
-    AttributeValueSubstring(AttributeName, &'a str),
+    AttributeValueSubstring(AttributeName, Cow<'a, str>),
+    /// Selects elements which are the first element among their siblings, e.g. `:first-child`
+    FirstChild,
+    /// Selects elements whose 1-based position among their element siblings is `an+b` for some `n >= 0`,
+    /// e.g. `:nth-child(2n+1)` is `NthChild(2, 1)`
+    NthChild(i32, i32),
+    /// Selects elements which don't match the sub-selector, e.g. `:not(.foo)`
+    Not(Box<Selector<'a>>),
+    /// Selects elements without children, ignoring whitespace-only text, e.g. `:empty`
+    Empty,
+    /// Selects elements which have a descendant matching the sub-selector, e.g. `:has(img)`
+    Has(Box<Selector<'a>>),
 }
 impl<'a> Selector<'a> {
+    /// Parses a CSS selector, e.g. `form#upload-form > input[data-phx-upload-ref]`
+    ///
+    /// Supports type, universal, id, class and attribute selectors, the descendant, child and sibling
+    /// combinators, selector lists, and the `:first-child`, `:nth-child()`, `:not()`, `:empty` and `:has()`
+    /// pseudo-classes. Namespaces are written as `ns|name`.
+    pub fn parse(input: &'a str) -> Result<Self, SelectorError> {
+        SelectorParser { input, pos: 0 }.selector_list()
+    }
+
+    /// Converts this selector into one which doesn't borrow its source, by copying the strings it borrows
+    ///
+    /// This lets a selector outlive the string it was parsed from, e.g. when it is stored for later use.
+    pub fn into_static(self) -> Selector<'static> {
+        let owned = |s: Cow<str>| Cow::Owned(s.into_owned());
+        let boxed = |s: Box<Self>| Box::new(s.into_static());
+        match self {
+            Self::Tag(name) => Selector::Tag(name),
+            Self::Id(id) => Selector::Id(owned(id)),
+            Self::All => Selector::All,
+            Self::Class(class) => Selector::Class(owned(class)),
+            Self::And(l, r) => Selector::And(boxed(l), boxed(r)),
+            Self::Or(l, r) => Selector::Or(boxed(l), boxed(r)),
+            Self::Descendant(l, r) => Selector::Descendant(boxed(l), boxed(r)),
+            Self::Child(l, r) => Selector::Child(boxed(l), boxed(r)),
+            Self::Adjacent(l, r) => Selector::Adjacent(boxed(l), boxed(r)),
+            Self::Sibling(l, r) => Selector::Sibling(boxed(l), boxed(r)),
+            Self::Attribute(name) => Selector::Attribute(name),
+            Self::AttributeValue(name, value) => Selector::AttributeValue(name, value),
+            Self::AttributeValueWhitespacedContains(name, value) => {
+                Selector::AttributeValueWhitespacedContains(name, owned(value))
+            }
+            Self::AttributeValueStartsWith(name, value) => {
+                Selector::AttributeValueStartsWith(name, owned(value))
+            }
+            Self::AttributeValueEndsWith(name, value) => {
+                Selector::AttributeValueEndsWith(name, owned(value))
+            }
+            Self::AttributeValueSubstring(name, value) => {
+                Selector::AttributeValueSubstring(name, owned(value))
+            }
+            Self::FirstChild => Selector::FirstChild,
+            Self::NthChild(a, b) => Selector::NthChild(a, b),
+            Self::Not(selector) => Selector::Not(boxed(selector)),
+            Self::Empty => Selector::Empty,
+            Self::Has(selector) => Selector::Has(boxed(selector)),
+        }
+    }
+
     /// Returns true if this selection can match at most one node, which is only true when an identified
     /// node is selected or is selected using a combinator that implies exlusion. For example, selecting
     /// an identified node as a descendant/child of an arbitrary selector is guaranteed to be unique,
@@ -41,6 +109,8 @@ impl<'a> Selector<'a> {
             Self::And(l, r) => l.is_unique() || r.is_unique(),
             Self::Descendant(_, child) => child.is_unique(),
             Self::Child(_, child) => child.is_unique(),
+            Self::Adjacent(_, selector) => selector.is_unique(),
+            Self::Sibling(_, selector) => selector.is_unique(),
             _ => false,
         }
     }
@@ -54,11 +124,12 @@ impl<'a> Selector<'a> {
 
         match self {
             Self::Tag(t) => t == &element.name,
-            Self::Id(id) => match document.get_by_id(*id) {
+            Self::Id(id) => match document.get_by_id(id) {
                 None => false,
                 Some(identified) => node == identified,
             },
             Self::All => true,
+            Self::Class(class) => element.classes().any(|c| c == class),
             Self::And(l, r) => l.matches(node, document) && r.matches(node, document),
             Self::Or(l, r) => l.matches(node, document) || r.matches(node, document),
             Self::Descendant(ancestor, selector) => {
@@ -83,8 +154,53 @@ impl<'a> Selector<'a> {
                     Some(p) => parent.matches(p, document),
                 }
             }
+            Self::Adjacent(sibling, selector) => {
+                if !selector.matches(node, document) {
+                    return false;
+                }
+                match preceding_elements(node, document).next() {
+                    None => false,
+                    Some(prev) => sibling.matches(prev, document),
+                }
+            }
+            Self::Sibling(sibling, selector) => {
+                if !selector.matches(node, document) {
+                    return false;
+                }
+                preceding_elements(node, document).any(|prev| sibling.matches(prev, document))
+            }
+            Self::FirstChild => {
+                document.parent(node).is_some()
+                    && preceding_elements(node, document).next().is_none()
+            }
+            Self::NthChild(a, b) => {
+                if document.parent(node).is_none() {
+                    return false;
+                }
+                // Widened so that no `an+b` the parser accepts can overflow
+                let (a, b) = (i64::from(*a), i64::from(*b));
+                let position = preceding_elements(node, document).count() as i64 + 1;
+                match (a, position - b) {
+                    (0, diff) => diff == 0,
+                    (a, diff) => diff % a == 0 && diff / a >= 0,
+                }
+            }
+            Self::Not(selector) => !selector.matches(node, document),
+            Self::Empty => document.children(node).iter().all(|child| {
+                matches!(document.get(*child), NodeData::Leaf { value } if value.trim().is_empty())
+            }),
+            Self::Has(selector) => {
+                let mut stack = document.children(node).to_vec();
+                while let Some(descendant) = stack.pop() {
+                    if selector.matches(descendant, document) {
+                        return true;
+                    }
+                    stack.extend_from_slice(document.children(descendant));
+                }
+                false
+            }
             Self::Attribute(name) => {
-                for attr in element.attributes() {
+                for attr in element.attributes.iter() {
                     if &attr.name == name {
                         return true;
                     }
@@ -92,7 +208,7 @@ impl<'a> Selector<'a> {
                 false
             }
             Self::AttributeValue(name, ref value) => {
-                for attr in element.attributes() {
+                for attr in element.attributes.iter() {
                     if &attr.name == name && attr.value.eq(&Some(value.name())) {
                         return true;
                     }
@@ -100,7 +216,7 @@ impl<'a> Selector<'a> {
                 false
             }
             Self::AttributeValueWhitespacedContains(name, expected) => {
-                for attr in element.attributes() {
+                for attr in element.attributes.iter() {
                     if &attr.name != name {
                         continue;
                     }
@@ -109,7 +225,7 @@ impl<'a> Selector<'a> {
                         None => "",
                     };
                     for split in value.split_whitespace() {
-                        if split == *expected {
+                        if split == expected {
                             return true;
                         }
                     }
@@ -117,12 +233,12 @@ impl<'a> Selector<'a> {
                 false
             }
             Self::AttributeValueStartsWith(name, prefix) => {
-                for attr in element.attributes() {
+                for attr in element.attributes.iter() {
                     if &attr.name != name {
                         continue;
                     }
                     match &attr.value {
-                        Some(s) if s.starts_with(prefix) => return true,
+                        Some(s) if s.starts_with(prefix.as_ref()) => return true,
                         None if prefix.is_empty() => return true,
                         _ => continue,
                     }
@@ -130,12 +246,12 @@ impl<'a> Selector<'a> {
                 false
             }
             Self::AttributeValueEndsWith(name, suffix) => {
-                for attr in element.attributes() {
+                for attr in element.attributes.iter() {
                     if &attr.name != name {
                         continue;
                     }
                     match &attr.value {
-                        Some(s) if s.ends_with(suffix) => return true,
+                        Some(s) if s.ends_with(suffix.as_ref()) => return true,
                         None if suffix.is_empty() => return true,
                         _ => continue,
                     }
@@ -143,12 +259,12 @@ impl<'a> Selector<'a> {
                 false
             }
             Self::AttributeValueSubstring(name, substring) => {
-                for attr in element.attributes() {
+                for attr in element.attributes.iter() {
                     if &attr.name != name {
                         continue;
                     }
                     match &attr.value {
-                        Some(s) if s.contains(substring) => return true,
+                        Some(s) if s.contains(substring.as_ref()) => return true,
                         None if substring.is_empty() => return true,
                         _ => continue,
                     }
@@ -159,20 +275,409 @@ impl<'a> Selector<'a> {
     }
 }
 
+/// Returns the element siblings which precede `node`, nearest first
+fn preceding_elements(node: NodeRef, document: &Document) -> impl Iterator<Item = NodeRef> + '_ {
+    let siblings = match document.parent(node) {
+        None => &[][..],
+        Some(parent) => {
+            let children = document.children(parent);
+            let index = children
+                .iter()
+                .position(|child| *child == node)
+                .unwrap_or(0);
+            &children[..index]
+        }
+    };
+    siblings
+        .iter()
+        .rev()
+        .copied()
+        .filter(|sibling| matches!(document.get(*sibling), NodeData::NodeElement { .. }))
+}
+
+/// Represents the possible types of failure that can occur while parsing a [`Selector`]
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error, uniffi::Error)]
+#[uniffi(flat_error)]
+pub enum SelectorError {
+    #[error("expected {expected} at offset {offset}, but the selector ended")]
+    UnexpectedEnd {
+        offset: usize,
+        expected: &'static str,
+    },
+    #[error("expected {expected} at offset {offset}, found {found:?}")]
+    UnexpectedChar {
+        offset: usize,
+        found: char,
+        expected: &'static str,
+    },
+    #[error("unterminated string starting at offset {offset}")]
+    UnterminatedString { offset: usize },
+    #[error("unknown pseudo-class `:{name}` at offset {offset}")]
+    UnknownPseudoClass { offset: usize, name: String },
+    #[error("invalid argument to `:{pseudo_class}()` at offset {offset}")]
+    InvalidArgument {
+        offset: usize,
+        pseudo_class: &'static str,
+    },
+    #[error("{feature} are not supported, found at offset {offset}")]
+    Unsupported {
+        offset: usize,
+        feature: &'static str,
+    },
+}
+
+/// A recursive descent parser for selectors, which borrows names and values from its input
+struct SelectorParser<'a> {
+    input: &'a str,
+    pos: usize,
+}
+impl<'a> SelectorParser<'a> {
+    /// Parses a comma-separated list of selectors, which must make up the whole input
+    fn selector_list(&mut self) -> Result<Selector<'a>, SelectorError> {
+        let selector = self.list()?;
+        match self.peek() {
+            None => Ok(selector),
+            Some(_) => Err(self.unexpected("a combinator or `,`")),
+        }
+    }
+
+    /// Parses a comma-separated list of selectors, up to a closing `)` or the end of input
+    fn list(&mut self) -> Result<Selector<'a>, SelectorError> {
+        let mut selector = self.complex()?;
+        while self.eat(',') {
+            let next = self.complex()?;
+            selector = Selector::Or(Box::new(selector), Box::new(next));
+        }
+        Ok(selector)
+    }
+
+    /// Parses a sequence of compound selectors joined by combinators, up to anything else
+    fn complex(&mut self) -> Result<Selector<'a>, SelectorError> {
+        self.skip_whitespace();
+        let mut selector = self.compound()?;
+        loop {
+            let spaced = self.skip_whitespace();
+            let combinator = match self.peek() {
+                Some(c @ ('>' | '+' | '~')) => {
+                    self.bump();
+                    self.skip_whitespace();
+                    c
+                }
+                Some(c) if spaced && c != ',' && c != ')' => ' ',
+                _ => return Ok(selector),
+            };
+            let prev = Box::new(selector);
+            let next = Box::new(self.compound()?);
+            selector = match combinator {
+                '>' => Selector::Child(prev, next),
+                '+' => Selector::Adjacent(prev, next),
+                '~' => Selector::Sibling(prev, next),
+                _ => Selector::Descendant(prev, next),
+            };
+        }
+    }
+
+    /// Parses an optional type selector followed by any number of id, class and attribute selectors
+    fn compound(&mut self) -> Result<Selector<'a>, SelectorError> {
+        let mut parts = vec![];
+        match self.peek() {
+            Some('*') => {
+                self.bump();
+                parts.push(Selector::All);
+            }
+            Some(c) if is_name_char(c) => {
+                let name = match self.qualified_name("a tag name")? {
+                    (None, name) => ElementName::new(name),
+                    (Some(ns), name) => ElementName::new_with_namespace(ns, name),
+                };
+                parts.push(Selector::Tag(name));
+            }
+            _ => (),
+        }
+        loop {
+            match self.peek() {
+                Some('#') => {
+                    self.bump();
+                    parts.push(Selector::Id(self.ident("an id")?.into()));
+                }
+                Some('.') => {
+                    self.bump();
+                    parts.push(Selector::Class(self.ident("a class name")?.into()));
+                }
+                Some('[') => {
+                    self.bump();
+                    parts.push(self.attribute()?);
+                }
+                Some(':') => {
+                    self.bump();
+                    parts.push(self.pseudo_class()?);
+                }
+                _ => break,
+            }
+        }
+        parts
+            .into_iter()
+            .reduce(|l, r| Selector::And(Box::new(l), Box::new(r)))
+            .ok_or_else(|| self.unexpected("a selector"))
+    }
+
+    /// Parses the contents of an attribute selector, after the opening `[`
+    fn attribute(&mut self) -> Result<Selector<'a>, SelectorError> {
+        self.skip_whitespace();
+        let name = match self.qualified_name("an attribute name")? {
+            (None, name) => AttributeName::new(name),
+            (Some(ns), name) => AttributeName::new_with_namespace(ns, name),
+        };
+        self.skip_whitespace();
+        let op = match self.peek() {
+            Some(']') => {
+                self.bump();
+                return Ok(Selector::Attribute(name));
+            }
+            Some('=') => {
+                self.bump();
+                '='
+            }
+            Some(c @ ('~' | '^' | '$' | '*')) => {
+                self.bump();
+                if !self.eat('=') {
+                    return Err(self.unexpected("`=`"));
+                }
+                c
+            }
+            Some('|') => return Err(self.unsupported("`|=` attribute selectors")),
+            _ => return Err(self.unexpected("an attribute operator or `]`")),
+        };
+        self.skip_whitespace();
+        let value = self.value()?;
+        self.skip_whitespace();
+        match self.peek() {
+            Some(']') => self.bump(),
+            Some(c) if is_name_char(c) => return Err(self.unsupported("attribute flags")),
+            _ => return Err(self.unexpected("`]`")),
+        };
+        Ok(match op {
+            '=' => Selector::AttributeValue(name, value.into()),
+            '~' => Selector::AttributeValueWhitespacedContains(name, value.into()),
+            '^' => Selector::AttributeValueStartsWith(name, value.into()),
+            '$' => Selector::AttributeValueEndsWith(name, value.into()),
+            _ => Selector::AttributeValueSubstring(name, value.into()),
+        })
+    }
+
+    /// Parses a pseudo-class, after the `:`
+    fn pseudo_class(&mut self) -> Result<Selector<'a>, SelectorError> {
+        let offset = self.pos;
+        let name = self.ident("a pseudo-class")?;
+        let selector = match name {
+            "first-child" => return Ok(Selector::FirstChild),
+            "empty" => return Ok(Selector::Empty),
+            "nth-child" | "not" | "has" => {
+                if !self.eat('(') {
+                    return Err(self.unexpected("`(`"));
+                }
+                match name {
+                    "nth-child" => self.nth()?,
+                    "not" => Selector::Not(Box::new(self.list()?)),
+                    _ => {
+                        self.skip_whitespace();
+                        if let Some('>' | '+' | '~') = self.peek() {
+                            return Err(self.unsupported("relative selectors in `:has()`"));
+                        }
+                        Selector::Has(Box::new(self.list()?))
+                    }
+                }
+            }
+            _ => {
+                return Err(SelectorError::UnknownPseudoClass {
+                    offset,
+                    name: name.to_string(),
+                })
+            }
+        };
+        self.skip_whitespace();
+        if !self.eat(')') {
+            return Err(self.unexpected("`)`"));
+        }
+        Ok(selector)
+    }
+
+    /// Parses the `an+b` argument of `:nth-child()`, also accepting `odd` and `even`
+    fn nth(&mut self) -> Result<Selector<'a>, SelectorError> {
+        self.skip_whitespace();
+        let offset = self.pos;
+        let len = self.input[offset..]
+            .find(')')
+            .unwrap_or(self.input.len() - offset);
+        let arg: String = self.input[offset..offset + len]
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .collect();
+        let integer = |s: &str| s.strip_prefix('+').unwrap_or(s).parse::<i32>().ok();
+        let nth = match arg.to_ascii_lowercase().as_str() {
+            "odd" => Some((2, 1)),
+            "even" => Some((2, 0)),
+            arg => match arg.split_once('n') {
+                None => integer(arg).map(|b| (0, b)),
+                Some((a, b)) => {
+                    let a = match a {
+                        "" | "+" => Some(1),
+                        "-" => Some(-1),
+                        a => integer(a),
+                    };
+                    let b = match b {
+                        "" => Some(0),
+                        b if b.starts_with(['+', '-']) => integer(b),
+                        _ => None,
+                    };
+                    a.zip(b)
+                }
+            },
+        };
+        let (a, b) = nth.ok_or(SelectorError::InvalidArgument {
+            offset,
+            pseudo_class: "nth-child",
+        })?;
+        self.pos = offset + len;
+        Ok(Selector::NthChild(a, b))
+    }
+
+    /// Parses an attribute value, which is either a name or a quoted string
+    fn value(&mut self) -> Result<&'a str, SelectorError> {
+        let quote = match self.peek() {
+            Some(c @ ('"' | '\'')) => c,
+            _ => return self.ident("an attribute value"),
+        };
+        let start = self.pos;
+        self.bump();
+        loop {
+            match self.peek() {
+                None => return Err(SelectorError::UnterminatedString { offset: start }),
+                Some('\\') => return Err(self.unsupported("escape sequences")),
+                Some(c) if c == quote => break,
+                Some(_) => {
+                    self.bump();
+                }
+            }
+        }
+        let value = &self.input[start + 1..self.pos];
+        self.bump();
+        Ok(value)
+    }
+
+    /// Parses a name with an optional namespace, e.g. `ns|name`
+    fn qualified_name(
+        &mut self,
+        expected: &'static str,
+    ) -> Result<(Option<&'a str>, &'a str), SelectorError> {
+        let name = self.ident(expected)?;
+        let mut rest = self.input[self.pos..].chars();
+        if rest.next() == Some('|') && rest.next().is_some_and(is_name_char) {
+            self.bump();
+            return Ok((Some(name), self.ident(expected)?));
+        }
+        Ok((None, name))
+    }
+
+    fn ident(&mut self, expected: &'static str) -> Result<&'a str, SelectorError> {
+        let start = self.pos;
+        while self.peek().is_some_and(is_name_char) {
+            self.bump();
+        }
+        if self.peek() == Some('\\') {
+            return Err(self.unsupported("escape sequences"));
+        }
+        if self.pos == start {
+            return Err(self.unexpected(expected));
+        }
+        Ok(&self.input[start..self.pos])
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.input[self.pos..].chars().next()
+    }
+
+    fn bump(&mut self) {
+        if let Some(c) = self.peek() {
+            self.pos += c.len_utf8();
+        }
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        let eaten = self.peek() == Some(c);
+        if eaten {
+            self.bump();
+        }
+        eaten
+    }
+
+    /// Skips any whitespace, returning true if there was some
+    fn skip_whitespace(&mut self) -> bool {
+        let start = self.pos;
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.bump();
+        }
+        self.pos > start
+    }
+
+    fn unexpected(&self, expected: &'static str) -> SelectorError {
+        match self.peek() {
+            None => SelectorError::UnexpectedEnd {
+                offset: self.pos,
+                expected,
+            },
+            Some(found) => SelectorError::UnexpectedChar {
+                offset: self.pos,
+                found,
+                expected,
+            },
+        }
+    }
+
+    fn unsupported(&self, feature: &'static str) -> SelectorError {
+        SelectorError::Unsupported {
+            offset: self.pos,
+            feature,
+        }
+    }
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '-' || c == '_' || !c.is_ascii()
+}
+
 pub struct SelectionIter<'doc, 'select> {
     document: &'doc Document,
     selection: Selector<'select>,
-    state: petgraph::visit::Dfs<NodeRef, fixedbitset::FixedBitSet>,
+    state: Traversal,
     is_unique: bool,
     is_done: bool,
 }
+
+/// How a selection visits the nodes which may match it
+enum Traversal {
+    /// Visits every node of the subtree
+    Dfs(petgraph::visit::Dfs<NodeRef, fixedbitset::FixedBitSet>),
+    /// Visits only the nodes found using the indexes of the document, in the same order as `Dfs` would
+    Indexed(std::vec::IntoIter<NodeRef>),
+}
+
 impl<'doc, 'select> SelectionIter<'doc, 'select> {
     pub(super) fn new(
         document: &'doc Document,
         selection: Selector<'select>,
         start: NodeRef,
     ) -> Self {
-        let state = petgraph::visit::Dfs::new(document, start);
+        let candidates = document
+            .indexes
+            .as_deref()
+            .and_then(|indexes| indexes.candidates(&selection));
+        let state = match candidates {
+            Some(candidates) => Traversal::Indexed(
+                index::in_traversal_order(document, start, candidates).into_iter(),
+            ),
+            None => Traversal::Dfs(petgraph::visit::Dfs::new(document, start)),
+        };
         let is_unique = selection.is_unique();
         Self {
             document,
@@ -191,7 +696,14 @@ impl<'doc, 'select> Iterator for SelectionIter<'doc, 'select> {
             return None;
         }
 
-        while let Some(node) = self.state.next(self.document) {
+        loop {
+            let node = match &mut self.state {
+                Traversal::Dfs(dfs) => dfs.next(self.document),
+                Traversal::Indexed(candidates) => candidates.next(),
+            };
+            let Some(node) = node else {
+                break;
+            };
             if self.selection.matches(node, self.document) {
                 if self.is_unique {
                     self.is_done = true;
@@ -0,0 +1,231 @@
+use std::{collections::BTreeSet, sync::Arc};
+
+use super::{
+    changes::{ancestors, is_attached, subtree, Touched},
+    Document, NodeData, NodeRef, Selector, SelectorError,
+};
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, uniffi::Enum)]
+pub enum MatchChangeType {
+    /// The node started matching the selector
+    Added = 0,
+    /// The node no longer matches the selector, or was removed from the document
+    Removed = 1,
+    /// The node still matches the selector, but its data changed or it was moved
+    Changed = 2,
+}
+
+/// Receives a notification for every node which starts or stops matching a subscribed selector
+#[uniffi::export(callback_interface)]
+pub trait MatchChangeHandler: Send + Sync {
+    fn handle(&self, change_type: MatchChangeType, node_ref: Arc<NodeRef>, node_data: NodeData);
+}
+
+/// The selector subscriptions of a document, see [`Document::subscribe`]
+#[derive(Clone, Default)]
+pub(super) struct Subscriptions {
+    next_id: u64,
+    entries: Vec<Subscription>,
+}
+
+#[derive(Clone)]
+struct Subscription {
+    id: u64,
+    selector: Selector<'static>,
+    scope: Scope,
+    handler: Arc<dyn MatchChangeHandler>,
+    /// The nodes which matched the selector as of the last update
+    matches: BTreeSet<NodeRef>,
+}
+
+impl Subscriptions {
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn subscribe(
+        &mut self,
+        doc: &Document,
+        selector: &str,
+        handler: Arc<dyn MatchChangeHandler>,
+    ) -> Result<u64, SelectorError> {
+        let selector = Selector::parse(selector)?.into_static();
+        let scope = Scope::of(&selector);
+        let matches = doc.select(selector.clone()).collect();
+        let id = self.next_id;
+        self.next_id += 1;
+        self.entries.push(Subscription {
+            id,
+            selector,
+            scope,
+            handler,
+            matches,
+        });
+        Ok(id)
+    }
+
+    pub fn unsubscribe(&mut self, id: u64) -> bool {
+        let len = self.entries.len();
+        self.entries.retain(|subscription| subscription.id != id);
+        self.entries.len() != len
+    }
+
+    /// Re-evaluates every subscription against the nodes which `touched` may have affected, returning the
+    /// nodes which started or stopped matching
+    ///
+    /// The changes must be passed to [`Subscriptions::update`] to take effect.
+    pub fn evaluate(&self, doc: &Document, touched: &Touched) -> Vec<MatchChange> {
+        let mut changes = vec![];
+        for subscription in self.entries.iter() {
+            subscription.evaluate(doc, touched, &mut changes);
+        }
+        changes
+    }
+
+    /// Records `changes` in the matches of their subscriptions
+    pub fn update(&mut self, changes: &[MatchChange]) {
+        for change in changes {
+            let Some(subscription) = self.entries.iter_mut().find(|s| s.id == change.id) else {
+                continue;
+            };
+            match change.change_type {
+                MatchChangeType::Added => subscription.matches.insert(change.node),
+                MatchChangeType::Removed => subscription.matches.remove(&change.node),
+                MatchChangeType::Changed => continue,
+            };
+        }
+    }
+}
+
+/// A change to the matches of a subscription
+pub(super) struct MatchChange {
+    id: u64,
+    pub handler: Arc<dyn MatchChangeHandler>,
+    pub change_type: MatchChangeType,
+    pub node: NodeRef,
+}
+
+impl Subscription {
+    fn evaluate(&self, doc: &Document, touched: &Touched, changes: &mut Vec<MatchChange>) {
+        let mut change = |change_type, node| {
+            changes.push(MatchChange {
+                id: self.id,
+                handler: self.handler.clone(),
+                change_type,
+                node,
+            })
+        };
+
+        for node in self.matches.iter().copied() {
+            if !is_attached(doc, node) {
+                change(MatchChangeType::Removed, node);
+            }
+        }
+        for node in self.scope.affected(doc, touched) {
+            if !is_attached(doc, node) {
+                continue;
+            }
+            let matched = self.matches.contains(&node);
+            match (matched, self.selector.matches(node, doc)) {
+                (false, true) => change(MatchChangeType::Added, node),
+                (true, false) => change(MatchChangeType::Removed, node),
+                (true, true) if touched.changed.contains(&node) => {
+                    change(MatchChangeType::Changed, node)
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+/// The nodes other than itself which decide whether a node matches a selector
+#[derive(Debug, Clone, Copy, Default)]
+struct Scope {
+    ancestors: bool,
+    siblings: bool,
+    descendants: bool,
+}
+impl Scope {
+    fn of(selector: &Selector) -> Self {
+        match selector {
+            Selector::And(l, r) | Selector::Or(l, r) => Self::of(l).union(Self::of(r)),
+            Selector::Descendant(l, r) | Selector::Child(l, r) => Self {
+                ancestors: true,
+                ..Self::of(l).union(Self::of(r))
+            },
+            Selector::Adjacent(l, r) | Selector::Sibling(l, r) => Self {
+                siblings: true,
+                ..Self::of(l).union(Self::of(r))
+            },
+            Selector::FirstChild | Selector::NthChild(..) => Self {
+                siblings: true,
+                ..Self::default()
+            },
+            Selector::Empty => Self {
+                descendants: true,
+                ..Self::default()
+            },
+            Selector::Has(selector) => Self {
+                descendants: true,
+                ..Self::of(selector)
+            },
+            Selector::Not(selector) => Self::of(selector),
+            _ => Self::default(),
+        }
+    }
+
+    fn union(self, other: Self) -> Self {
+        Self {
+            ancestors: self.ancestors || other.ancestors,
+            siblings: self.siblings || other.siblings,
+            descendants: self.descendants || other.descendants,
+        }
+    }
+
+    /// Returns the nodes whose match against a selector with this scope may have been changed by `touched`
+    ///
+    /// A parent whose children changed only needs matching again when the selector looks at descendants,
+    /// and its other children only when the selector looks at siblings, so inserting a node under the root
+    /// doesn't re-match the whole document for most selectors. When it does look at those, and also at
+    /// ancestors (e.g. `div:has(p) span` or `li:first-child span`), every node below a touched parent is
+    /// matched again, which is the whole document when that parent is the root.
+    fn affected(self, doc: &Document, touched: &Touched) -> BTreeSet<NodeRef> {
+        let mut nodes = touched.changed.clone();
+        if self.descendants {
+            nodes.extend(touched.parents.iter().copied());
+        }
+        for node in touched.attached.iter() {
+            nodes.extend(subtree(doc, *node));
+        }
+        if self.descendants {
+            let found = nodes
+                .iter()
+                .flat_map(|node| ancestors(doc, *node))
+                .collect::<Vec<_>>();
+            nodes.extend(found);
+        }
+        if self.siblings {
+            let mut found = vec![];
+            for node in nodes.iter().copied() {
+                if let Some(parent) = doc.parent(node) {
+                    let siblings = doc.children(parent);
+                    let position = siblings.iter().position(|sibling| *sibling == node);
+                    found.extend_from_slice(&siblings[position.unwrap_or(0)..]);
+                }
+            }
+            for parent in touched.parents.iter() {
+                found.extend_from_slice(doc.children(*parent));
+            }
+            nodes.extend(found);
+        }
+        if self.ancestors {
+            let found = nodes
+                .iter()
+                .flat_map(|node| subtree(doc, *node))
+                .collect::<Vec<_>>();
+            nodes.extend(found);
+        }
+        nodes
+    }
+}
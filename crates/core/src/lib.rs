@@ -1,6 +1,5 @@
 #![feature(slice_take)]
 #![feature(assert_matches)]
-#![feature(sync_unsafe_cell)]
 
 pub mod diff;
 pub mod dom;
@@ -1,10 +1,15 @@
-use std::{collections::HashMap, sync::Arc, time::Duration};
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::Arc,
+    time::Duration,
+};
 
 use log::{debug, error};
 use phoenix_channels_client::{url::Url, Channel, Event, Number, Payload, Socket, Topic, JSON};
+use tokio::sync::mpsc;
 
 use crate::{
-    diff::fragment::{FragmentMerge, Root, RootDiff},
+    diff::fragment::{FragmentMerge, RenderOptions, Root, RootDiff},
     dom::{AttributeName, ElementName, Selector},
     parser::parse,
 };
@@ -74,15 +79,26 @@ impl LiveChannel {
         // TODO: This should probably take the event closure to send changes back to swift/kotlin
         let mut document = self.document.clone();
         let events = self.channel.events();
+        // The component deletion handshake runs alongside the diffs, and reports back after each step
+        let (handshake, mut replies) = mpsc::unbounded_channel();
+        let mut destroying = BTreeSet::new();
         loop {
-            let event = events.event().await?;
-            if let Event::User { user: user_event } = event.event {
-                if user_event == "diff" {
-                    let payload = event.payload.to_string();
-                    debug!("PAYLOAD: {payload}");
-                    let diff: RootDiff = serde_json::from_str(payload.as_str())?;
-                    debug!("diff: {diff:#?}");
-                    document = document.merge(diff)?;
+            tokio::select! {
+                event = events.event() => {
+                    let event = event?;
+                    if let Event::User { user: user_event } = event.event {
+                        if user_event == "diff" {
+                            let payload = event.payload.to_string();
+                            debug!("PAYLOAD: {payload}");
+                            let diff: RootDiff = serde_json::from_str(payload.as_str())?;
+                            debug!("diff: {diff:#?}");
+                            document = document.merge(diff)?;
+                            self.destroy_orphaned_components(&document, &mut destroying, &handshake);
+                        }
+                    }
+                }
+                Some(reply) = replies.recv() => {
+                    self.continue_destroying_components(reply, &mut document, &mut destroying, &handshake);
                 }
             }
         }
@@ -99,8 +115,7 @@ impl LiveChannel {
                     let rendered = rendered.to_string();
                     let root: RootDiff = serde_json::from_str(rendered.as_str())?;
                     let root: Root = root.try_into()?;
-                    let root: String = root.try_into()?;
-                    let document = parse(&root)?;
+                    let document = root.render_document(RenderOptions::default())?;
                     Some(document)
                 } else {
                     None
@@ -386,6 +401,145 @@ impl LiveChannel {
     }
 }
 
+impl LiveChannel {
+    /// Starts the component deletion handshake for components which are no longer rendered
+    ///
+    /// Like the JS client, the server is first told which components will be destroyed. Once that is
+    /// acknowledged, the components which are still orphaned are reported as destroyed, and only those
+    /// the server confirmed are pruned from the document. Until then they stay in the document, so a diff
+    /// which renders them again can still be merged. `destroying` holds the components in the handshake,
+    /// which aren't sent again until it has finished.
+    fn destroy_orphaned_components(
+        &self,
+        document: &Root,
+        destroying: &mut BTreeSet<i32>,
+        handshake: &mpsc::UnboundedSender<HandshakeReply>,
+    ) {
+        let cids: Vec<i32> = document
+            .orphaned_components()
+            .difference(destroying)
+            .copied()
+            .collect();
+        if cids.is_empty() {
+            return;
+        }
+        debug!("destroying components: {cids:?}");
+        destroying.extend(cids.iter().copied());
+        self.send_handshake_event(HandshakeEvent::WillDestroy, cids, handshake.clone());
+    }
+
+    /// Continues the component deletion handshake once the server has replied
+    ///
+    /// The components are checked against `document` as it is when the reply arrives, as diffs may have
+    /// been merged while waiting for it.
+    fn continue_destroying_components(
+        &self,
+        reply: HandshakeReply,
+        document: &mut Root,
+        destroying: &mut BTreeSet<i32>,
+        handshake: &mpsc::UnboundedSender<HandshakeReply>,
+    ) {
+        match reply {
+            HandshakeReply::WillDestroy(cids) => {
+                // A component rendered again in the meantime is no longer destroyed
+                let orphaned = document.orphaned_components();
+                let (cids, rendered): (Vec<i32>, Vec<i32>) =
+                    cids.into_iter().partition(|cid| orphaned.contains(cid));
+                for cid in rendered {
+                    destroying.remove(&cid);
+                }
+                if !cids.is_empty() {
+                    self.send_handshake_event(HandshakeEvent::Destroyed, cids, handshake.clone());
+                }
+            }
+            HandshakeReply::Destroyed { cids, destroyed } => {
+                for cid in cids.iter() {
+                    destroying.remove(cid);
+                }
+                let orphaned = document.orphaned_components();
+                document
+                    .prune_components(destroyed.into_iter().filter(|cid| orphaned.contains(cid)));
+            }
+            // The components are sent again after the next diff if they are still orphaned
+            HandshakeReply::Failed(cids) => {
+                for cid in cids.iter() {
+                    destroying.remove(cid);
+                }
+            }
+        }
+    }
+
+    /// Sends a handshake event for `cids` without waiting for the reply, which is passed to `handshake`
+    ///
+    /// Failures, including timeouts, are logged rather than returned, so they don't end the diff loop.
+    fn send_handshake_event(
+        &self,
+        event: HandshakeEvent,
+        cids: Vec<i32>,
+        handshake: mpsc::UnboundedSender<HandshakeReply>,
+    ) {
+        let channel = self.channel.clone();
+        let timeout = self.timeout;
+        tokio::spawn(async move {
+            let call = async {
+                let payload = serde_json::json!({ "cids": cids }).to_string();
+                let payload = Payload::json_from_serialized(payload)?;
+                let user = event.name().to_string();
+                let reply = channel.call(Event::User { user }, payload, timeout).await?;
+                Ok::<_, LiveSocketError>(reply)
+            };
+            let result = call.await;
+            let reply = match (event, result) {
+                (HandshakeEvent::WillDestroy, Ok(_)) => HandshakeReply::WillDestroy(cids),
+                (HandshakeEvent::Destroyed, Ok(reply)) => {
+                    // The server replies with the CIDs it destroyed, fall back to the ones we sent otherwise
+                    let destroyed = serde_json::from_str::<DestroyedComponents>(&reply.to_string())
+                        .map(|reply| reply.cids)
+                        .unwrap_or_else(|_| cids.clone());
+                    HandshakeReply::Destroyed { cids, destroyed }
+                }
+                (_, Err(err)) => {
+                    error!("{} failed for components {cids:?}: {err}", event.name());
+                    HandshakeReply::Failed(cids)
+                }
+            };
+            // The diff loop may have ended in the meantime, in which case there's nothing left to update
+            let _ = handshake.send(reply);
+        });
+    }
+}
+
+/// The events the client sends in the component deletion handshake
+#[derive(Debug, Clone, Copy)]
+enum HandshakeEvent {
+    WillDestroy,
+    Destroyed,
+}
+impl HandshakeEvent {
+    fn name(self) -> &'static str {
+        match self {
+            Self::WillDestroy => "cids_will_destroy",
+            Self::Destroyed => "cids_destroyed",
+        }
+    }
+}
+
+/// The outcome of a handshake event, passed back to the diff loop
+#[derive(Debug)]
+enum HandshakeReply {
+    /// The server acknowledged that these components will be destroyed
+    WillDestroy(Vec<i32>),
+    /// The server destroyed the components in `destroyed`, out of those in `cids`
+    Destroyed { cids: Vec<i32>, destroyed: Vec<i32> },
+    /// The event for these components failed or timed out
+    Failed(Vec<i32>),
+}
+
+#[derive(serde::Deserialize)]
+struct DestroyedComponents {
+    cids: Vec<i32>,
+}
+
 #[uniffi::export(async_runtime = "tokio")]
 impl LiveSocket {
     #[uniffi::constructor]
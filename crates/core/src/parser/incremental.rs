@@ -0,0 +1,26 @@
+use super::{parse, ParseError};
+use crate::dom::Document;
+
+/// A parser which builds a `Document` from input which arrives in pieces
+///
+/// The pieces are buffered and tokenized by [`parse`] when parsing finishes, so a piece may end anywhere,
+/// even in the middle of a tag, and the result is always the same as parsing the concatenated input.
+#[derive(Debug, Default)]
+pub struct IncrementalParser {
+    input: String,
+}
+impl IncrementalParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends the next piece of input
+    pub fn push_str(&mut self, input: &str) {
+        self.input.push_str(input);
+    }
+
+    /// Finishes parsing, returning the document
+    pub fn finish(self) -> Result<Document, ParseError> {
+        parse(self.input.as_str())
+    }
+}
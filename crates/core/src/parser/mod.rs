@@ -1,3 +1,5 @@
+mod incremental;
+
 use std::{
     assert_matches::assert_matches, borrow::Cow, collections::VecDeque, convert::Infallible, fmt,
     mem,
@@ -7,6 +9,7 @@ use html5gum::{Emitter, Error, Readable, Reader, State, Tokenizer};
 use smallstr::SmallString;
 use smallvec::SmallVec;
 
+pub use self::incremental::IncrementalParser;
 use crate::{dom::*, symbols, InternedString};
 
 /// Parses a `Document` from the given input
@@ -31,7 +34,10 @@ where
                 }
             }
             Token::End(_) => {
-                current_node = document.parent(current_node).unwrap();
+                // Stray end tags without a matching start tag are ignored
+                if let Some(parent) = document.parent(current_node) {
+                    current_node = parent;
+                }
             }
             Token::String(content) => {
                 let node = document.push_node(content);
@@ -163,6 +169,8 @@ impl DocumentEmitter {
     fn flush_current_attribute(&mut self) {
         if let Some((k, v)) = self.current_attribute.take() {
             match self.current_token.as_mut().unwrap() {
+                // Attributes on end tags are a parse error, reported when they start
+                Token::End(_) => (),
                 Token::Start(StartToken {
                     ref mut ids,
                     ref mut element,
@@ -229,6 +237,7 @@ impl Emitter for DocumentEmitter {
 
     #[inline]
     fn init_start_tag(&mut self) {
+        self.current_tag.clear();
         self.current_token = Some(Token::Start(StartToken {
             ids: vec![],
             element: Element::new(symbols::Empty.into()),
@@ -238,6 +247,8 @@ impl Emitter for DocumentEmitter {
 
     #[inline]
     fn init_end_tag(&mut self) {
+        // A candidate end tag in text may have been abandoned, leaving its name behind
+        self.current_tag.clear();
         self.current_token = Some(Token::End(symbols::Empty.into()));
     }
 
@@ -280,7 +291,7 @@ impl Emitter for DocumentEmitter {
                 assert!(!self.current_tag.is_empty());
                 let t = smallvec_to_smallstr(mem::take(&mut self.current_tag));
                 self.emit_token(Token::End(t.as_str().into()));
-                html5gum::naive_next_state(self.last_start_tag.as_str().as_bytes())
+                None
             }
             other => invalid_state("invalid state in which to emit tag", Some(&other)),
         }
@@ -306,6 +317,7 @@ impl Emitter for DocumentEmitter {
             }) => {
                 *self_closing = true;
             }
+            Token::End(_) => self.emit_error(Error::EndTagWithTrailingSolidus),
             other => invalid_state(
                 "invalid state in which to mark a tag self-closing",
                 Some(other),
@@ -334,6 +346,9 @@ impl Emitter for DocumentEmitter {
 
     #[inline]
     fn init_attribute(&mut self) {
+        if let Some(Token::End(_)) = self.current_token {
+            self.emit_error(Error::EndTagWithAttributes);
+        }
         self.flush_current_attribute();
         self.current_attribute = Some(Default::default());
     }
@@ -367,7 +382,11 @@ impl Emitter for DocumentEmitter {
 
     fn current_is_appropriate_end_tag_token(&mut self) -> bool {
         match &self.current_token {
-            Some(Token::End(tag)) => self.last_start_tag != "" && *tag == self.last_start_tag,
+            // The name of the current tag is only moved into its token when it's emitted
+            Some(Token::End(_)) => {
+                self.last_start_tag != ""
+                    && self.current_tag.as_slice() == self.last_start_tag.as_str().as_bytes()
+            }
             _ => false,
         }
     }
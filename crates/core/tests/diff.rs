@@ -1,5 +1,5 @@
 use liveview_native_core::{
-    diff::{self, Patch},
+    diff::{self, MoveTo, Patch, PatchError, PatchResult},
     dom::*,
     parser::ParseError,
 };
@@ -15,12 +15,33 @@ enum Error {
     ParseError(#[from] ParseError),
 }
 
+/// Lists every node attached to the tree of `doc`, with its data and children
+fn tree(doc: &Document) -> Vec<(NodeRef, NodeData, Vec<NodeRef>)> {
+    let mut nodes = vec![];
+    let mut stack = vec![doc.root()];
+    while let Some(node) = stack.pop() {
+        let children = doc.children(node).to_vec();
+        stack.extend(children.iter().rev());
+        nodes.push((node, doc.get(node).clone(), children));
+    }
+    nodes
+}
+
 fn check_transformation(from: &str, to: &str) -> Result<(), Error> {
     let mut prev = Document::parse(from)?;
     let next = Document::parse(to)?;
 
     let mut patches = diff::diff(&prev, &next);
 
+    // Patches produced by the differ always pass validation, and can be undone
+    let mut checked = Document::from_bytes(&prev.to_bytes()).unwrap();
+    let (_, undo) = checked
+        .try_apply_patches_with_undo(patches.clone())
+        .unwrap();
+    assert_eq!(checked.to_string(), next.to_string());
+    checked.try_apply_patches(undo).unwrap();
+    assert_eq!(tree(&checked), tree(&prev));
+
     let mut editor = prev.edit();
     let mut stack = vec![];
     for patch in patches.drain(..) {
@@ -281,6 +302,19 @@ fn diff_live_form() -> Result<(), Error> {
     )
 }
 
+#[test]
+fn diff_keyed_tag_change() -> Result<(), Error> {
+    check_transformation(r#"<button id="a"/>"#, r#"<tr id="a"/>"#)
+}
+
+#[test]
+fn diff_duplicate_ids() -> Result<(), Error> {
+    check_transformation(
+        r#"<tr id="a"><td><button/></td></tr>"#,
+        r#"<td><button/></td><tr id="a"><td><button id="a"/></td></tr>"#,
+    )
+}
+
 test_fixture!("attr-value-empty-string");
 test_fixture!("change-tagname");
 test_fixture!("change-tagname-ids");
@@ -288,7 +322,7 @@ test_fixture!("change-types");
 test_fixture!("data-table");
 test_fixture!("data-table2");
 test_fixture!("equal");
-// test_fixture!("id-change-tag-name");
+test_fixture!("id-change-tag-name");
 test_fixture!("ids-nested");
 test_fixture!("ids-nested-2");
 test_fixture!("ids-nested-3");
@@ -324,3 +358,508 @@ test_fixture!("text-to-text");
 test_fixture!("todomvc");
 test_fixture!("todomvc2");
 test_fixture!("two");
+
+#[test]
+fn diff_keyed_reorder() -> Result<(), Error> {
+    check_diff(
+        r#"<a id="1"/><a id="2"/>"#,
+        r#"<a id="2"/><a id="1"/>"#,
+        &[
+            Patch::Push(NodeRef::from_u32(0)),
+            Patch::MoveNode {
+                node: NodeRef::from_u32(2),
+                before: Some(NodeRef::from_u32(1)),
+            },
+            Patch::Pop,
+        ],
+    )
+}
+
+#[test]
+fn diff_keyed_move_between_parents() -> Result<(), Error> {
+    let from = r#"<ul><li id="x"><b/></li></ul><ol/>"#;
+    let to = r#"<ul/><ol><li id="x"><b/></li></ol>"#;
+    check_transformation(from, to)?;
+
+    let mut prev = Document::parse(from)?;
+    let next = Document::parse(to)?;
+    let (ul, ol, li) = (
+        prev.children(prev.root())[0],
+        prev.children(prev.root())[1],
+        prev.get_by_id("x").unwrap(),
+    );
+
+    // The keyed node is moved with its subtree rather than recreated
+    let mut editor = prev.edit();
+    let mut stack = vec![];
+    let results = diff::diff(editor.document(), &next)
+        .into_iter()
+        .filter_map(|patch| patch.apply(&mut editor, &mut stack))
+        .collect::<Vec<_>>();
+    editor.finish();
+
+    let [PatchResult::Move {
+        node,
+        new_parent,
+        index,
+        ..
+    }] = results.as_slice()
+    else {
+        panic!("expected a single move, got {results:?}");
+    };
+    assert_eq!((*node, *new_parent, *index), (li, ol, 0));
+    assert_eq!(prev.children(ul), &[]);
+    Ok(())
+}
+
+#[test]
+fn diff_move_node_result() {
+    let mut doc = Document::parse(r#"<ul><li id="a"/><li id="b"/></ul><ol/>"#).unwrap();
+    let (ul, ol) = (doc.children(doc.root())[0], doc.children(doc.root())[1]);
+    let (a, b) = (doc.get_by_id("a").unwrap(), doc.get_by_id("b").unwrap());
+
+    let mut editor = doc.edit();
+    let mut stack = vec![];
+    let result = Patch::MoveNode {
+        node: b,
+        before: Some(a),
+    }
+    .apply(&mut editor, &mut stack);
+    assert!(matches!(
+        result,
+        Some(PatchResult::Move { node, old_parent: Some(old_parent), new_parent, index: 0 })
+            if node == b && old_parent == ul && new_parent == ul
+    ));
+
+    stack.push(ol);
+    let result = Patch::MoveNode {
+        node: a,
+        before: None,
+    }
+    .apply(&mut editor, &mut stack);
+    assert!(matches!(
+        result,
+        Some(PatchResult::Move { node, old_parent: Some(old_parent), new_parent, index: 0 })
+            if node == a && old_parent == ul && new_parent == ol
+    ));
+    // The parent stays on the stack, like with `Attach`
+    assert_eq!(stack, vec![ol]);
+    editor.finish();
+
+    assert_eq!(doc.children(ul), &[b]);
+    assert_eq!(doc.children(ol), &[a]);
+}
+
+#[test]
+fn diff_insert_before_with_children() -> Result<(), Error> {
+    check_transformation(r#"<a/>"#, r#"<b><c><e/><f/></c><d/></b><a/>"#)
+}
+
+#[test]
+fn diff_keyed_list_single_move() -> Result<(), Error> {
+    let items = (0..1000).map(|i| format!(r#"<li id="{i}">{i}</li>"#));
+    let from = format!("<ul>{}</ul>", items.clone().collect::<String>());
+    let mut moved = items.collect::<Vec<_>>();
+    let item = moved.remove(10);
+    moved.insert(900, item);
+    let to = format!("<ul>{}</ul>", moved.concat());
+    check_transformation(&from, &to)?;
+
+    // Moving one item in a keyed list moves just that item
+    let prev = Document::parse(&from)?;
+    let next = Document::parse(&to)?;
+    let patches = diff::diff(&prev, &next);
+    let ul = prev.children(prev.root())[0];
+    assert_eq!(
+        patches,
+        [
+            Patch::Push(ul),
+            Patch::MoveNode {
+                node: prev.get_by_id("10").unwrap(),
+                before: prev.get_by_id("901"),
+            },
+            Patch::Pop,
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn diff_keyed_list_changes() -> Result<(), Error> {
+    // Removals, insertions, moves within the list and moves between lists at once
+    check_transformation(
+        r#"<ul id="l"><li id="a">a</li><li id="b">b</li><li id="c"><i id="x"/></li><li id="d">d</li></ul>"#,
+        r#"<ul id="l"><li id="d">d!</li><li id="e"><b/><b/></li><li id="a">a</li><li id="x"/><li id="c"></li></ul>"#,
+    )?;
+    check_transformation(
+        r#"<ul><li id="a"/><li id="b"/></ul><ol><li id="c"/></ol>"#,
+        r#"<ul><li id="c"/><li id="a"/></ul><ol><li id="b"/></ol>"#,
+    )?;
+    // A keyed list containing one of its own ancestors by id is rebuilt rather than moved into itself
+    check_transformation(
+        r#"<div id="p"><ul id="l"><li id="a"/></ul></div>"#,
+        r#"<div id="q"><ul id="l"><li id="p"/></ul></div>"#,
+    )
+}
+
+#[test]
+fn diff_attribute_keys() -> Result<(), Error> {
+    let prev = Document::parse(
+        r#"<List><Item phx-key="a">a</Item><Item phx-key="b">b</Item><Item phx-key="c">c</Item></List>"#,
+    )?;
+    let next = Document::parse(
+        r#"<List><Item phx-key="c">c</Item><Item phx-key="a">a</Item><Item phx-key="b">b</Item></List>"#,
+    )?;
+    let list = prev.children(prev.root())[0];
+    let [a, _, c] = prev.children(list) else {
+        panic!("expected three items");
+    };
+
+    // Without a key strategy, items are matched by position and their text replaced
+    let patches = diff::diff(&prev, &next);
+    assert!(!patches
+        .iter()
+        .any(|patch| matches!(patch, Patch::MoveNode { .. })));
+
+    let keys = diff::AttributeKeys::new(["phx-key", "id"]);
+    let patches = diff::diff_with_keys(&prev, &next, &keys);
+    assert_eq!(
+        patches,
+        [
+            Patch::Push(list),
+            Patch::MoveNode {
+                node: *c,
+                before: Some(*a),
+            },
+            Patch::Pop,
+        ]
+    );
+
+    let mut doc = prev.clone();
+    let mut editor = doc.edit();
+    let mut stack = vec![];
+    for patch in patches {
+        patch.apply(&mut editor, &mut stack);
+    }
+    editor.finish();
+    assert_eq!(doc.to_string(), next.to_string());
+    Ok(())
+}
+
+#[test]
+fn diff_key_function() -> Result<(), Error> {
+    let prev = Document::parse(r#"<ul><li data-id="1"/><li data-id="2"><b/></li></ul>"#)?;
+    let next = Document::parse(r#"<ol><li data-id="2"><b/></li></ol><ul><li data-id="1"/></ul>"#)?;
+    let keys = |element: &Element| {
+        element
+            .attributes
+            .iter()
+            .find(|attr| attr.name.name == "data-id")
+            .and_then(|attr| attr.value.clone())
+    };
+
+    let li = prev.children(prev.children(prev.root())[0])[1];
+    let patches = diff::diff_with_keys(&prev, &next, &keys);
+    // The keyed item is moved into the new list rather than recreated
+    assert!(patches
+        .iter()
+        .any(|patch| matches!(patch, Patch::MoveNode { node, .. } if *node == li)));
+
+    let mut doc = prev.clone();
+    let mut editor = doc.edit();
+    let mut stack = vec![];
+    for patch in patches {
+        patch.apply(&mut editor, &mut stack);
+    }
+    editor.finish();
+    assert_eq!(doc.to_string(), next.to_string());
+    Ok(())
+}
+
+#[test]
+fn diff_attribute_patches() -> Result<(), Error> {
+    // Only the attributes which differ are patched
+    check_diff(
+        r#"<a href="/" class="x" hidden/>"#,
+        r#"<a href="/" class="y" target="_blank"/>"#,
+        &[
+            Patch::UpdateAttribute {
+                node: NodeRef::from_u32(1),
+                name: "class".into(),
+                value: Some("y".into()),
+            },
+            Patch::RemoveAttributeByName {
+                node: NodeRef::from_u32(1),
+                name: "hidden".into(),
+            },
+            Patch::AddAttributeTo {
+                node: NodeRef::from_u32(1),
+                name: "target".into(),
+                value: Some("_blank".into()),
+            },
+        ],
+    )?;
+    // Reordered attributes can't be reproduced one attribute at a time
+    check_diff(
+        r#"<a href="/" class="x"/>"#,
+        r#"<a class="x" href="/"/>"#,
+        &[Patch::SetAttributes {
+            node: NodeRef::from_u32(1),
+            attributes: vec![
+                Attribute::new("class", Some("x".into())),
+                Attribute::new("href", Some("/".into())),
+            ],
+        }],
+    )
+}
+
+#[test]
+fn diff_attribute_change_result() {
+    let mut doc = Document::parse(r#"<a href="/" class="x"/>"#).unwrap();
+    let a = doc.children(doc.root())[0];
+
+    let mut editor = doc.edit();
+    let mut stack = vec![];
+    let result = Patch::SetAttributes {
+        node: a,
+        attributes: vec![
+            Attribute::new("href", Some("/about".into())),
+            Attribute::new("hidden", None),
+        ],
+    }
+    .apply(&mut editor, &mut stack);
+    let Some(PatchResult::Change {
+        node, attributes, ..
+    }) = result
+    else {
+        panic!("expected a change, got {result:?}");
+    };
+    assert_eq!(node, a);
+    assert_eq!(
+        attributes,
+        [
+            AttributeChange::Updated {
+                name: "href".into(),
+                old_value: Some("/".into()),
+                new_value: Some("/about".into()),
+            },
+            AttributeChange::Removed {
+                name: "class".into(),
+                old_value: Some("x".into()),
+            },
+            AttributeChange::Added {
+                name: "hidden".into(),
+                value: None,
+            },
+        ]
+    );
+}
+
+#[test]
+fn diff_update_text() -> Result<(), Error> {
+    check_diff(
+        r#"<p>hello</p>"#,
+        r#"<p>goodbye</p>"#,
+        &[Patch::UpdateText {
+            node: NodeRef::from_u32(2),
+            value: "goodbye".into(),
+        }],
+    )?;
+
+    // The leaf keeps its identity, only its text changes
+    let mut doc = Document::parse(r#"<p>hello</p>"#)?;
+    let p = doc.children(doc.root())[0];
+    let text = doc.children(p)[0];
+    let mut editor = doc.edit();
+    let result = Patch::UpdateText {
+        node: text,
+        value: "goodbye".into(),
+    }
+    .apply(&mut editor, &mut vec![]);
+    editor.finish();
+    assert!(matches!(
+        result,
+        Some(PatchResult::UpdateText { node, parent, data: NodeData::Leaf { value } })
+            if node == text && parent == p && value == "hello"
+    ));
+    assert_eq!(doc.children(p), [text]);
+    assert_eq!(
+        doc.get(text),
+        &NodeData::Leaf {
+            value: "goodbye".into()
+        }
+    );
+    Ok(())
+}
+
+#[test]
+fn diff_serialized_patches() -> Result<(), Error> {
+    let prev = Document::parse(include_str!("fixtures/todomvc/from.html"))?;
+    let next = Document::parse(include_str!("fixtures/todomvc/to.html"))?;
+
+    // Patches computed in one place can be applied to a copy of the document elsewhere
+    let patches = diff::diff(&prev, &next);
+    let json = serde_json::to_string(&patches).unwrap();
+    assert_eq!(serde_json::from_str::<Vec<Patch>>(&json).unwrap(), patches);
+
+    let mut copy = Document::from_bytes(&prev.to_bytes()).unwrap();
+    let changes = copy
+        .try_apply_patches(serde_json::from_str(&json).unwrap())
+        .unwrap();
+    assert!(!changes.is_empty());
+    assert_eq!(copy.to_string(), next.to_string());
+    Ok(())
+}
+
+#[test]
+fn diff_rejected_patches() -> Result<(), Error> {
+    let mut doc = Document::parse(r#"<ul><li id="a">a</li></ul>"#)?;
+    let ul = doc.children(doc.root())[0];
+    let li = doc.get_by_id("a").unwrap();
+    let text = doc.children(li)[0];
+    let before = doc.to_bytes();
+
+    let result = doc.try_apply_patches(vec![
+        Patch::Remove { node: text },
+        Patch::Remove {
+            node: NodeRef::from_u32(99),
+        },
+    ]);
+    assert!(matches!(
+        result,
+        Err(PatchError::UnknownNode { index: 1, node }) if node == NodeRef::from_u32(99)
+    ));
+    let result = doc.try_apply_patches(vec![Patch::Push(ul), Patch::Pop, Patch::Pop]);
+    assert!(matches!(
+        result,
+        Err(PatchError::StackUnderflow { index: 2 })
+    ));
+    let result = doc.try_apply_patches(vec![Patch::MoveNode {
+        node: ul,
+        before: Some(li),
+    }]);
+    assert!(matches!(result, Err(PatchError::Invalid { index: 0, .. })));
+    let result = doc.try_apply_patches(vec![Patch::UpdateAttribute {
+        node: text,
+        name: "class".into(),
+        value: None,
+    }]);
+    let err = result.unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "patch 0 cannot be applied at /ul[1]/li[1]/text()[1]: attributes can only be changed on elements"
+    );
+    let result = doc.try_apply_patches(vec![Patch::Remove { node: doc.root() }]);
+    assert!(matches!(result, Err(PatchError::Invalid { index: 0, .. })));
+    let result = doc.try_apply_patches(vec![
+        Patch::Detach { node: text },
+        Patch::UpdateText {
+            node: text,
+            value: "b".into(),
+        },
+    ]);
+    assert!(matches!(result, Err(PatchError::Invalid { index: 1, .. })));
+    // Leaves can't become parents, whichever patch would make them one
+    let b = NodeData::new("b");
+    let result = doc.try_apply_patches(vec![
+        Patch::Move(MoveTo::Node(text)),
+        Patch::Append { node: b },
+    ]);
+    assert!(matches!(result, Err(PatchError::Invalid { index: 1, .. })));
+    let result = doc.try_apply_patches(vec![Patch::Push(text), Patch::Push(li), Patch::Attach]);
+    assert!(matches!(result, Err(PatchError::Invalid { index: 2, .. })));
+    let result = doc.try_apply_patches(vec![
+        Patch::Push(text),
+        Patch::MoveNode {
+            node: li,
+            before: None,
+        },
+    ]);
+    assert!(matches!(result, Err(PatchError::Invalid { index: 1, .. })));
+    // A document has a single root
+    let result = doc.try_apply_patches(vec![Patch::Create {
+        node: NodeData::Root,
+    }]);
+    assert!(matches!(result, Err(PatchError::Invalid { index: 0, .. })));
+    let result = doc.try_apply_patches(vec![Patch::CreateAndMoveTo {
+        node: NodeData::Root,
+    }]);
+    assert!(matches!(result, Err(PatchError::Invalid { index: 0, .. })));
+    let result = doc.try_apply_patches(vec![Patch::Replace {
+        node: li,
+        replacement: NodeData::Root,
+    }]);
+    assert!(matches!(result, Err(PatchError::Invalid { index: 0, .. })));
+    // Replacing a node with a leaf would leave a leaf with children
+    let result = doc.try_apply_patches(vec![Patch::Replace {
+        node: li,
+        replacement: NodeData::Leaf { value: "b".into() },
+    }]);
+    let err = result.unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "patch 0 cannot be applied at /ul[1]/li[1]: a node with children cannot be replaced by a leaf"
+    );
+    // Rejected lists leave the document as it was
+    assert_eq!(doc.to_bytes(), before);
+
+    let result =
+        serde_json::from_str::<Vec<Patch>>(r#"[{"Push": 1}, {"Remove": {"node": 3}}, "Pop"]"#);
+    let changes = doc.try_apply_patches(result.unwrap()).unwrap();
+    assert_eq!(changes.removed.len(), 1);
+    assert_eq!(
+        doc.to_string(),
+        Document::parse("<ul><li id=\"a\"/></ul>")?.to_string()
+    );
+    // Removed nodes are deleted, and can't be referred to by later patches
+    let removed = NodeRef::from_u32(3);
+    assert!(!doc.contains(removed));
+    let result = doc.try_apply_patches(vec![Patch::Push(ul), Patch::Push(removed), Patch::Attach]);
+    assert!(matches!(
+        result,
+        Err(PatchError::UnknownNode { index: 1, node }) if node == removed
+    ));
+    Ok(())
+}
+
+#[test]
+fn diff_undo_patches() -> Result<(), Error> {
+    let mut doc = Document::parse(
+        r#"<ul class="list"><li id="a">a</li><li id="b"><b>b</b></li></ul><p>text</p>"#,
+    )?;
+    let original = tree(&doc);
+    let ul = doc.children(doc.root())[0];
+    let p = doc.children(doc.root())[1];
+    let (a, b) = (doc.get_by_id("a").unwrap(), doc.get_by_id("b").unwrap());
+
+    let (_, undo) = doc
+        .try_apply_patches_with_undo(vec![
+            Patch::Remove { node: b },
+            Patch::MoveNode {
+                node: a,
+                before: Some(p),
+            },
+            Patch::UpdateText {
+                node: doc.children(p)[0],
+                value: "changed".into(),
+            },
+            Patch::SetAttributes {
+                node: ul,
+                attributes: vec![],
+            },
+            Patch::AppendTo {
+                parent: ul,
+                node: NodeData::new("li"),
+            },
+        ])
+        .unwrap();
+    assert_eq!(
+        doc.to_string(),
+        Document::parse(r#"<ul><li/></ul><li id="a">a</li><p>changed</p>"#)?.to_string()
+    );
+
+    // Undoing restores the removed subtree, and every other node, with the same refs
+    doc.try_apply_patches(undo).unwrap();
+    assert_eq!(tree(&doc), original);
+    Ok(())
+}
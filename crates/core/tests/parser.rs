@@ -3,8 +3,9 @@
 use std::assert_matches::assert_matches;
 
 use liveview_native_core::{
-    dom::{AttributeName, NodeData},
-    parser, InternedString,
+    dom::{AttributeName, Document, NodeData},
+    parser::{self, IncrementalParser, ParseError},
+    InternedString,
 };
 
 #[test]
@@ -71,3 +72,114 @@ fn parser_preserve_upcase() {
     let expected_name: InternedString = "Component".into();
     assert_eq!(element.name, expected_name);
 }
+
+#[test]
+fn parser_stray_end_tags() {
+    let result = parser::parse("</div><p>text</p></p></section>");
+    assert_matches!(result, Ok(_));
+    let document = result.unwrap();
+    let root = document.root();
+    let children = document.children(root);
+    assert_eq!(children.len(), 1);
+    assert_eq!(document.children(children[0]).len(), 1);
+}
+
+#[test]
+fn parser_raw_text_end_tags() {
+    // The text of these elements used to run to the end of the input, as their end tag was never matched
+    let inputs = [
+        "<textarea>a &amp; <b></textareax></textarea><p>b</p>",
+        "<title>a</title><p>b</p>",
+        "<style>a</stylex></style><p>b</p>",
+        "<script>a</script><p>b</p>",
+    ];
+    for input in inputs {
+        let document = parser::parse(input).expect(input);
+        let root = document.root();
+        let children = document.children(root);
+        assert_eq!(children.len(), 2, "{input}");
+        let text = document.children(children[0]);
+        assert_eq!(text.len(), 1, "{input}");
+        let NodeData::Leaf { value } = document.get(text[0]) else {
+            panic!("expected text in {input}");
+        };
+        assert!(value.starts_with('a') && !value.contains("</p>"), "{input}");
+        let p = document.get(children[1]);
+        assert_matches!(p, NodeData::NodeElement { element } if element.name == InternedString::from("p"), "{input}");
+    }
+}
+
+#[test]
+fn parser_end_tag_errors() {
+    // These used to panic, rather than failing like html5gum's own emitter does
+    let inputs = [
+        ("<a></a b>", "end-tag-with-attributes"),
+        ("<a></a b=\"c\" d>", "end-tag-with-attributes"),
+        ("<a></a/>", "end-tag-with-trailing-solidus"),
+    ];
+    for (input, error) in inputs {
+        let result = parser::parse(input);
+        assert_matches!(result, Err(ParseError::Tokenizer(_)), "{input}");
+        assert!(result.unwrap_err().to_string().ends_with(error), "{input}");
+    }
+}
+
+/// Parses `input` with an `IncrementalParser`, pushing it in pieces of `size` bytes
+fn parse_in_pieces(input: &str, size: usize) -> Result<Document, ParseError> {
+    let mut parser = IncrementalParser::new();
+    let mut rest = input;
+    while !rest.is_empty() {
+        let mut end = size.min(rest.len());
+        while !rest.is_char_boundary(end) {
+            end += 1;
+        }
+        parser.push_str(&rest[..end]);
+        rest = &rest[end..];
+    }
+    parser.finish()
+}
+
+/// Returns the encoded document, or the error if parsing failed
+fn parse_result(result: Result<Document, ParseError>) -> Result<Vec<u8>, String> {
+    result
+        .map(|document| document.to_bytes())
+        .map_err(|error| error.to_string())
+}
+
+#[test]
+fn parser_incremental_matches_parse() {
+    let inputs = [
+        "<html lang=\"en\"><head><meta charset=\"utf-8\"/></head><body><a href=\"about:blank\">Hello World!</a></body></html>",
+        "<Component id=5><SubComponent id='7' disabled><!-- note --><a href=about:blank>Hello &amp; goodbye &#39;World&#x27;</a></SubComponent></Component>",
+        "<!DOCTYPE html><p title=\"a &quot;b&quot; &lt;c&gt;\">  text  <br/> more <!----> <!-- a -- b --> end</p>",
+        "<script>if (a < b && c) { document.write('</p>'); }</script><style> p > a { } </style>",
+        "<textarea>x</textareax> </TEXTAREA><b class=x/>y</b>",
+        "</div><p>stray</p></p>a < b",
+        "<title>a &copy; b</title><p>&notin; &nbsp;&hellip;</p><a href=\"?x=1&amp=2\" title='say \"hi\" &amp; &copy;'>&a</a>",
+        "<p>&foo;</p>",
+        "<p>&#0;</p>",
+        "<!-- a --!>",
+        "<a b=\"1\"c=\"2\">",
+        "<p>\0</p>",
+        include_str!("fixtures/todomvc/from.html"),
+        include_str!("fixtures/svg-xlink/from.html"),
+        include_str!("fixtures/select-element/from.html"),
+    ];
+    for input in inputs {
+        let expected = parse_result(parser::parse(input));
+        // Split the input in pieces of every size, so each piece boundary falls in every state
+        for size in 1..=16 {
+            let result = parse_result(parse_in_pieces(input, size));
+            assert_eq!(result, expected, "{input}");
+        }
+    }
+}
+
+#[test]
+fn parser_incremental_eof_errors() {
+    for input in ["<a", "<a href=\"x", "<!-- a", "<!DOCTYPE", "<"] {
+        let mut parser = IncrementalParser::new();
+        parser.push_str(input);
+        assert_matches!(parser.finish(), Err(ParseError::Tokenizer(_)), "{input}");
+    }
+}